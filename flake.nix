@@ -132,6 +132,10 @@
             "--nemesis partition"
           ];
         };
+        broadcast-acked = {
+          inherit (broadcast) bin maelstrom-args;
+          bin-args = ["--gossip" "acked"];
+        };
         broadcast-stress = {
           inherit (broadcast) bin;
           maelstrom-args = [
@@ -165,6 +169,14 @@
             latency-max = 2000;
           };
         };
+        broadcast-stress-tree = {
+          inherit (broadcast-stress) bin maelstrom-args;
+          bin-args = ["--topology" "tree"];
+        };
+        broadcast-stress-adaptive = {
+          inherit (broadcast-stress) bin maelstrom-args analysis-params;
+          bin-args = ["--adaptive"];
+        };
         counter = {
           bin = "counter";
           maelstrom-args = [
@@ -175,6 +187,21 @@
             "--nemesis partition"
           ];
         };
+        counter-per-node-keys = {
+          inherit (counter) bin maelstrom-args;
+          bin-args = ["--mode" "per-node-keys"];
+        };
+        counter-crdt = {
+          bin = "counter";
+          bin-args = ["--mode" "crdt"];
+          maelstrom-args = [
+            "-w pn-counter"
+            "--node-count 3"
+            "--rate 100"
+            "--time-limit 20"
+            "--nemesis partition"
+          ];
+        };
         logs-single = {
           bin = "logs";
           maelstrom-args = [
@@ -194,7 +221,6 @@
             "--time-limit 20"
             "--rate 1000"
           ];
-          regression-ignore = true;
         };
       };
       maelstrom-script = label: {
@@ -209,7 +235,7 @@
       in
         pkgs.writeShellScriptBin "test-${label}" ''
           set -x
-          cargo build --bin ${pkgs.lib.escapeShellArg bin} \
+          cargo build --bin ${pkgs.lib.escapeShellArg bin} --bin analysis \
           && \
             ${maelstrom}/bin/maelstrom test \
             ${pkgs.lib.escapeShellArgs maelstrom-args} \
@@ -224,6 +250,7 @@
                 {
                   inherit label exit-code-var;
                   src = pkgs.lib.escapeShellArg out-file;
+                  analysis = "target/debug/analysis";
                 }
                 analysis-params}
               if [ ''$${exit-code-var} -eq 0 ]; then
@@ -276,6 +303,7 @@
               ${maelstrom-analysis-text {
                   inherit label exit-code-var;
                   src = "$src";
+                  analysis = "${crate.package}/bin/analysis";
                 }
                 analysis-params}
               if [ ''$${exit-code-var} -eq 0 ]; then
@@ -289,68 +317,23 @@
         label,
         src,
         exit-code-var,
+        analysis,
       }: {
         msgs-per-op,
         latency-median,
         latency-max,
-      }: let
-        numeric_check_lt = {
-          value,
-          threshold,
-          label,
-          units,
-        }: ''
-          if (( $(echo "${value} < ${toString threshold}" | ${pkgs.bc}/bin/bc -l) )); then
-            echo "[PASS] ${label}: ${value} (below goal of ${toString threshold} ${units})"
-          else
-            echo "[FAIL] ${label} is out of range: ${value} (should be below goal of ${toString threshold} ${units})"
-            ${exit-code-var}=1
-          fi
-        '';
-      in ''
-        # exit on first error
-        set -e
-
-        # print analysis
-        grep "msgs-per-op" ${src}
-        grep stable-latencies ${src} -A 4
-
-        set +x
-        msgs_per_op1=$(grep "msgs-per-op" ${src} | cut -d "p" -f3 | cut -d "}" -f1 | xargs echo | cut -d " " -f 1)
-        msgs_per_op2=$(grep "msgs-per-op" ${src} | cut -d "p" -f3 | cut -d "}" -f1 | xargs echo | cut -d " " -f 2)
-        latency_median=$(grep stable-latencies ${src} -A 4 | grep "0.5 " | xargs echo | cut -d " " -f 2- | cut -d "," -f1)
-        latency_max=$(grep stable-latencies ${src} -A 4 | grep "1 " | xargs echo | cut -d " " -f 2- | cut -d "}" -f1)
-
+      }: ''
         ${exit-code-var}=0
-        ${numeric_check_lt {
-          value = "$msgs_per_op1";
-          threshold = msgs-per-op;
-          label = "Messages per op (#1)";
-          units = "messages per op";
-        }}
-        ${numeric_check_lt {
-          value = "$msgs_per_op2";
-          threshold = msgs-per-op;
-          label = "Messages per op (#2)";
-          units = "messages per op";
-        }}
-        ${numeric_check_lt {
-          value = "$latency_median";
-          threshold = latency-median;
-          label = "Latency Median";
-          units = "ms";
-        }}
-        ${numeric_check_lt {
-          value = "$latency_max";
-          threshold = latency-max;
-          label = "Latency Max";
-          units = "ms";
-        }}
+        ${analysis} \
+          --input ${src} \
+          --label ${pkgs.lib.escapeShellArg label} \
+          --msgs-per-op ${toString msgs-per-op} \
+          --latency-median ${toString latency-median} \
+          --latency-max ${toString latency-max} \
+          || ${exit-code-var}=1
 
-        if [ ''$${exit-code-var} -eq 0 ]; then
-          echo "Output analysis check passed."
-        else
-          echo "Output analysis check failed, reference output file:"
+        if [ ''$${exit-code-var} -ne 0 ]; then
+          echo "reference output file:"
           echo -e "\t" ${src}
         fi
       '';
@@ -0,0 +1,568 @@
+//! Asynchronous flavor of [`Node`](crate::Node), running on tokio
+//!
+//! Each inbound request is handled on its own task, so a handler may `await` replies to its
+//! own requests ([`Runtime::rpc`]) and `tokio::time` timers without blocking other messages.
+//! As for [`Node`](crate::Node), the requests the node receives, the replies to its own
+//! requests, and the messages it sends have distinct payload types.
+
+use crate::{Body, Error, ErrorCode, ErrorPolicy, Init, InitPayload, Message, RawMessage};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt},
+    sync::oneshot,
+    task::JoinSet,
+};
+
+/// Node whose message handlers are asynchronous
+///
+/// Handlers run concurrently, so the node is shared (use interior mutability for state).
+pub trait AsyncNode<S = ()>: Sized + Send + Sync + 'static {
+    /// Payload of requests the node receives (messages without `in_reply_to`)
+    type Request: DeserializeOwned + Send + 'static;
+    /// Payload of replies to the node's [`Runtime::rpc`] calls
+    type Response: DeserializeOwned + Send + 'static;
+    /// Payload of replies and requests the node sends (the only payload its [`Runtime`] sends)
+    type Outbound: Serialize + Send + 'static;
+
+    fn from_init(
+        init: Init,
+        start: S,
+        runtime: Runtime<Self::Outbound, Self::Response>,
+    ) -> impl Future<Output = anyhow::Result<Self>> + Send;
+
+    /// Handles a request
+    fn handle(
+        self: Arc<Self>,
+        message: Message<Self::Request>,
+        runtime: Runtime<Self::Outbound, Self::Response>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// How errors other than [`Error`]s are handled (by default, aborting the node)
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::default()
+    }
+
+    /// Called once after the last input and handler, before the node stops
+    fn shutdown(
+        self: Arc<Self>,
+        _runtime: Runtime<Self::Outbound, Self::Response>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Handle for sending messages with `O` payloads from an [`AsyncNode`], and receiving `R`
+/// payloads in reply
+pub struct Runtime<O, R> {
+    shared: Arc<Shared>,
+    _payloads: PhantomData<fn(O) -> R>,
+}
+struct Shared {
+    node_id: String,
+    msg_id: AtomicUsize,
+    /// Pending [`Runtime::rpc`] calls, or `None` once the input has ended
+    pending: Mutex<Option<HashMap<usize, oneshot::Sender<RawMessage>>>>,
+    output: Mutex<Box<dyn std::io::Write + Send>>,
+}
+type PendingLock<'a> =
+    std::sync::MutexGuard<'a, Option<HashMap<usize, oneshot::Sender<RawMessage>>>>;
+type OutputLock<'a> = std::sync::MutexGuard<'a, Box<dyn std::io::Write + Send>>;
+impl Shared {
+    fn lock_pending(&self) -> PendingLock<'_> {
+        self.pending.lock().expect("pending rpc lock poisoned")
+    }
+    fn lock_output(&self) -> OutputLock<'_> {
+        self.output.lock().expect("output lock poisoned")
+    }
+
+    /// Completes the pending [`Runtime::rpc`] call which `message` replies to
+    ///
+    /// Returns the message if it is not a reply to a pending call
+    fn try_complete(&self, message: RawMessage) -> Option<RawMessage> {
+        let Some(in_reply_to) = message.body.in_reply_to else {
+            return Some(message);
+        };
+        let reply_tx = self
+            .lock_pending()
+            .as_mut()
+            .and_then(|pending| pending.remove(&in_reply_to));
+        let Some(reply_tx) = reply_tx else {
+            return Some(message);
+        };
+        // NOTE: ignore calls that were cancelled (e.g. by timeout)
+        let _ = reply_tx.send(message);
+        None
+    }
+
+    /// Fails all pending and future [`Runtime::rpc`] calls, as no more replies can arrive
+    fn close_pending(&self) {
+        self.lock_pending().take();
+    }
+}
+/// Removes a [`Runtime::rpc`] call from the pending calls when it completes or is cancelled
+struct PendingGuard<'a> {
+    shared: &'a Shared,
+    msg_id: usize,
+}
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(pending) = self.shared.lock_pending().as_mut() {
+            pending.remove(&self.msg_id);
+        }
+    }
+}
+impl<O, R> Clone for Runtime<O, R> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+            _payloads: PhantomData,
+        }
+    }
+}
+
+impl<O, R> Runtime<O, R>
+where
+    O: Serialize,
+    R: DeserializeOwned,
+{
+    pub fn node_id(&self) -> &str {
+        &self.shared.node_id
+    }
+
+    /// Increments the node's message counter and returns the next message id
+    pub fn next_msg_id(&self) -> usize {
+        self.shared.msg_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Writes `message` to the output
+    pub fn send(&self, message: Message<O>) -> anyhow::Result<()> {
+        message.send(&mut *self.shared.lock_output())
+    }
+
+    /// Replies to `request` with `payload`
+    pub fn reply<Q>(&self, request: Message<Q>, payload: O) -> anyhow::Result<()> {
+        let mut reply = request.reply_with(None, payload);
+        reply.body.msg_id = Some(self.next_msg_id());
+        self.send(reply)
+    }
+
+    /// Sends `payload` to `dest`, and waits for the reply
+    ///
+    /// If the returned future is dropped before the reply arrives (e.g. on timeout), the call
+    /// is forgotten and a later reply is logged and dropped.
+    ///
+    /// Fails with a [`Crash`](ErrorCode::Crash) error if the input ends before the reply
+    /// arrives, as no more replies can, or if the reply is not a [`AsyncNode::Response`].
+    pub async fn rpc(&self, dest: impl Into<String>, payload: O) -> anyhow::Result<Message<R>> {
+        let dest = dest.into();
+        let msg_id = self.next_msg_id();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        match self.lock_pending().as_mut() {
+            Some(pending) => pending.insert(msg_id, reply_tx),
+            None => return Err(input_ended(&dest)),
+        };
+        let _pending = PendingGuard {
+            shared: &self.shared,
+            msg_id,
+        };
+
+        let message = Message {
+            src: self.shared.node_id.clone(),
+            dest: dest.clone(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        self.send(message)?;
+        let reply = reply_rx.await.map_err(|_| input_ended(&dest))?;
+        reply.deserialize_payload().map_err(|err| {
+            let text = format!("unexpected reply from {dest}: {err}");
+            Error::new(ErrorCode::Crash, text).into()
+        })
+    }
+
+    /// Sends `payload` to `dest` as in [`Runtime::rpc`], failing with a
+    /// [`Timeout`](ErrorCode::Timeout) error if no reply arrives within `timeout`
+    pub async fn rpc_with_timeout(
+        &self,
+        dest: impl Into<String>,
+        payload: O,
+        timeout: Duration,
+    ) -> anyhow::Result<Message<R>> {
+        let dest = dest.into();
+        match tokio::time::timeout(timeout, self.rpc(dest.clone(), payload)).await {
+            Ok(result) => result,
+            Err(_elapsed) => {
+                let text = format!("no reply from {dest} within {timeout:?}");
+                Err(Error::new(ErrorCode::Timeout, text).into())
+            }
+        }
+    }
+
+    fn lock_pending(&self) -> PendingLock<'_> {
+        self.shared.lock_pending()
+    }
+}
+
+/// Returns the error for a [`Runtime::rpc`] call to `dest` which cannot receive a reply
+fn input_ended(dest: &str) -> anyhow::Error {
+    let text = format!("input ended before reply from {dest}");
+    Error::new(ErrorCode::Crash, text).into()
+}
+
+/// Runs an [`AsyncNode`] on stdin/stdout, until end of input or an aborting error
+///
+/// Errors are handled per the node's [`ErrorPolicy`], as in [`main_loop`](crate::main_loop).
+pub fn main_loop_async<N, S>(start: S) -> anyhow::Result<()>
+where
+    N: AsyncNode<S>,
+    S: 'static,
+{
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("build tokio runtime")?
+        .block_on(run::<N, S>(
+            start,
+            tokio::io::BufReader::new(tokio::io::stdin()),
+            std::io::stdout(),
+        ))
+}
+
+/// Runs an [`AsyncNode`] on the `input` lines, writing messages to `output`
+async fn run<N, S>(
+    start: S,
+    input: impl AsyncBufRead + Unpin,
+    mut output: impl std::io::Write + Send + 'static,
+) -> anyhow::Result<()>
+where
+    N: AsyncNode<S>,
+    S: 'static,
+{
+    let mut stdin = input.lines();
+
+    let init_message = stdin
+        .next_line()
+        .await
+        .context("read from stdin")?
+        .context("initial message not present on stdin")?;
+    let init_message: Message<InitPayload> =
+        Message::from_json(&init_message).context("initial message")?;
+    let mut msg_id = 0;
+    let init = crate::reply_init(init_message, &mut msg_id, &mut output)?;
+
+    let runtime = Runtime {
+        shared: Arc::new(Shared {
+            node_id: init.node_id.clone(),
+            msg_id: AtomicUsize::new(msg_id),
+            pending: Mutex::new(Some(HashMap::new())),
+            output: Mutex::new(Box::new(output)),
+        }),
+        _payloads: PhantomData,
+    };
+    let node = Arc::new(N::from_init(init, start, runtime.clone()).await?);
+
+    let mut handlers = JoinSet::new();
+    let result = step_inputs(&node, &runtime, &mut stdin, &mut handlers).await;
+    handlers.abort_all();
+    while handlers.join_next().await.is_some() {}
+
+    let shutdown_result = node.shutdown(runtime).await.context("shutdown");
+    result.and(shutdown_result)
+}
+
+/// Spawns a handler for each input message, until end of input (and the handlers finish) or an
+/// aborting error
+///
+/// At end of input, pending [`Runtime::rpc`] calls fail so that their handlers can finish.
+async fn step_inputs<N, S>(
+    node: &Arc<N>,
+    runtime: &Runtime<N::Outbound, N::Response>,
+    stdin: &mut tokio::io::Lines<impl AsyncBufRead + Unpin>,
+    handlers: &mut JoinSet<anyhow::Result<()>>,
+) -> anyhow::Result<()>
+where
+    N: AsyncNode<S>,
+    S: 'static,
+{
+    loop {
+        tokio::select! {
+            line = stdin.next_line() => {
+                let Some(line) = line.context("read from stdin")? else {
+                    eprintln!("end of input");
+                    break;
+                };
+                let Some(message) = parse_request(&line, node.error_policy(), &runtime.shared)?
+                else {
+                    continue;
+                };
+                handlers.spawn(handle(Arc::clone(node), message, runtime.clone()));
+            }
+            Some(result) = handlers.join_next() => {
+                result.context("handler task panicked")??;
+            }
+        }
+    }
+
+    runtime.shared.close_pending();
+    while let Some(result) = handlers.join_next().await {
+        result.context("handler task panicked")??;
+    }
+    Ok(())
+}
+
+/// Parses an input `line` as a request for the node, completing the pending
+/// [`Runtime::rpc`] call instead if it is a reply
+///
+/// Returns `None` if the line was not a request: a malformed line is handled per `policy`, a
+/// reply to no pending call is logged, and a request with an unexpected payload is rejected
+/// with a [`NotSupported`](ErrorCode::NotSupported) error.
+fn parse_request<P>(
+    line: &str,
+    policy: ErrorPolicy,
+    shared: &Shared,
+) -> anyhow::Result<Option<Message<P>>>
+where
+    P: DeserializeOwned,
+{
+    let message: RawMessage = match Message::from_json(line).context("message") {
+        Ok(message) => message,
+        Err(err) => match policy {
+            ErrorPolicy::Abort => return Err(err),
+            ErrorPolicy::Log | ErrorPolicy::Reply => {
+                eprintln!("skipping input: {err:#}");
+                return Ok(None);
+            }
+        },
+    };
+    let Some(message) = shared.try_complete(message) else {
+        return Ok(None);
+    };
+    let request = message.header();
+    if let Some(in_reply_to) = request.body.in_reply_to {
+        // NOTE: e.g. the call timed out, and replies are never answered
+        eprintln!(
+            "ignoring reply to {in_reply_to} from {} without a pending call",
+            request.src
+        );
+        return Ok(None);
+    }
+    match message.deserialize_payload() {
+        Ok(message) => Ok(Some(message)),
+        Err(err) => {
+            let err = crate::reject_message(&request, "request", err);
+            crate::handle_message_error(policy, &request, err, &mut *shared.lock_output())?;
+            Ok(None)
+        }
+    }
+}
+
+/// Runs the handler for `message`, sending an `error` reply for any [`Error`] returned and
+/// handling other errors per the node's [`ErrorPolicy`]
+async fn handle<N, S>(
+    node: Arc<N>,
+    message: Message<N::Request>,
+    runtime: Runtime<N::Outbound, N::Response>,
+) -> anyhow::Result<()>
+where
+    N: AsyncNode<S>,
+    S: 'static,
+{
+    let request = message.header();
+    let policy = node.error_policy();
+    let shared = Arc::clone(&runtime.shared);
+    match node.handle(message, runtime).await {
+        Ok(()) => Ok(()),
+        Err(err) => crate::handle_message_error(policy, &request, err, &mut *shared.lock_output()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RawMessage;
+    use serde::Deserialize;
+    use serde_json::json;
+    use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+    /// Node which answers each `fetch` with the value it reads from the `svc` node
+    struct Relay;
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        Fetch,
+    }
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Response {
+        GetOk { value: u64 },
+    }
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Outbound {
+        FetchOk { value: u64 },
+        Get,
+    }
+    impl AsyncNode for Relay {
+        type Request = Request;
+        type Response = Response;
+        type Outbound = Outbound;
+
+        async fn from_init(
+            _init: Init,
+            _start: (),
+            _runtime: Runtime<Outbound, Response>,
+        ) -> anyhow::Result<Self> {
+            Ok(Self)
+        }
+
+        async fn handle(
+            self: Arc<Self>,
+            message: Message<Request>,
+            runtime: Runtime<Outbound, Response>,
+        ) -> anyhow::Result<()> {
+            let Request::Fetch = message.body.payload;
+            let reply = runtime.rpc("svc", Outbound::Get).await?;
+            let Response::GetOk { value } = reply.body.payload;
+            runtime.reply(message, Outbound::FetchOk { value })
+        }
+    }
+
+    /// Output which passes on each message written by the node
+    struct ChannelOutput {
+        line: Vec<u8>,
+        tx: mpsc::UnboundedSender<RawMessage>,
+    }
+    impl std::io::Write for ChannelOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.line.extend_from_slice(buf);
+            while let Some(end) = self.line.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.line.drain(..=end).collect();
+                let message = serde_json::from_slice(&line).expect("output is a message");
+                let _ = self.tx.send(message);
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs a [`Relay`] node, driven by `driver` with the node's input and output
+    async fn run_relay<F>(
+        driver: impl FnOnce(InputWriter, mpsc::UnboundedReceiver<RawMessage>) -> F,
+    ) where
+        F: Future<Output = ()>,
+    {
+        let (input, node_input) = tokio::io::duplex(4096);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let output = ChannelOutput { line: vec![], tx };
+        let node = run::<Relay, ()>((), tokio::io::BufReader::new(node_input), output);
+        let driver = driver(InputWriter(input), rx);
+        let run_both = async { tokio::join!(node, driver) };
+        let (result, ()) = tokio::time::timeout(Duration::from_secs(5), run_both)
+            .await
+            .expect("node stopped");
+        result.unwrap();
+    }
+
+    struct InputWriter(tokio::io::DuplexStream);
+    impl InputWriter {
+        async fn send(&mut self, message: serde_json::Value) {
+            let line = format!("{message}\n");
+            self.0.write_all(line.as_bytes()).await.unwrap();
+        }
+    }
+
+    async fn init(input: &mut InputWriter, rx: &mut mpsc::UnboundedReceiver<RawMessage>) {
+        input
+            .send(json!({"src": "c0", "dest": "n0", "body": {
+                "msg_id": 0, "type": "init", "node_id": "n0", "node_ids": ["n0"]
+            }}))
+            .await;
+        let init_ok = rx.recv().await.unwrap();
+        assert_eq!(init_ok.body.payload, json!({"type": "init_ok"}));
+    }
+
+    #[tokio::test]
+    async fn request_awaits_rpc_reply() {
+        run_relay(|mut input, mut rx| async move {
+            init(&mut input, &mut rx).await;
+            input
+                .send(json!({"src": "c1", "dest": "n0", "body": {"msg_id": 1, "type": "fetch"}}))
+                .await;
+            let get = rx.recv().await.unwrap();
+            assert_eq!(get.dest, "svc");
+            assert_eq!(get.body.payload, json!({"type": "get"}));
+
+            let get_msg_id = get.body.msg_id.unwrap();
+            input
+                .send(json!({"src": "svc", "dest": "n0", "body": {
+                    "in_reply_to": get_msg_id, "type": "get_ok", "value": 5
+                }}))
+                .await;
+            let fetch_ok = rx.recv().await.unwrap();
+            assert_eq!(fetch_ok.dest, "c1");
+            assert_eq!(fetch_ok.body.in_reply_to, Some(1));
+            assert_eq!(
+                fetch_ok.body.payload,
+                json!({"type": "fetch_ok", "value": 5})
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn unexpected_reply_fails_rpc() {
+        run_relay(|mut input, mut rx| async move {
+            init(&mut input, &mut rx).await;
+            input
+                .send(json!({"src": "c1", "dest": "n0", "body": {"msg_id": 1, "type": "fetch"}}))
+                .await;
+            let get = rx.recv().await.unwrap();
+            let get_msg_id = get.body.msg_id.unwrap();
+            input
+                .send(json!({"src": "svc", "dest": "n0", "body": {
+                    "in_reply_to": get_msg_id, "type": "fetch_ok", "value": 5
+                }}))
+                .await;
+            let error = rx.recv().await.unwrap();
+            assert_eq!(error.dest, "c1");
+            assert_eq!(error.body.in_reply_to, Some(1));
+            assert_eq!(error.body.payload["code"], json!(13));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn end_of_input_fails_waiting_rpc() {
+        run_relay(|mut input, mut rx| async move {
+            init(&mut input, &mut rx).await;
+            input
+                .send(json!({"src": "c1", "dest": "n0", "body": {"msg_id": 1, "type": "fetch"}}))
+                .await;
+            let get = rx.recv().await.unwrap();
+            assert_eq!(get.dest, "svc");
+
+            drop(input);
+            let error = rx.recv().await.unwrap();
+            assert_eq!(error.dest, "c1");
+            assert_eq!(error.body.in_reply_to, Some(1));
+            assert_eq!(error.body.payload["code"], json!(13));
+        })
+        .await;
+    }
+}
@@ -0,0 +1,532 @@
+//! Parser for EDN (extensible data notation), the format of Maelstrom's results
+
+use anyhow::{bail, Context};
+
+/// Parsed EDN value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    /// Floating point, or an integer/ratio/decimal not representable as [`Value::Int`]
+    Float(f64),
+    String(String),
+    Char(char),
+    /// Keyword, without the leading `:`
+    Keyword(String),
+    Symbol(String),
+    List(Vec<Value>),
+    Vector(Vec<Value>),
+    Set(Vec<Value>),
+    /// Map entries, in order of appearance
+    Map(Vec<(Value, Value)>),
+    /// Tagged element, e.g. `#inst "..."` (with the tag excluding the leading `#`)
+    Tagged(String, Box<Value>),
+}
+impl Value {
+    /// Returns the value of the `keyword` entry, if this is a map containing it
+    pub fn get(&self, keyword: &str) -> Option<&Value> {
+        self.get_entry(|key| matches!(key, Value::Keyword(k) if k == keyword))
+    }
+
+    /// Returns the value at the path of nested `keywords`
+    pub fn get_path(&self, keywords: &[&str]) -> Option<&Value> {
+        keywords
+            .iter()
+            .try_fold(self, |value, keyword| value.get(keyword))
+    }
+
+    /// Returns the value of the first entry whose key matches, if this is a map (or a record)
+    pub fn get_entry(&self, mut matches: impl FnMut(&Value) -> bool) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries
+                .iter()
+                .find_map(|(key, value)| matches(key).then_some(value)),
+            Value::Tagged(_, value) => value.get_entry(matches),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Int(value) => Some(value as f64),
+            Value::Float(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the first EDN value of `input`, returning it and the remaining input
+pub fn parse_prefix(input: &str) -> anyhow::Result<(Value, &str)> {
+    let mut parser = Parser { input, position: 0 };
+    let value = parser.value().with_context(|| {
+        let (line, column) = parser.line_column();
+        format!("invalid EDN at line {line} column {column}")
+    })?;
+    Ok((value, &input[parser.position..]))
+}
+
+/// Parses `input`, which must contain exactly one EDN value
+pub fn parse(input: &str) -> anyhow::Result<Value> {
+    let (value, rest) = parse_prefix(input)?;
+    let mut parser = Parser {
+        input: rest,
+        position: 0,
+    };
+    parser.skip_ignored()?;
+    if parser.peek().is_some() {
+        bail!("unexpected input after EDN value: {:.20?}", parser.rest());
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    /// Byte offset of the next character
+    position: usize,
+}
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.input[self.position..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn line_column(&self) -> (usize, usize) {
+        let before = &self.input[..self.position];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        (line, column)
+    }
+
+    /// Skips whitespace, commas, comments and discarded (`#_`) values
+    fn skip_ignored(&mut self) -> anyhow::Result<()> {
+        while let Some(c) = self.peek() {
+            match c {
+                ';' => {
+                    let line_end = self.rest().find('\n').unwrap_or(self.rest().len());
+                    self.position += line_end;
+                }
+                '#' if self.rest().starts_with("#_") => {
+                    self.position += 2;
+                    self.value()?;
+                }
+                c if c.is_whitespace() || c == ',' => self.position += c.len_utf8(),
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> anyhow::Result<Value> {
+        self.skip_ignored()?;
+        let Some(c) = self.next() else {
+            bail!("unexpected end of input");
+        };
+        match c {
+            '(' => Ok(Value::List(self.sequence(')')?)),
+            '[' => Ok(Value::Vector(self.sequence(']')?)),
+            '{' => self.map(),
+            '"' => Ok(Value::String(self.string()?)),
+            '\\' => self.char(),
+            ':' => Ok(Value::Keyword(self.token())),
+            '#' => match self.peek() {
+                Some('{') => {
+                    self.next();
+                    Ok(Value::Set(self.sequence('}')?))
+                }
+                Some('#') => {
+                    self.next();
+                    match self.token().as_str() {
+                        "Inf" => Ok(Value::Float(f64::INFINITY)),
+                        "-Inf" => Ok(Value::Float(f64::NEG_INFINITY)),
+                        "NaN" => Ok(Value::Float(f64::NAN)),
+                        other => bail!("unknown symbolic value ##{other}"),
+                    }
+                }
+                Some('"') => {
+                    self.next();
+                    Ok(Value::Tagged(
+                        "regex".to_string(),
+                        Box::new(Value::String(self.regex()?)),
+                    ))
+                }
+                _ => {
+                    let tag = self.token();
+                    if tag.is_empty() {
+                        bail!("missing tag after #");
+                    }
+                    Ok(Value::Tagged(tag, Box::new(self.value()?)))
+                }
+            },
+            ')' | ']' | '}' => {
+                self.position -= c.len_utf8();
+                bail!("unexpected {c:?}")
+            }
+            c => {
+                self.position -= c.len_utf8();
+                self.atom()
+            }
+        }
+    }
+
+    /// Parses values until `close`
+    fn sequence(&mut self, close: char) -> anyhow::Result<Vec<Value>> {
+        let mut values = vec![];
+        loop {
+            self.skip_ignored()?;
+            if self.peek() == Some(close) {
+                self.next();
+                return Ok(values);
+            }
+            values.push(self.value()?);
+        }
+    }
+
+    fn map(&mut self) -> anyhow::Result<Value> {
+        let values = self.sequence('}')?;
+        if values.len() % 2 != 0 {
+            bail!("map has a key without a value");
+        }
+        let mut values = values.into_iter();
+        let mut entries = vec![];
+        while let (Some(key), Some(value)) = (values.next(), values.next()) {
+            entries.push((key, value));
+        }
+        Ok(Value::Map(entries))
+    }
+
+    /// Parses the rest of a string, after the opening quote
+    fn string(&mut self) -> anyhow::Result<String> {
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                None => bail!("unterminated string"),
+                Some('"') => return Ok(string),
+                Some('\\') => {
+                    let escaped = match self.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => self.unicode_escape()?,
+                        Some(c @ ('"' | '\\' | '/')) => c,
+                        other => bail!("invalid string escape {other:?}"),
+                    };
+                    string.push(escaped);
+                }
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    /// Parses the rest of a regex literal, after the opening quote, keeping its escapes as is
+    fn regex(&mut self) -> anyhow::Result<String> {
+        let mut regex = String::new();
+        loop {
+            match self.next() {
+                None => bail!("unterminated regex"),
+                Some('"') => return Ok(regex),
+                Some('\\') => {
+                    let Some(escaped) = self.next() else {
+                        bail!("unterminated regex");
+                    };
+                    if escaped != '"' {
+                        regex.push('\\');
+                    }
+                    regex.push(escaped);
+                }
+                Some(c) => regex.push(c),
+            }
+        }
+    }
+
+    /// Parses the 4 hex digits of a `\u` escape
+    fn unicode_escape(&mut self) -> anyhow::Result<char> {
+        let Some(digits) = self.rest().get(..4) else {
+            bail!("incomplete unicode escape");
+        };
+        let code = u32::from_str_radix(digits, 16)
+            .with_context(|| format!("invalid unicode escape {digits:?}"))?;
+        self.position += 4;
+        char::from_u32(code).with_context(|| format!("invalid unicode character {code:#x}"))
+    }
+
+    /// Parses the rest of a character, after the backslash
+    fn char(&mut self) -> anyhow::Result<Value> {
+        let Some(first) = self.next() else {
+            bail!("unexpected end of input in character");
+        };
+        // a single character, unless followed by more of a name (e.g. `\newline`)
+        let name = format!("{first}{}", self.token());
+        let c = match name.as_str() {
+            "newline" => '\n',
+            "return" => '\r',
+            "space" => ' ',
+            "tab" => '\t',
+            "formfeed" => '\u{c}',
+            "backspace" => '\u{8}',
+            name if name.len() == 5 && name.starts_with('u') => {
+                let code = u32::from_str_radix(&name[1..], 16)
+                    .with_context(|| format!("invalid character \\{name}"))?;
+                char::from_u32(code).with_context(|| format!("invalid character \\{name}"))?
+            }
+            _ if name.chars().count() == 1 => first,
+            _ => bail!("unknown character \\{name}"),
+        };
+        Ok(Value::Char(c))
+    }
+
+    /// Consumes the characters up to the next delimiter
+    fn token(&mut self) -> String {
+        let rest = self.rest();
+        let end = rest
+            .find(|c: char| c.is_whitespace() || "()[]{}\",;".contains(c))
+            .unwrap_or(rest.len());
+        let token = rest[..end].to_string();
+        self.position += end;
+        token
+    }
+
+    /// Parses a number, `nil`, `true`, `false` or a symbol
+    fn atom(&mut self) -> anyhow::Result<Value> {
+        let token = self.token();
+        match token.as_str() {
+            "" => bail!("unexpected {:?}", self.peek()),
+            "nil" => return Ok(Value::Nil),
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            _ => {}
+        }
+        let mut chars = token.chars();
+        let first = chars.next().unwrap_or_default();
+        let numeric = first.is_ascii_digit()
+            || (matches!(first, '+' | '-') && chars.next().is_some_and(|c| c.is_ascii_digit()));
+        if !numeric {
+            return Ok(Value::Symbol(token));
+        }
+        number(&token).with_context(|| format!("invalid number {token:?}"))
+    }
+}
+
+/// Parses an integer (with optional `N` suffix), float (with optional `M` suffix) or ratio
+fn number(token: &str) -> anyhow::Result<Value> {
+    if let Some((numerator, denominator)) = token.split_once('/') {
+        let numerator: f64 = numerator.parse()?;
+        let denominator: f64 = denominator.parse()?;
+        return Ok(Value::Float(numerator / denominator));
+    }
+    if let Some(integer) = token.strip_suffix('N') {
+        let digits = integer.trim_start_matches(['+', '-']);
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            bail!("not an integer");
+        }
+        return Ok(match integer.parse() {
+            Ok(integer) => Value::Int(integer),
+            Err(_) => Value::Float(integer.parse()?),
+        });
+    }
+    if let Some(decimal) = token.strip_suffix('M') {
+        return Ok(Value::Float(decimal.parse()?));
+    }
+    if let Ok(integer) = token.parse() {
+        return Ok(Value::Int(integer));
+    }
+    Ok(Value::Float(token.parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyword(name: &str) -> Value {
+        Value::Keyword(name.to_string())
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse("42").unwrap(), Value::Int(42));
+        assert_eq!(parse("-7").unwrap(), Value::Int(-7));
+        assert_eq!(parse("+3").unwrap(), Value::Int(3));
+        assert_eq!(parse("2.5").unwrap(), Value::Float(2.5));
+        assert_eq!(parse("1e3").unwrap(), Value::Float(1000.0));
+        assert_eq!(parse("-x").unwrap(), Value::Symbol("-x".to_string()));
+        assert!(parse("1x").is_err());
+    }
+
+    #[test]
+    fn ratios() {
+        assert_eq!(parse("1/2").unwrap(), Value::Float(0.5));
+        assert_eq!(parse("-3/4").unwrap(), Value::Float(-0.75));
+        assert_eq!(parse("22/7").unwrap().as_f64(), Some(22.0 / 7.0));
+        assert!(parse("1/").is_err());
+        assert!(parse("1/x").is_err());
+    }
+
+    #[test]
+    fn suffixes() {
+        assert_eq!(parse("42N").unwrap(), Value::Int(42));
+        // beyond i64, so approximated
+        assert_eq!(
+            parse("123456789012345678901234567890N").unwrap(),
+            Value::Float(1.2345678901234568e29)
+        );
+        assert_eq!(parse("1.5M").unwrap(), Value::Float(1.5));
+        assert_eq!(parse("3M").unwrap(), Value::Float(3.0));
+        assert!(parse("1.5N").is_err());
+    }
+
+    #[test]
+    fn symbolic_values() {
+        assert_eq!(parse("##Inf").unwrap(), Value::Float(f64::INFINITY));
+        assert_eq!(parse("##-Inf").unwrap(), Value::Float(f64::NEG_INFINITY));
+        assert!(matches!(parse("##NaN").unwrap(), Value::Float(value) if value.is_nan()));
+        assert!(parse("##Foo").is_err());
+    }
+
+    #[test]
+    fn discards() {
+        assert_eq!(
+            parse("[1 #_2 3]").unwrap(),
+            Value::Vector(vec![Value::Int(1), Value::Int(3)])
+        );
+        // discards nest, each discarding the next value
+        assert_eq!(
+            parse("[#_ #_ 1 2 3]").unwrap(),
+            Value::Vector(vec![Value::Int(3)])
+        );
+        assert_eq!(
+            parse("{:a 1 #_:b #_{:c [2]}}").unwrap(),
+            Value::Map(vec![(keyword("a"), Value::Int(1))])
+        );
+        assert_eq!(parse("#_(ignored) nil").unwrap(), Value::Nil);
+        assert!(parse("[1 #_]").is_err());
+    }
+
+    #[test]
+    fn comments_and_commas() {
+        let value = parse("; leading\n[1, 2 ; trailing\n ,3]").unwrap();
+        assert_eq!(
+            value,
+            Value::Vector(vec![Value::Int(1), Value::Int(2), Value::Int(3)])
+        );
+    }
+
+    #[test]
+    fn tagged() {
+        assert_eq!(
+            parse(r#"#inst "2024-01-01T00:00:00Z""#).unwrap(),
+            Value::Tagged(
+                "inst".to_string(),
+                Box::new(Value::String("2024-01-01T00:00:00Z".to_string()))
+            )
+        );
+        let record = parse("#jepsen.history.Op{:index 3, :type :ok}").unwrap();
+        let Value::Tagged(tag, _) = &record else {
+            panic!("not tagged: {record:?}");
+        };
+        assert_eq!(tag, "jepsen.history.Op");
+        // records are looked up like maps
+        assert_eq!(record.get("type"), Some(&keyword("ok")));
+        assert_eq!(record.get("index"), Some(&Value::Int(3)));
+        assert_eq!(
+            parse(r#"#"a\d+""#).unwrap(),
+            Value::Tagged(
+                "regex".to_string(),
+                Box::new(Value::String(r"a\d+".to_string()))
+            )
+        );
+        assert_eq!(
+            parse(r#"#"\"quoted\"""#).unwrap(),
+            Value::Tagged(
+                "regex".to_string(),
+                Box::new(Value::String(r#""quoted""#.to_string()))
+            )
+        );
+        assert!(parse("# 1").is_err());
+    }
+
+    #[test]
+    fn chars() {
+        let chars =
+            parse(r"[\a \A \newline \return \space \tab \formfeed \backspace \u0041 \( \\]")
+                .unwrap();
+        let expected = [
+            'a', 'A', '\n', '\r', ' ', '\t', '\u{c}', '\u{8}', 'A', '(', '\\',
+        ];
+        assert_eq!(
+            chars,
+            Value::Vector(expected.into_iter().map(Value::Char).collect())
+        );
+        assert!(parse(r"\foo").is_err());
+        assert!(parse(r"\uZZZZ").is_err());
+        assert!(parse(r"\").is_err());
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            parse(r#""a\"b\\c\né""#).unwrap(),
+            Value::String("a\"b\\c\né".to_string())
+        );
+        assert!(parse(r#""unterminated"#).is_err());
+        assert!(parse(r#""\q""#).is_err());
+        assert!(parse(r#""\u12""#).is_err());
+    }
+
+    #[test]
+    fn collections() {
+        let value = parse(r#"(nil true false "s" :k sym) #{1} {}"#);
+        assert!(value.is_err(), "trailing values are rejected");
+        let (list, rest) = parse_prefix(r#"(nil true false "s" :k sym) #{1}"#).unwrap();
+        assert_eq!(
+            list,
+            Value::List(vec![
+                Value::Nil,
+                Value::Bool(true),
+                Value::Bool(false),
+                Value::String("s".to_string()),
+                keyword("k"),
+                Value::Symbol("sym".to_string()),
+            ])
+        );
+        assert_eq!(parse(rest).unwrap(), Value::Set(vec![Value::Int(1)]));
+        assert!(parse("{:a}").is_err());
+        assert!(parse("[1 2").is_err());
+        assert!(parse("]").is_err());
+    }
+
+    #[test]
+    fn error_location() {
+        let error = parse("{:a 1,\n :b [1 2}").unwrap_err();
+        assert_eq!(error.to_string(), "invalid EDN at line 2 column 9");
+    }
+
+    #[test]
+    fn lookup() {
+        let value = parse("{:net {:all {:msgs-per-op 12.5}}, 0.5 7, \"s\" 1}").unwrap();
+        assert_eq!(
+            value
+                .get_path(&["net", "all", "msgs-per-op"])
+                .and_then(Value::as_f64),
+            Some(12.5)
+        );
+        assert_eq!(value.get_path(&["net", "servers", "msgs-per-op"]), None);
+        assert_eq!(
+            value.get_entry(|key| key.as_f64() == Some(0.5)),
+            Some(&Value::Int(7))
+        );
+        // only keywords match
+        assert_eq!(value.get("s"), None);
+        assert_eq!(Value::Int(1).get("a"), None);
+    }
+}
@@ -0,0 +1,117 @@
+//! Checks the performance of a Maelstrom run against goals, from its `results.edn` or stdout
+
+use anyhow::{bail, Context};
+use report::{Goals, Report};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use telephone_line::config::{self, Config};
+
+mod edn;
+mod report;
+
+/// Runtime configuration of the analysis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Params {
+    /// Maelstrom's `results.edn` or stdout, or `None` to read stdin
+    input: Option<String>,
+    /// Name of the test case, shown in the report
+    label: Option<String>,
+    format: Format,
+    msgs_per_op: Option<f64>,
+    /// Goal for the median stable latency (ms)
+    latency_median: Option<f64>,
+    /// Goal for the maximum stable latency (ms)
+    latency_max: Option<f64>,
+}
+const PARAMS_DEFAULT: Params = Params {
+    input: None,
+    label: None,
+    format: Format::Text,
+    msgs_per_op: None,
+    latency_median: None,
+    latency_max: None,
+};
+impl Config for Params {
+    const ENV_PREFIX: &'static str = "ANALYSIS";
+    const PRESETS: &'static [(&'static str, Self)] = &[("default", PARAMS_DEFAULT)];
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let goals = [self.msgs_per_op, self.latency_median, self.latency_max];
+        if goals.into_iter().flatten().any(|goal| goal.is_nan()) {
+            bail!("goals must be numbers");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Format {
+    Text,
+    Json,
+    Markdown,
+}
+
+/// Finds the results map in `input`, which is either the map alone (`results.edn`) or
+/// Maelstrom's stdout ending with it
+fn find_results(input: &str) -> anyhow::Result<edn::Value> {
+    if let Ok(results) = edn::parse(input) {
+        return Ok(results);
+    }
+    // NOTE: the results are printed starting at the last line which opens a map, and may be
+    // followed by a summary line
+    let starts = input
+        .match_indices('{')
+        .map(|(index, _)| index)
+        .filter(|&index| index == 0 || input[..index].ends_with('\n'));
+    let mut last_error = None;
+    for start in starts.collect::<Vec<_>>().into_iter().rev() {
+        match edn::parse_prefix(&input[start..]) {
+            Ok((results @ edn::Value::Map(_), _)) if results.get("valid?").is_some() => {
+                return Ok(results)
+            }
+            Ok(_) => {}
+            Err(error) => last_error = Some(error),
+        }
+    }
+    match last_error {
+        Some(error) => Err(error.context("no results map found")),
+        None => bail!("no results map found"),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let params = config::load::<Params>(std::env::args().skip(1))?;
+
+    let input = match &params.input {
+        Some(path) => {
+            std::fs::read_to_string(path).with_context(|| format!("read input {path:?}"))?
+        }
+        None => {
+            let mut input = String::new();
+            std::io::stdin()
+                .read_to_string(&mut input)
+                .context("read stdin")?;
+            input
+        }
+    };
+    let results = find_results(&input)?;
+
+    let goals = Goals {
+        msgs_per_op: params.msgs_per_op,
+        latency_median: params.latency_median,
+        latency_max: params.latency_max,
+    };
+    let report = Report::new(params.label, &results, goals);
+    match params.format {
+        Format::Text => println!("{}", report.to_text()),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        Format::Markdown => println!("{}", report.to_markdown()),
+    }
+
+    if !report.passed {
+        bail!("analysis check failed");
+    }
+    Ok(())
+}
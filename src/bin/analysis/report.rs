@@ -0,0 +1,314 @@
+//! Metrics of a Maelstrom run, checked against goals
+
+use crate::edn::Value;
+use serde::Serialize;
+
+/// Goals which each metric should be below, if set
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Goals {
+    pub msgs_per_op: Option<f64>,
+    pub latency_median: Option<f64>,
+    pub latency_max: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub label: Option<String>,
+    /// Maelstrom's verdict on the workload's correctness
+    pub valid: Option<bool>,
+    pub checks: Vec<Check>,
+    pub passed: bool,
+}
+
+/// Metric of the run, with the goal it should be below (if any)
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub units: &'static str,
+    /// Value of the metric, if present in the results
+    pub value: Option<f64>,
+    pub goal: Option<f64>,
+    pub passed: bool,
+}
+
+impl Report {
+    /// Extracts the metrics from Maelstrom's `results`, and checks them against `goals`
+    pub fn new(label: Option<String>, results: &Value, goals: Goals) -> Self {
+        let valid = match results.get("valid?") {
+            Some(&Value::Bool(valid)) => Some(valid),
+            _ => None,
+        };
+        let stable_latency = |quantile: f64| {
+            results
+                .get_path(&["workload", "stable-latencies"])?
+                .get_entry(|key| key.as_f64() == Some(quantile))?
+                .as_f64()
+        };
+        let msgs_per_op = |group| results.get_path(&["net", group, "msgs-per-op"])?.as_f64();
+        let metrics = [
+            (
+                "Messages per op (all)",
+                "messages per op",
+                msgs_per_op("all"),
+                goals.msgs_per_op,
+            ),
+            (
+                "Messages per op (servers)",
+                "messages per op",
+                msgs_per_op("servers"),
+                goals.msgs_per_op,
+            ),
+            (
+                "Latency Median",
+                "ms",
+                stable_latency(0.5),
+                goals.latency_median,
+            ),
+            ("Latency Max", "ms", stable_latency(1.0), goals.latency_max),
+        ];
+
+        let checks: Vec<_> = metrics
+            .into_iter()
+            .map(|(name, units, value, goal)| Check {
+                name,
+                units,
+                value,
+                goal,
+                // NOTE: a missing metric fails its goal, rather than silently passing
+                passed: goal.is_none_or(|goal| value.is_some_and(|value| value < goal)),
+            })
+            .collect();
+        let passed = valid != Some(false) && checks.iter().all(|check| check.passed);
+        Self {
+            label,
+            valid,
+            checks,
+            passed,
+        }
+    }
+
+    /// Formats as the lines printed by the analysis scripts
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![];
+        if let Some(label) = &self.label {
+            lines.push(format!("Analysis of {label}"));
+        }
+        match self.valid {
+            Some(true) => lines.push("[PASS] Maelstrom results are valid".to_string()),
+            Some(false) => lines.push("[FAIL] Maelstrom results are invalid".to_string()),
+            None => lines.push("[????] Maelstrom results have no :valid? verdict".to_string()),
+        }
+        for check in &self.checks {
+            let Check {
+                name,
+                units,
+                value,
+                goal,
+                passed,
+            } = check;
+            let line = match (value, goal) {
+                (None, None) => format!("[----] {name}: missing"),
+                (None, Some(goal)) => format!(
+                    "[FAIL] {name} is missing (should be below goal of {goal} {units})"
+                ),
+                (Some(value), None) => format!("[----] {name}: {value} {units}"),
+                (Some(value), Some(goal)) if *passed => {
+                    format!("[PASS] {name}: {value} (below goal of {goal} {units})")
+                }
+                (Some(value), Some(goal)) => format!(
+                    "[FAIL] {name} is out of range: {value} (should be below goal of {goal} {units})"
+                ),
+            };
+            lines.push(line);
+        }
+        lines.push(if self.passed {
+            "Output analysis check passed.".to_string()
+        } else {
+            "Output analysis check failed.".to_string()
+        });
+        lines.join("\n")
+    }
+
+    /// Formats as a Markdown table, e.g. for a CI summary
+    pub fn to_markdown(&self) -> String {
+        let mut lines = vec![];
+        let title = self.label.as_deref().unwrap_or("Maelstrom run");
+        let verdict = if self.passed { "passed" } else { "failed" };
+        lines.push(format!("### Analysis of {title}: {verdict}"));
+        lines.push(String::new());
+        lines.push("| Check | Value | Goal | Result |".to_string());
+        lines.push("| --- | --- | --- | --- |".to_string());
+        let valid = match self.valid {
+            Some(true) => ("true", "PASS"),
+            Some(false) => ("false", "FAIL"),
+            None => ("missing", "-"),
+        };
+        lines.push(format!("| Valid | {} | true | {} |", valid.0, valid.1));
+        for check in &self.checks {
+            let value = check.value.map_or("missing".to_string(), |value| {
+                format!("{value} {}", check.units)
+            });
+            let (goal, result) = match check.goal {
+                Some(goal) => (
+                    format!("< {goal} {}", check.units),
+                    if check.passed { "PASS" } else { "FAIL" },
+                ),
+                None => ("-".to_string(), "-"),
+            };
+            lines.push(format!("| {} | {value} | {goal} | {result} |", check.name));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edn;
+
+    /// Excerpt of the `results.edn` of a Maelstrom broadcast run
+    const RESULTS: &str = r#"{:perf {:latency-graph {:valid? true},
+        :rate-graph {:valid? true},
+        :valid? true},
+ :timeline {:valid? true},
+ :exceptions {:valid? true},
+ :stats {:valid? true,
+         :count 1093,
+         :ok-count 1093,
+         :fail-count 0,
+         :info-count 0,
+         :by-f {:broadcast {:valid? true,
+                            :count 545,
+                            :ok-count 545,
+                            :fail-count 0,
+                            :info-count 0},
+                :read {:valid? true,
+                       :count 548,
+                       :ok-count 548,
+                       :fail-count 0,
+                       :info-count 0}}},
+ :availability {:valid? true, :ok-fraction 1.0},
+ :net {:all {:send-count 23850,
+             :recv-count 23850,
+             :msg-count 23850,
+             :msgs-per-op 21.820679},
+       :clients {:send-count 2286, :recv-count 2286, :msg-count 2286},
+       :servers {:send-count 21564,
+                 :recv-count 21564,
+                 :msg-count 21564,
+                 :msgs-per-op 19.729185},
+       :valid? true},
+ :workload {:worst-stale (),
+            :duplicated-count 0,
+            :valid? true,
+            :lost-count 0,
+            :lost (),
+            :stable-count 545,
+            :stale-count 0,
+            :stale (),
+            :never-read-count 0,
+            :stable-latencies {0 0, 0.5 312, 0.95 540, 0.99 583, 1 601},
+            :attempt-count 545,
+            :never-read (),
+            :duplicated {}},
+ :valid? true}
+"#;
+
+    fn results() -> Value {
+        edn::parse(RESULTS).unwrap()
+    }
+
+    fn values(report: &Report) -> Vec<Option<f64>> {
+        report.checks.iter().map(|check| check.value).collect()
+    }
+
+    #[test]
+    fn extracts_metrics() {
+        let report = Report::new(None, &results(), Goals::default());
+        assert_eq!(report.valid, Some(true));
+        assert_eq!(
+            values(&report),
+            [Some(21.820679), Some(19.729185), Some(312.0), Some(601.0)]
+        );
+        // no goals, so only the verdict counts
+        assert!(report.passed);
+        assert!(report.checks.iter().all(|check| check.passed));
+    }
+
+    #[test]
+    fn passes_below_goals() {
+        let goals = Goals {
+            msgs_per_op: Some(30.0),
+            latency_median: Some(400.0),
+            latency_max: Some(650.0),
+        };
+        let report = Report::new(Some("broadcast".to_string()), &results(), goals);
+        assert!(report.passed);
+        let text = report.to_text();
+        assert!(text.starts_with("Analysis of broadcast\n[PASS] Maelstrom results are valid"));
+        assert!(text.contains("[PASS] Latency Max: 601 (below goal of 650 ms)"));
+        assert!(text.ends_with("Output analysis check passed."));
+        assert!(report
+            .to_markdown()
+            .starts_with("### Analysis of broadcast: passed"));
+    }
+
+    #[test]
+    fn fails_at_or_above_goals() {
+        let goals = Goals {
+            msgs_per_op: Some(20.0),
+            latency_median: Some(312.0),
+            latency_max: None,
+        };
+        let report = Report::new(None, &results(), goals);
+        assert!(!report.passed);
+        let passed: Vec<_> = report.checks.iter().map(|check| check.passed).collect();
+        // all ops exceed the goal, but the servers alone don't; the goal is exclusive
+        assert_eq!(passed, [false, true, false, true]);
+        let text = report.to_text();
+        assert!(text.contains(
+            "[FAIL] Messages per op (all) is out of range: 21.820679 \
+             (should be below goal of 20 messages per op)"
+        ));
+        assert!(text.contains("[----] Latency Max: 601 ms"));
+        assert!(text.ends_with("Output analysis check failed."));
+        assert!(report
+            .to_markdown()
+            .contains("| Latency Median | 312 ms | < 312 ms | FAIL |"));
+    }
+
+    #[test]
+    fn invalid_results_fail() {
+        let results =
+            edn::parse(&RESULTS.replace(" :valid? true}\n", " :valid? false}\n")).unwrap();
+        let report = Report::new(None, &results, Goals::default());
+        assert_eq!(report.valid, Some(false));
+        assert!(!report.passed);
+        assert!(report
+            .to_text()
+            .contains("[FAIL] Maelstrom results are invalid"));
+    }
+
+    #[test]
+    fn missing_metrics_fail_their_goals() {
+        let results = edn::parse("{:net {:all {:msgs-per-op 3}}}").unwrap();
+        let goals = Goals {
+            msgs_per_op: Some(5.0),
+            latency_median: None,
+            latency_max: Some(100.0),
+        };
+        let report = Report::new(None, &results, goals);
+        assert_eq!(report.valid, None);
+        assert_eq!(values(&report), [Some(3.0), None, None, None]);
+        let passed: Vec<_> = report.checks.iter().map(|check| check.passed).collect();
+        assert_eq!(passed, [true, false, true, false]);
+        assert!(!report.passed);
+        let text = report.to_text();
+        assert!(text.contains("[????] Maelstrom results have no :valid? verdict"));
+        assert!(text.contains(
+            "[FAIL] Messages per op (servers) is missing \
+             (should be below goal of 5 messages per op)"
+        ));
+        assert!(text.contains("[----] Latency Median: missing"));
+    }
+}
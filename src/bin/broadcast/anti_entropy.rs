@@ -0,0 +1,221 @@
+//! Anti-entropy between neighbors: a node keeps a summary of the values each peer is known to
+//! have (a [`RangeSet`], compact for the mostly consecutive values maelstrom broadcasts),
+//! updated from the peer's gossip and acknowledgements, and gossips only the delta beyond it
+//!
+//! Gossip may be lost, so the summary can lag behind what was sent. Depending on the
+//! [`GossipMode`](super::GossipMode), the delta is resent with backoff until acknowledged
+//! ([`AntiEntropy::should_send_acked`]), or a random sample of the values the peer is believed
+//! to know is resent with each delta ([`AntiEntropy::sample_known`]).
+
+use anyhow::bail;
+use rand::rngs::StdRng;
+use std::collections::BTreeMap;
+use telephone_line::{range_set::RangeSet, Error};
+
+pub struct AntiEntropy {
+    /// Values each node is known to have
+    known: BTreeMap<String, RangeSet>,
+    /// Unacknowledged gossip to each neighbor
+    unacked: BTreeMap<String, Unacked>,
+}
+
+/// Gossip sent to a neighbor, awaiting its `gossip_ok`
+struct Unacked {
+    messages: RangeSet,
+    /// Gossip ticks to wait before resending
+    wait_ticks: u32,
+    /// Gossip ticks waited before the latest resend
+    backoff_ticks: u32,
+}
+
+impl AntiEntropy {
+    /// Creates summaries for the nodes `node_ids`, each initially knowing nothing
+    pub fn new(node_ids: &[String]) -> Self {
+        Self {
+            known: node_ids
+                .iter()
+                .map(|node_id| (node_id.clone(), RangeSet::new()))
+                .collect(),
+            unacked: BTreeMap::new(),
+        }
+    }
+
+    /// Records that `peer` has `messages` (from its gossip, or its acknowledgement of ours)
+    ///
+    /// Fails with a [`NotSupported`](telephone_line::ErrorCode::NotSupported) error if `peer`
+    /// is not in the cluster (e.g. a client sending gossip).
+    pub fn knows(&mut self, peer: &str, messages: &RangeSet) -> anyhow::Result<()> {
+        let Some(known) = self.known.get_mut(peer) else {
+            return Err(Error::not_supported(format!("gossip from unknown node {peer}")).into());
+        };
+        known.union(messages);
+        if self
+            .unacked
+            .get(peer)
+            .is_some_and(|unacked| unacked.messages.difference(known).is_empty())
+        {
+            self.unacked.remove(peer);
+        }
+        Ok(())
+    }
+
+    /// Returns the values of `messages` which `peer` is not known to have
+    pub fn delta(&self, peer: &str, messages: &RangeSet) -> anyhow::Result<RangeSet> {
+        let Some(known) = self.known.get(peer) else {
+            bail!("unknown neighbor {peer}");
+        };
+        Ok(messages.difference(known))
+    }
+
+    /// Returns true if `delta` should be sent to `peer` now, backing off (up to
+    /// `max_backoff_ticks`) while resending the same unacknowledged values
+    ///
+    /// Call once per gossip tick (or eager push) for each neighbor.
+    pub fn should_send_acked(
+        &mut self,
+        peer: &str,
+        delta: &RangeSet,
+        max_backoff_ticks: u32,
+    ) -> bool {
+        if delta.is_empty() {
+            self.unacked.remove(peer);
+            return false;
+        }
+        match self.unacked.get_mut(peer) {
+            Some(unacked) if delta.difference(&unacked.messages).is_empty() => {
+                if unacked.wait_ticks > 1 {
+                    unacked.wait_ticks -= 1;
+                    return false;
+                }
+                unacked.backoff_ticks = (unacked.backoff_ticks * 2).min(max_backoff_ticks);
+                unacked.wait_ticks = unacked.backoff_ticks;
+            }
+            // new values, so send without waiting
+            _ => {
+                let unacked = Unacked {
+                    messages: delta.clone(),
+                    wait_ticks: 1,
+                    backoff_ticks: 1,
+                };
+                self.unacked.insert(peer.to_string(), unacked);
+            }
+        }
+        true
+    }
+
+    /// Adds to `delta` a random sample of `cap` values (or all, if fewer) of `messages` which
+    /// `peer` is believed to know, so that lost gossip is eventually resent
+    ///
+    /// Takes time in the number of ranges and the sample size, not the number of values.
+    pub fn sample_known(
+        &self,
+        peer: &str,
+        messages: &RangeSet,
+        mut delta: RangeSet,
+        cap: u32,
+        rng: &mut StdRng,
+    ) -> anyhow::Result<RangeSet> {
+        let Some(known) = self.known.get(peer) else {
+            bail!("unknown neighbor {peer}");
+        };
+        let already_known = messages.intersection(known);
+        let already_known_len = already_known.len();
+        let amount =
+            usize::try_from(cap).map_or(already_known_len, |cap| cap.min(already_known_len));
+        let mut offsets = rand::seq::index::sample(rng, already_known_len, amount).into_vec();
+        offsets.sort_unstable();
+
+        // walk the ranges once, finding the value at each (ascending) offset
+        let mut offsets = offsets.into_iter().peekable();
+        let mut range_offset = 0;
+        for range in already_known.ranges() {
+            let (start, end) = range.into_inner();
+            let range_len = (end - start).saturating_add(1);
+            while let Some(offset) = offsets.next_if(|&offset| offset - range_offset < range_len) {
+                delta.insert(start + (offset - range_offset));
+            }
+            range_offset = range_offset.saturating_add(range_len);
+        }
+        Ok(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn set(values: impl IntoIterator<Item = usize>) -> RangeSet {
+        values.into_iter().collect()
+    }
+
+    fn anti_entropy() -> AntiEntropy {
+        AntiEntropy::new(&["n0".to_string(), "n1".to_string()])
+    }
+
+    #[test]
+    fn delta_excludes_known_values() {
+        let mut anti_entropy = anti_entropy();
+        let messages = set(0..10);
+        anti_entropy.knows("n1", &set(2..5)).unwrap();
+        anti_entropy.knows("n1", &set([8, 20])).unwrap();
+        let delta = anti_entropy.delta("n1", &messages).unwrap();
+        assert_eq!(delta, set([0, 1, 5, 6, 7, 9]));
+        assert_eq!(anti_entropy.delta("n0", &messages).unwrap(), messages);
+        assert!(anti_entropy.delta("n9", &messages).is_err());
+        let error = anti_entropy.knows("n9", &messages).unwrap_err();
+        let error = error.downcast::<Error>().unwrap();
+        assert_eq!(error.code, telephone_line::ErrorCode::NotSupported);
+    }
+
+    #[test]
+    fn unacked_delta_is_resent_with_backoff() {
+        let mut anti_entropy = anti_entropy();
+        let delta = set(0..3);
+        let sent: Vec<_> = (0..16)
+            .map(|_| anti_entropy.should_send_acked("n1", &delta, 4))
+            .collect();
+        let ticks: Vec<_> = (0..16).filter(|&tick| sent[tick]).collect();
+        // waits 1, 2, 4, then at most 4 ticks between resends
+        assert_eq!(ticks, [0, 1, 3, 7, 11, 15]);
+
+        // new values are sent without waiting
+        assert!(!anti_entropy.should_send_acked("n1", &delta, 4));
+        assert!(anti_entropy.should_send_acked("n1", &set(0..4), 4));
+    }
+
+    #[test]
+    fn acknowledgement_stops_resends() {
+        let mut anti_entropy = anti_entropy();
+        let messages = set(0..3);
+        assert!(anti_entropy.should_send_acked("n1", &messages, 4));
+        anti_entropy.knows("n1", &messages).unwrap();
+        let delta = anti_entropy.delta("n1", &messages).unwrap();
+        assert!(!anti_entropy.should_send_acked("n1", &delta, 4));
+        assert!(anti_entropy.unacked.is_empty());
+    }
+
+    #[test]
+    fn sample_resends_some_known_values() {
+        let mut anti_entropy = anti_entropy();
+        let messages = set(0..100);
+        anti_entropy.knows("n1", &set(0..90)).unwrap();
+        let delta = anti_entropy.delta("n1", &messages).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let sampled = anti_entropy
+            .sample_known("n1", &messages, delta.clone(), 9, &mut rng)
+            .unwrap();
+        assert!(delta.difference(&sampled).is_empty());
+        let resent = sampled.difference(&delta);
+        assert_eq!(resent.len(), 9);
+        assert!(resent.difference(&set(0..90)).is_empty(), "{resent:?}");
+
+        // samples across ranges, taking all values when there are fewer than the cap
+        anti_entropy.knows("n1", &set([95, 97])).unwrap();
+        let messages = set([1, 2, 3, 50, 95, 96, 97, 1000]);
+        let sampled = anti_entropy
+            .sample_known("n1", &messages, RangeSet::new(), 100, &mut rng)
+            .unwrap();
+        assert_eq!(sampled, set([1, 2, 3, 50, 95, 97]));
+    }
+}
@@ -0,0 +1,412 @@
+use anti_entropy::AntiEntropy;
+use anyhow::bail;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+use telephone_line::{
+    config::{self, Config},
+    main_loop, next_msg_id,
+    range_set::RangeSet,
+    Body, EventSender, Message, Node, Output,
+};
+use topology::Strategy;
+
+mod anti_entropy;
+#[cfg(test)]
+mod tests;
+mod topology;
+
+struct Broadcast {
+    params: Params,
+    msg_id: usize,
+    node_id: String,
+    node_ids: Vec<String>,
+    /// Nodes to gossip to, chosen by [`Params::topology`]
+    neighbors: BTreeSet<String>,
+    rng: StdRng,
+    messages: RangeSet,
+    anti_entropy: AntiEntropy,
+    event_tx: EventSender<Event>,
+    /// Current interval between gossip ticks
+    gossip_interval: Duration,
+    /// Number of values learned since the last gossip tick
+    learned_since_tick: usize,
+    /// Smoothed number of values learned per second
+    learn_rate: f64,
+}
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Params {
+    topology: Strategy,
+    gossip: GossipMode,
+    #[serde(rename = "gossip_interval_ms", with = "config::millis")]
+    gossip_interval: Duration,
+    /// Maximum number of gossip ticks to wait before resending the same unacknowledged values
+    max_backoff_ticks: u32,
+    additional_cap_ratio: f64,
+    additional_cap_floor: u32,
+    /// Scheduling of gossip by load (from a first tick after `gossip_interval`), or `None` to
+    /// gossip every `gossip_interval`
+    adaptive: Option<Adaptive>,
+}
+impl Params {
+    fn calculate_cap(self, notify_of_len: usize) -> u32 {
+        let Params {
+            additional_cap_ratio,
+            additional_cap_floor,
+            ..
+        } = self;
+        ((notify_of_len as f64 * additional_cap_ratio) as u32) + additional_cap_floor
+    }
+}
+
+/// How gossip recovers from lost messages
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum GossipMode {
+    /// Neighbors reply `gossip_ok`, and unacknowledged values are resent with backoff (the
+    /// default)
+    Acked,
+    /// Gossip is not acknowledged, so a random sample of the values a neighbor is believed to
+    /// know are resent (capped by [`Params::calculate_cap`]), for comparison with `Acked`
+    Probabilistic,
+}
+
+/// Scheduling of gossip from the observed rate of new values: pushing each value immediately
+/// under light load (for latency), and coalescing values into fewer gossip messages under heavy
+/// load (for msgs-per-op)
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Adaptive {
+    /// Learn rate (values per second) up to which new values are pushed immediately
+    eager_max_rate: f64,
+    /// Largest delta for a neighbor which is pushed immediately
+    eager_max_delta: usize,
+    /// Number of new values to coalesce into each gossip tick
+    batch_size: f64,
+    #[serde(rename = "min_interval_ms", with = "config::millis")]
+    min_interval: Duration,
+    #[serde(rename = "max_interval_ms", with = "config::millis")]
+    max_interval: Duration,
+    /// Weight of the latest tick in the smoothed learn rate
+    rate_smoothing: f64,
+}
+
+const PARAMS_DEFAULT: Params = Params {
+    topology: Strategy::Mesh,
+    gossip: GossipMode::Acked,
+    gossip_interval: Duration::from_millis(530),
+    max_backoff_ticks: 8,
+    additional_cap_ratio: 0.1,
+    additional_cap_floor: 10,
+    adaptive: Some(Adaptive {
+        eager_max_rate: 10.0,
+        eager_max_delta: 4,
+        batch_size: 20.0,
+        min_interval: Duration::from_millis(150),
+        max_interval: Duration::from_millis(1500),
+        rate_smoothing: 0.3,
+    }),
+};
+const PARAMS_LOW_LATENCY: Params = Params {
+    gossip_interval: Duration::from_millis(400),
+    adaptive: None,
+    ..PARAMS_DEFAULT
+};
+const PARAMS_LOW_BANDWIDTH: Params = Params {
+    gossip_interval: Duration::from_millis(1500),
+    adaptive: None,
+    ..PARAMS_DEFAULT
+};
+const PARAMS_FIXED_INTERVAL: Params = Params {
+    adaptive: None,
+    ..PARAMS_DEFAULT
+};
+impl Config for Params {
+    const ENV_PREFIX: &'static str = "BROADCAST";
+    const PRESETS: &'static [(&'static str, Self)] = &[
+        ("default", PARAMS_DEFAULT),
+        ("low-latency", PARAMS_LOW_LATENCY),
+        ("low-bandwidth", PARAMS_LOW_BANDWIDTH),
+        ("fixed-interval", PARAMS_FIXED_INTERVAL),
+    ];
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.gossip_interval.is_zero() {
+            bail!("gossip_interval_ms must be positive");
+        }
+        if self.max_backoff_ticks == 0 {
+            bail!("max_backoff_ticks must be positive");
+        }
+        if !(self.additional_cap_ratio >= 0.0 && self.additional_cap_ratio.is_finite()) {
+            bail!("additional_cap_ratio must be a non-negative number");
+        }
+        let Some(adaptive) = self.adaptive else {
+            return Ok(());
+        };
+        if adaptive.min_interval.is_zero() || adaptive.min_interval > adaptive.max_interval {
+            bail!("adaptive.min_interval_ms must be positive and at most adaptive.max_interval_ms");
+        }
+        if !(adaptive.batch_size > 0.0 && adaptive.batch_size.is_finite()) {
+            bail!("adaptive.batch_size must be a positive number");
+        }
+        if adaptive.eager_max_rate.is_nan() || adaptive.eager_max_rate < 0.0 {
+            bail!("adaptive.eager_max_rate must be a non-negative number");
+        }
+        if !(adaptive.rate_smoothing > 0.0 && adaptive.rate_smoothing <= 1.0) {
+            bail!("adaptive.rate_smoothing must be in (0, 1]");
+        }
+        Ok(())
+    }
+}
+
+impl Node<Params> for Broadcast {
+    type Request = Request;
+    type Response = Response;
+    type Outbound = Outbound;
+    type Event = Event;
+
+    fn from_init(
+        init: telephone_line::Init,
+        msg_id: usize,
+        params: Params,
+        event_tx: EventSender<Self::Event>,
+    ) -> Self
+    where
+        Self: Sized,
+    {
+        if params.adaptive.is_some() {
+            event_tx.after(params.gossip_interval, Event::StartGossip);
+        } else {
+            event_tx.every(params.gossip_interval, || Event::StartGossip);
+        }
+        let neighbors = params
+            .topology
+            .neighbors(&init.node_id, &init.node_ids, None);
+        Self {
+            params,
+            msg_id,
+            node_id: init.node_id,
+            neighbors,
+            rng: event_tx.rng(),
+            anti_entropy: AntiEntropy::new(&init.node_ids),
+            node_ids: init.node_ids,
+            gossip_interval: params.gossip_interval,
+            learned_since_tick: 0,
+            learn_rate: 0.0,
+            messages: RangeSet::new(),
+            event_tx,
+        }
+    }
+
+    fn step_request(
+        &mut self,
+        message: Message<Self::Request>,
+        output: &mut Output<'_, Self::Outbound>,
+    ) -> anyhow::Result<()> {
+        match message.body.payload.clone() {
+            Request::Broadcast { message: value } => {
+                let learned = usize::from(self.messages.insert(value));
+
+                message
+                    .reply_with(Some(&mut self.msg_id), Outbound::BroadcastOk)
+                    .send(output)?;
+                self.learned(learned, output)
+            }
+            Request::Read => {
+                let messages = self.messages.iter().collect();
+                message
+                    .reply_with(Some(&mut self.msg_id), Outbound::ReadOk { messages })
+                    .send(output)
+            }
+            Request::Topology { topology } => {
+                if self.params.topology.uses_topology_message() {
+                    self.neighbors = self.params.topology.neighbors(
+                        &self.node_id,
+                        &self.node_ids,
+                        Some(&topology),
+                    );
+                }
+
+                message
+                    .reply_with(Some(&mut self.msg_id), Outbound::TopologyOk)
+                    .send(output)
+            }
+            Request::Gossip { messages } => {
+                // extend our knowledge of others (rejecting gossip from outside the cluster)
+                self.anti_entropy.knows(&message.src, &messages)?;
+                // extend our knowledge
+                let learned = messages.difference(&self.messages).len();
+                self.messages.union(&messages);
+
+                if message.body.msg_id.is_some() {
+                    // NOTE: acknowledge only what was received, so each ack costs as much as
+                    // its gossip (the sender learns the rest from this node's own gossip)
+                    message
+                        .reply_with(Some(&mut self.msg_id), Outbound::GossipOk { messages })
+                        .send(output)?;
+                }
+                self.learned(learned, output)
+            }
+        }
+    }
+
+    fn step_response(
+        &mut self,
+        message: Message<Self::Response>,
+        _output: &mut Output<'_, Self::Outbound>,
+    ) -> anyhow::Result<()> {
+        match message.body.payload {
+            Response::GossipOk { messages } => self.anti_entropy.knows(&message.src, &messages),
+        }
+    }
+
+    fn step_event(
+        &mut self,
+        event: Event,
+        output: &mut Output<'_, Self::Outbound>,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::StartGossip => {
+                if let Some(adaptive) = self.params.adaptive {
+                    self.tune_interval(adaptive);
+                    self.event_tx
+                        .after(self.gossip_interval, Event::StartGossip);
+                }
+                for neighbor in self.neighbors.clone() {
+                    self.gossip_to(neighbor, true, output)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl Broadcast {
+    /// Sends `neighbor` the values it is missing (unless backing off), plus a sample of the
+    /// values it is believed to know if `resend` (in [`GossipMode::Probabilistic`])
+    fn gossip_to(
+        &mut self,
+        neighbor: String,
+        resend: bool,
+        output: &mut Output<'_, Outbound>,
+    ) -> anyhow::Result<()> {
+        let notify_of = self.anti_entropy.delta(&neighbor, &self.messages)?;
+
+        let (msg_id, notify_of) = match self.params.gossip {
+            GossipMode::Acked => {
+                let max_backoff_ticks = self.params.max_backoff_ticks;
+                if !self
+                    .anti_entropy
+                    .should_send_acked(&neighbor, &notify_of, max_backoff_ticks)
+                {
+                    return Ok(());
+                }
+                (Some(next_msg_id(&mut self.msg_id)), notify_of)
+            }
+            GossipMode::Probabilistic if resend => {
+                let cap = self.params.calculate_cap(notify_of.len());
+                let notify_of = self.anti_entropy.sample_known(
+                    &neighbor,
+                    &self.messages,
+                    notify_of,
+                    cap,
+                    &mut self.rng,
+                )?;
+                (None, notify_of)
+            }
+            GossipMode::Probabilistic => (None, notify_of),
+        };
+        if notify_of.is_empty() {
+            return Ok(());
+        }
+        Message {
+            src: self.node_id.clone(),
+            dest: neighbor,
+            body: Body {
+                msg_id,
+                in_reply_to: None,
+                payload: Outbound::Gossip {
+                    messages: notify_of,
+                },
+            },
+        }
+        .send(output)
+    }
+
+    /// Counts `count` newly learned values, pushing them to neighbors immediately if
+    /// [`Params::adaptive`] finds the load light
+    fn learned(&mut self, count: usize, output: &mut Output<'_, Outbound>) -> anyhow::Result<()> {
+        self.learned_since_tick += count;
+        let Some(adaptive) = self.params.adaptive else {
+            return Ok(());
+        };
+        if count == 0 || self.learn_rate > adaptive.eager_max_rate {
+            return Ok(());
+        }
+        for neighbor in self.neighbors.clone() {
+            // NOTE: larger deltas are left to the next tick, which coalesces them
+            let delta = self.anti_entropy.delta(&neighbor, &self.messages)?;
+            if delta.len() <= adaptive.eager_max_delta {
+                self.gossip_to(neighbor, false, output)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the learn rate from the last tick, and sets the next interval to coalesce about
+    /// [`Adaptive::batch_size`] values per gossip
+    fn tune_interval(&mut self, adaptive: Adaptive) {
+        let tick_rate = self.learned_since_tick as f64 / self.gossip_interval.as_secs_f64();
+        self.learned_since_tick = 0;
+        self.learn_rate += adaptive.rate_smoothing * (tick_rate - self.learn_rate);
+
+        let interval = if self.learn_rate > 0.0 {
+            Duration::from_secs_f64(adaptive.batch_size / self.learn_rate)
+        } else {
+            adaptive.max_interval
+        };
+        self.gossip_interval = interval.clamp(adaptive.min_interval, adaptive.max_interval);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Broadcast {
+        message: usize,
+    },
+    Read,
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    Gossip {
+        messages: RangeSet,
+    },
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    GossipOk { messages: RangeSet },
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Outbound {
+    BroadcastOk,
+    ReadOk { messages: BTreeSet<usize> },
+    TopologyOk,
+    Gossip { messages: RangeSet },
+    GossipOk { messages: RangeSet },
+}
+
+#[derive(Serialize, Deserialize)]
+enum Event {
+    StartGossip,
+}
+
+fn main() -> anyhow::Result<()> {
+    let params = config::load::<Params>(std::env::args().skip(1))?;
+    main_loop::<Broadcast, _>(params)
+}
@@ -0,0 +1,297 @@
+//! Simulated clusters of broadcast nodes
+
+use super::*;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use telephone_line::simulator::Simulator;
+
+const NODE_COUNT: usize = 9;
+const VALUE_COUNT: usize = 30;
+
+/// Sends the maelstrom `topology` of a 3x3 grid to every node
+fn send_grid_topology(sim: &mut Simulator<Broadcast, Params>) {
+    let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+    let topology: serde_json::Map<_, _> = node_ids
+        .iter()
+        .enumerate()
+        .map(|(index, node_id)| {
+            let (row, column) = (index / 3, index % 3);
+            let neighbors: Vec<_> = [
+                (column > 0).then(|| index - 1),
+                (column < 2).then(|| index + 1),
+                (row > 0).then(|| index - 3),
+                (row < 2).then(|| index + 3),
+            ]
+            .into_iter()
+            .flatten()
+            .map(|neighbor| node_ids[neighbor].clone())
+            .collect();
+            (node_id.clone(), json!(neighbors))
+        })
+        .collect();
+    for node_id in &node_ids {
+        let payload = json!({"type": "topology", "topology": topology});
+        sim.send_client("c0", node_id, payload).unwrap();
+    }
+    sim.run_until_idle().unwrap();
+}
+
+/// Broadcasts the values to nodes in turn, then waits for gossip to spread them
+fn broadcast_values(sim: &mut Simulator<Broadcast, Params>) {
+    let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+    for value in 0..VALUE_COUNT {
+        let node_id = &node_ids[value * 4 % node_ids.len()];
+        let payload = json!({"type": "broadcast", "message": value});
+        let msg_id = sim.send_client("c1", node_id, payload).unwrap();
+        sim.run_for(Duration::from_millis(20)).unwrap();
+        let reply = sim.take_reply("c1", msg_id).unwrap();
+        assert_eq!(reply.body.payload, json!({"type": "broadcast_ok"}));
+    }
+    sim.run_for(Duration::from_secs(10)).unwrap();
+}
+
+/// Returns the values read from each node
+fn read_all(sim: &mut Simulator<Broadcast, Params>) -> Vec<BTreeSet<usize>> {
+    let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+    node_ids
+        .iter()
+        .map(|node_id| {
+            let msg_id = sim
+                .send_client("c2", node_id, json!({"type": "read"}))
+                .unwrap();
+            sim.run_until_idle().unwrap();
+            let reply = sim.take_reply("c2", msg_id).unwrap();
+            assert_eq!(reply.body.payload["type"], "read_ok");
+            serde_json::from_value::<Vec<usize>>(reply.body.payload["messages"].clone())
+                .unwrap()
+                .into_iter()
+                .collect()
+        })
+        .collect()
+}
+
+fn assert_converges(params: Params) {
+    let mut sim = Simulator::<Broadcast, _>::new(NODE_COUNT, params, 7).unwrap();
+    send_grid_topology(&mut sim);
+    broadcast_values(&mut sim);
+    let expected: BTreeSet<_> = (0..VALUE_COUNT).collect();
+    for messages in read_all(&mut sim) {
+        assert_eq!(messages, expected);
+    }
+}
+
+#[test]
+fn converges_with_each_preset() {
+    for (_, params) in Params::PRESETS {
+        assert_converges(*params);
+    }
+}
+
+#[test]
+fn converges_with_each_topology() {
+    for topology in ["mesh", "maelstrom", "spanning-tree", "grid", "tree:2:1"] {
+        assert_converges(Params {
+            topology: topology.parse().unwrap(),
+            ..PARAMS_DEFAULT
+        });
+    }
+}
+
+#[test]
+fn converges_with_each_gossip_mode() {
+    for gossip in [GossipMode::Acked, GossipMode::Probabilistic] {
+        assert_converges(Params {
+            gossip,
+            ..PARAMS_DEFAULT
+        });
+    }
+}
+
+/// Broadcasts with the grid split between its top row and the rest (as maelstrom's
+/// `--nemesis partition`), then heals it, returning the messages sent between nodes while
+/// partitioned, while healing, and after converging (asserting that every node then has every
+/// value)
+fn partition_and_heal(params: Params) -> [usize; 3] {
+    let mut sim = Simulator::<Broadcast, _>::new(NODE_COUNT, params, 7).unwrap();
+    send_grid_topology(&mut sim);
+    let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+    let top_row = node_ids[..3].to_vec();
+    sim.set_drop(move |src, dest, _at| {
+        let is_node = |id: &str| node_ids.iter().any(|node_id| node_id == id);
+        let in_top_row = |id: &str| top_row.iter().any(|node_id| node_id == id);
+        is_node(src) && is_node(dest) && in_top_row(src) != in_top_row(dest)
+    });
+    broadcast_values(&mut sim);
+    let partitioned = sim.network_stats();
+    assert!(partitioned.dropped > 0);
+    let values: Vec<_> = read_all(&mut sim).iter().map(BTreeSet::len).collect();
+    assert!(values.iter().all(|&len| len < VALUE_COUNT), "{values:?}");
+
+    sim.clear_drop();
+    sim.run_for(Duration::from_secs(20)).unwrap();
+    let healed = sim.network_stats();
+    let expected: BTreeSet<_> = (0..VALUE_COUNT).collect();
+    for messages in read_all(&mut sim) {
+        assert_eq!(messages, expected);
+    }
+
+    sim.run_for(Duration::from_secs(10)).unwrap();
+    let converged = sim.network_stats();
+    for messages in read_all(&mut sim) {
+        assert_eq!(messages, expected);
+    }
+    [
+        partitioned.sent,
+        healed.sent - partitioned.sent,
+        converged.sent - healed.sent,
+    ]
+}
+
+#[test]
+fn converges_after_partition_with_each_gossip_mode() {
+    for adaptive in [None, PARAMS_DEFAULT.adaptive] {
+        // both modes converge fully (asserted while healing and after converging)
+        let run = |gossip| {
+            partition_and_heal(Params {
+                topology: Strategy::Maelstrom,
+                gossip,
+                adaptive,
+                ..PARAMS_DEFAULT
+            })
+        };
+        let acked = run(GossipMode::Acked);
+        let probabilistic = run(GossipMode::Probabilistic);
+        // acked gossip backs off while partitioned, and goes quiet once every value is acked
+        let [acked_partitioned, acked_healing, acked_converged] = acked;
+        assert!(acked_healing > 0);
+        assert_eq!(acked_converged, 0);
+        // so it resends less than probabilistic gossip, which resends a sample of known values
+        // on every tick
+        let [probabilistic_partitioned, probabilistic_healing, probabilistic_converged] =
+            probabilistic;
+        assert!(acked_healing < probabilistic_healing);
+        assert!(acked_converged < probabilistic_converged);
+        assert!(acked.iter().sum::<usize>() < probabilistic.iter().sum::<usize>());
+        if adaptive.is_none() {
+            // NOTE: with adaptive gossip, each eager push of a new value is also acked
+            assert!(acked_partitioned < probabilistic_partitioned);
+        }
+    }
+}
+
+/// Returns the values each node has
+fn values(sim: &Simulator<Broadcast, Params>) -> Vec<Vec<usize>> {
+    let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+    node_ids
+        .iter()
+        .map(|node_id| sim.node(node_id).unwrap().messages.iter().collect())
+        .collect()
+}
+
+#[test]
+fn new_values_are_pushed_eagerly_under_light_load() {
+    let mut sim = Simulator::<Broadcast, _>::new(3, PARAMS_DEFAULT, 7).unwrap();
+    sim.send_client("c1", "n0", json!({"type": "broadcast", "message": 5}))
+        .unwrap();
+    // well before the first gossip tick
+    sim.run_for(Duration::from_millis(50)).unwrap();
+    assert_eq!(values(&sim), [[5], [5], [5]]);
+
+    // without adaptive gossip, values wait for the tick
+    let params = Params {
+        adaptive: None,
+        ..PARAMS_DEFAULT
+    };
+    let mut sim = Simulator::<Broadcast, _>::new(3, params, 7).unwrap();
+    sim.send_client("c1", "n0", json!({"type": "broadcast", "message": 5}))
+        .unwrap();
+    sim.run_for(Duration::from_millis(50)).unwrap();
+    assert_eq!(values(&sim), [vec![5], vec![], vec![]]);
+    sim.run_for(params.gossip_interval).unwrap();
+    assert_eq!(values(&sim), [[5], [5], [5]]);
+}
+
+#[test]
+fn gossip_interval_shortens_under_load_and_lengthens_when_idle() {
+    let adaptive = PARAMS_DEFAULT.adaptive.unwrap();
+    let mut sim = Simulator::<Broadcast, _>::new(3, PARAMS_DEFAULT, 7).unwrap();
+    let gossip_interval =
+        |sim: &Simulator<Broadcast, Params>| sim.node("n0").unwrap().gossip_interval;
+
+    // idle from the start
+    sim.run_for(Duration::from_secs(5)).unwrap();
+    assert_eq!(gossip_interval(&sim), adaptive.max_interval);
+
+    // 100 new values per second coalesce about `batch_size` values per tick
+    for value in 0..300 {
+        sim.send_client("c1", "n0", json!({"type": "broadcast", "message": value}))
+            .unwrap();
+        sim.run_for(Duration::from_millis(10)).unwrap();
+    }
+    let node = sim.node("n0").unwrap();
+    assert!(
+        node.learn_rate > adaptive.eager_max_rate,
+        "{}",
+        node.learn_rate
+    );
+    let loaded = gossip_interval(&sim);
+    assert!(loaded < PARAMS_DEFAULT.gossip_interval, "{loaded:?}");
+    assert!(loaded >= adaptive.min_interval, "{loaded:?}");
+    assert_eq!(values(&sim)[0].len(), 300);
+
+    // idle again
+    sim.run_for(Duration::from_secs(20)).unwrap();
+    assert_eq!(gossip_interval(&sim), adaptive.max_interval);
+    let expected: Vec<_> = (0..300).collect();
+    assert_eq!(values(&sim), vec![expected; 3]);
+}
+
+#[test]
+fn read_returns_a_plain_array() {
+    let mut sim = Simulator::<Broadcast, _>::new(1, PARAMS_DEFAULT, 0).unwrap();
+    for value in [3, 1, 2, 7] {
+        sim.send_client("c1", "n0", json!({"type": "broadcast", "message": value}))
+            .unwrap();
+    }
+    let msg_id = sim
+        .send_client("c1", "n0", json!({"type": "read"}))
+        .unwrap();
+    sim.run_until_idle().unwrap();
+    let reply = sim.take_reply("c1", msg_id).unwrap();
+    let expected: Value = json!({"type": "read_ok", "messages": [1, 2, 3, 7]});
+    assert_eq!(reply.body.payload, expected);
+}
+
+#[test]
+fn gossip_from_outside_the_cluster_is_rejected() {
+    let mut sim = Simulator::<Broadcast, _>::new(1, PARAMS_DEFAULT, 0).unwrap();
+    let payload = json!({"type": "gossip", "messages": [1, 2]});
+    let msg_id = sim.send_client("c1", "n0", payload).unwrap();
+    sim.run_until_idle().unwrap();
+    let reply = sim.take_reply("c1", msg_id).unwrap();
+    assert_eq!(reply.body.payload["code"], json!(10));
+
+    // the node keeps serving, without the rejected values
+    assert_eq!(read_all(&mut sim), [BTreeSet::new()]);
+}
+
+#[test]
+fn config_rejects_invalid_flags() {
+    let load = |args: &[&str]| config::load::<Params>(args.iter().map(|arg| arg.to_string()));
+
+    let error = format!("{:#}", load(&["--bogus", "1"]).err().unwrap());
+    assert!(error.contains("unknown field `bogus`"), "{error}");
+    let error = format!("{:#}", load(&["--topology", "tree:0"]).err().unwrap());
+    assert!(error.contains("tree arity must be positive"), "{error}");
+
+    assert!(load(&[]).unwrap().gossip == GossipMode::Acked);
+    let params = load(&["--gossip", "probabilistic", "--topology", "tree:2:1"]).unwrap();
+    assert!(params.gossip == GossipMode::Probabilistic);
+    assert_eq!(
+        params.topology,
+        Strategy::Tree {
+            arity: 2,
+            cross_links: 1
+        }
+    );
+}
@@ -0,0 +1,391 @@
+//! Strategies choosing the neighbors each node gossips to
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+/// Strategy for choosing the gossip neighbors of each node
+///
+/// (De)serializes as the string parsed by [`FromStr`](std::str::FromStr).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Strategy {
+    /// Every other node
+    Mesh,
+    /// Neighbors from the `topology` message sent by maelstrom
+    Maelstrom,
+    /// Breadth-first spanning tree of the maelstrom topology, rooted at the first node
+    SpanningTree,
+    /// Adjacent nodes in a square-ish grid
+    Grid,
+    /// Parent and children in a tree of the given arity, plus `cross_links` links to nodes
+    /// spread evenly across the cluster (shortening paths between distant branches)
+    Tree { arity: usize, cross_links: usize },
+}
+impl Strategy {
+    const TREE_ARITY_DEFAULT: usize = 4;
+    const TREE_CROSS_LINKS_DEFAULT: usize = 1;
+    /// Largest tree arity accepted, far beyond any useful cluster size
+    const TREE_ARITY_MAX: usize = 1024;
+    const TREE_CROSS_LINKS_MAX: usize = 64;
+
+    /// Returns true if the neighbors depend on the maelstrom `topology` message
+    pub fn uses_topology_message(self) -> bool {
+        matches!(self, Self::Maelstrom | Self::SpanningTree)
+    }
+
+    /// Returns the neighbors of `node_id`, given the maelstrom `topology` (if received)
+    pub fn neighbors(
+        self,
+        node_id: &str,
+        node_ids: &[String],
+        topology: Option<&HashMap<String, Vec<String>>>,
+    ) -> BTreeSet<String> {
+        // NOTE: sort so that all nodes agree on each node's position
+        let mut node_ids = node_ids.to_vec();
+        node_ids.sort();
+        let Some(index) = node_ids.iter().position(|id| id == node_id) else {
+            return BTreeSet::new();
+        };
+        // NOTE: the topology message may name nodes outside the cluster (or the node itself)
+        let in_cluster = |id: &String| id != node_id && node_ids.binary_search(id).is_ok();
+        let count = node_ids.len();
+        let indices: BTreeSet<usize> = match self {
+            Self::Mesh => (0..count).collect(),
+            Self::Maelstrom => {
+                let neighbors = topology.and_then(|topology| topology.get(node_id));
+                return neighbors
+                    .into_iter()
+                    .flatten()
+                    .filter(|id| in_cluster(id))
+                    .cloned()
+                    .collect();
+            }
+            Self::SpanningTree => {
+                let Some(topology) = topology else {
+                    return BTreeSet::new();
+                };
+                let mut neighbors =
+                    spanning_tree_neighbors(node_id, &node_ids[0], topology, &node_ids);
+                neighbors.retain(in_cluster);
+                return neighbors;
+            }
+            Self::Grid => {
+                let columns = (count as f64).sqrt().ceil() as usize;
+                let (row, column) = (index / columns, index % columns);
+                let mut indices = BTreeSet::new();
+                if column > 0 {
+                    indices.insert(index - 1);
+                }
+                if column + 1 < columns {
+                    indices.insert(index + 1);
+                }
+                if row > 0 {
+                    indices.insert(index - columns);
+                }
+                indices.insert(index + columns);
+                indices
+            }
+            Self::Tree { arity, cross_links } => {
+                let mut indices: BTreeSet<_> = (1..=arity)
+                    .map_while(|child| index.checked_mul(arity)?.checked_add(child))
+                    .take_while(|&child| child < count)
+                    .collect();
+                if index > 0 {
+                    indices.insert((index - 1) / arity);
+                }
+                // links in both directions, so that each link is used by both ends
+                for link in 1..=cross_links {
+                    let distance = count * link / (cross_links + 1);
+                    indices.insert((index + distance) % count);
+                    indices.insert((index + count - distance % count) % count);
+                }
+                indices
+            }
+        };
+        indices
+            .into_iter()
+            .filter(|&i| i != index)
+            .filter_map(|i| node_ids.get(i).cloned())
+            .collect()
+    }
+}
+impl std::str::FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    /// Parses `mesh`, `maelstrom`, `spanning-tree`, `grid`, or `tree[:ARITY[:CROSS_LINKS]]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap_or_default();
+        let strategy = match name {
+            "mesh" => Self::Mesh,
+            "maelstrom" => Self::Maelstrom,
+            "spanning-tree" => Self::SpanningTree,
+            "grid" => Self::Grid,
+            "tree" => {
+                let mut parse_next = |default| -> anyhow::Result<usize> {
+                    parts.next().map_or(Ok(default), |part| {
+                        part.parse()
+                            .with_context(|| format!("invalid number {part:?} in {s:?}"))
+                    })
+                };
+                let arity = parse_next(Self::TREE_ARITY_DEFAULT)?;
+                let cross_links = parse_next(Self::TREE_CROSS_LINKS_DEFAULT)?;
+                if arity == 0 {
+                    bail!("tree arity must be positive");
+                }
+                if arity > Self::TREE_ARITY_MAX {
+                    bail!("tree arity must be at most {}", Self::TREE_ARITY_MAX);
+                }
+                if cross_links > Self::TREE_CROSS_LINKS_MAX {
+                    let max = Self::TREE_CROSS_LINKS_MAX;
+                    bail!("tree cross_links must be at most {max}");
+                }
+                Self::Tree { arity, cross_links }
+            }
+            _ => bail!("unknown topology {s:?}"),
+        };
+        if let Some(extra) = parts.next() {
+            bail!("unexpected {extra:?} in topology {s:?}");
+        }
+        Ok(strategy)
+    }
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mesh => write!(f, "mesh"),
+            Self::Maelstrom => write!(f, "maelstrom"),
+            Self::SpanningTree => write!(f, "spanning-tree"),
+            Self::Grid => write!(f, "grid"),
+            Self::Tree { arity, cross_links } => write!(f, "tree:{arity}:{cross_links}"),
+        }
+    }
+}
+impl TryFrom<String> for Strategy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+impl From<Strategy> for String {
+    fn from(strategy: Strategy) -> Self {
+        strategy.to_string()
+    }
+}
+
+/// Returns the parent and children of `node_id` in the breadth-first spanning tree of
+/// `topology` rooted at `root`, passing only through the (sorted) `node_ids` of the cluster
+fn spanning_tree_neighbors(
+    node_id: &str,
+    root: &str,
+    topology: &HashMap<String, Vec<String>>,
+    node_ids: &[String],
+) -> BTreeSet<String> {
+    let mut parents = HashMap::from([(root, None)]);
+    let mut queue = VecDeque::from([root]);
+    while let Some(node) = queue.pop_front() {
+        // NOTE: visit in sorted order, as neighbor lists may differ in order between nodes
+        let mut adjacent: Vec<_> = topology.get(node).into_iter().flatten().collect();
+        adjacent.sort();
+        for next in adjacent {
+            // NOTE: a node outside the cluster would never relay gossip to its children
+            let in_cluster = node_ids.binary_search(next).is_ok();
+            if in_cluster && !parents.contains_key(next.as_str()) {
+                parents.insert(next, Some(node));
+                queue.push_back(next);
+            }
+        }
+    }
+    let parent = parents.get(node_id).copied().flatten();
+    let children = parents
+        .iter()
+        .filter(|(_, &parent)| parent == Some(node_id))
+        .map(|(&child, _)| child);
+    parent
+        .into_iter()
+        .chain(children)
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_ids(count: usize) -> Vec<String> {
+        (0..count).map(|index| format!("n{index}")).collect()
+    }
+
+    fn neighbors(strategy: Strategy, node_id: &str, count: usize) -> Vec<String> {
+        let node_ids = node_ids(count);
+        let neighbors = strategy.neighbors(node_id, &node_ids, None);
+        neighbors.into_iter().collect()
+    }
+
+    /// Asserts that every node's neighbors are its neighbors' neighbors, and that gossip
+    /// reaches every node from `n0`
+    fn assert_symmetric_and_connected(strategy: Strategy, count: usize) {
+        let node_ids = node_ids(count);
+        let neighbors: HashMap<_, _> = node_ids
+            .iter()
+            .map(|id| (id.clone(), strategy.neighbors(id, &node_ids, None)))
+            .collect();
+        for (id, adjacent) in &neighbors {
+            assert!(
+                !adjacent.contains(id),
+                "{strategy} {id} is its own neighbor"
+            );
+            for other in adjacent {
+                assert!(
+                    neighbors[other].contains(id),
+                    "{strategy} with {count} nodes: {id} -> {other} is one-way"
+                );
+            }
+        }
+        let mut reached = BTreeSet::from([node_ids[0].clone()]);
+        let mut queue = VecDeque::from([node_ids[0].clone()]);
+        while let Some(id) = queue.pop_front() {
+            for other in &neighbors[&id] {
+                if reached.insert(other.clone()) {
+                    queue.push_back(other.clone());
+                }
+            }
+        }
+        assert_eq!(reached.len(), count, "{strategy} with {count} nodes");
+    }
+
+    #[test]
+    fn grid_links_adjacent_cells() {
+        // 0 1 2
+        // 3 4 5
+        // 6 7 8
+        assert_eq!(neighbors(Strategy::Grid, "n4", 9), ["n1", "n3", "n5", "n7"]);
+        assert_eq!(neighbors(Strategy::Grid, "n0", 9), ["n1", "n3"]);
+        assert_eq!(neighbors(Strategy::Grid, "n8", 9), ["n5", "n7"]);
+        // 0 1 2 3
+        // 4 5 6 7
+        // 8 9
+        assert_eq!(neighbors(Strategy::Grid, "n6", 10), ["n2", "n5", "n7"]);
+        assert_eq!(neighbors(Strategy::Grid, "n9", 10), ["n5", "n8"]);
+        assert!(neighbors(Strategy::Grid, "n0", 1).is_empty());
+    }
+
+    #[test]
+    fn tree_links_parent_children_and_cross_links() {
+        let tree = Strategy::Tree {
+            arity: 2,
+            cross_links: 0,
+        };
+        assert_eq!(neighbors(tree, "n0", 7), ["n1", "n2"]);
+        assert_eq!(neighbors(tree, "n1", 7), ["n0", "n3", "n4"]);
+        assert_eq!(neighbors(tree, "n6", 7), ["n2"]);
+
+        let cross_linked = Strategy::Tree {
+            arity: 2,
+            cross_links: 1,
+        };
+        assert_eq!(neighbors(cross_linked, "n0", 7), ["n1", "n2", "n3", "n4"]);
+        assert_eq!(neighbors(cross_linked, "n6", 7), ["n2", "n3"]);
+    }
+
+    #[test]
+    fn generated_topologies_are_symmetric_and_connected() {
+        let strategies = [
+            Strategy::Mesh,
+            Strategy::Grid,
+            Strategy::Tree {
+                arity: 1,
+                cross_links: 0,
+            },
+            Strategy::Tree {
+                arity: 4,
+                cross_links: 1,
+            },
+            Strategy::Tree {
+                arity: 3,
+                cross_links: 3,
+            },
+        ];
+        for strategy in strategies {
+            for count in 1..=10 {
+                assert_symmetric_and_connected(strategy, count);
+            }
+        }
+    }
+
+    #[test]
+    fn topology_neighbors_are_limited_to_the_cluster() {
+        let node_ids = node_ids(3);
+        let topology = HashMap::from([
+            (
+                "n0".to_string(),
+                vec!["n0".into(), "n1".into(), "n7".into()],
+            ),
+            ("n1".to_string(), vec!["n0".into(), "n2".into()]),
+            ("n7".to_string(), vec!["n0".into()]),
+        ]);
+        let neighbors = Strategy::Maelstrom.neighbors("n0", &node_ids, Some(&topology));
+        assert_eq!(neighbors, BTreeSet::from(["n1".to_string()]));
+        let neighbors = Strategy::SpanningTree.neighbors("n0", &node_ids, Some(&topology));
+        assert_eq!(neighbors, BTreeSet::from(["n1".to_string()]));
+        let neighbors = Strategy::SpanningTree.neighbors("n1", &node_ids, Some(&topology));
+        assert_eq!(
+            neighbors,
+            BTreeSet::from(["n0".to_string(), "n2".to_string()])
+        );
+    }
+
+    #[test]
+    fn spanning_tree_avoids_nodes_outside_the_cluster() {
+        // NOTE: the shortest path from n0 to n2 is through `a9`, outside the cluster
+        let node_ids = node_ids(4);
+        let topology: HashMap<_, _> = [
+            ("n0", ["a9", "n1"].as_slice()),
+            ("a9", &["n0", "n2"]),
+            ("n1", &["n0", "n3"]),
+            ("n2", &["a9", "n3"]),
+            ("n3", &["n1", "n2"]),
+        ]
+        .into_iter()
+        .map(|(id, adjacent)| {
+            let adjacent = adjacent.iter().map(|id| id.to_string()).collect();
+            (id.to_string(), adjacent)
+        })
+        .collect();
+        let tree: Vec<Vec<_>> = node_ids
+            .iter()
+            .map(|id| {
+                let neighbors = Strategy::SpanningTree.neighbors(id, &node_ids, Some(&topology));
+                neighbors.into_iter().collect()
+            })
+            .collect();
+        assert_eq!(
+            tree,
+            [vec!["n1"], vec!["n0", "n3"], vec!["n3"], vec!["n1", "n2"]]
+        );
+    }
+
+    #[test]
+    fn tree_bounds_are_checked() {
+        for (topology, error) in [
+            ("tree:1025", "tree arity must be at most 1024"),
+            ("tree:2:65", "tree cross_links must be at most 64"),
+            ("tree:1000000000", "tree arity must be at most 1024"),
+        ] {
+            let parsed = topology.parse::<Strategy>();
+            assert_eq!(parsed.unwrap_err().to_string(), error, "{topology}");
+        }
+        // children beyond the cluster (or beyond `usize`) are skipped
+        let tree = Strategy::Tree {
+            arity: usize::MAX,
+            cross_links: 0,
+        };
+        assert_eq!(neighbors(tree, "n0", 3), ["n1", "n2"]);
+        assert_eq!(neighbors(tree, "n2", 3), ["n0"]);
+        let tree: Strategy = "tree:1024:64".parse().unwrap();
+        assert_symmetric_and_connected(tree, 10);
+    }
+}
@@ -0,0 +1,138 @@
+//! PN-Counter CRDT, gossiped between nodes
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Totals of the increments and decrements made at each node, merged by element-wise max
+///
+/// Each node only adds to its own entries, so every entry only grows, and merging states in any
+/// order (or more than once) converges.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PnCounter {
+    increments: BTreeMap<String, u64>,
+    decrements: BTreeMap<String, u64>,
+}
+impl PnCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `delta` (which may be negative) to the entries of `node_id`, saturating at
+    /// `u64::MAX`
+    pub fn add(&mut self, node_id: &str, delta: i64) {
+        if delta == 0 {
+            return;
+        }
+        let entries = if delta > 0 {
+            &mut self.increments
+        } else {
+            &mut self.decrements
+        };
+        let entry = entries.entry(node_id.to_string()).or_default();
+        *entry = entry.saturating_add(delta.unsigned_abs());
+    }
+
+    /// Merges the entries of `other`, keeping the larger of each
+    pub fn merge(&mut self, other: &Self) {
+        merge_max(&mut self.increments, &other.increments);
+        merge_max(&mut self.decrements, &other.decrements);
+    }
+
+    /// Returns the sum of all increments less all decrements, clamped to the range of `i64`
+    pub fn value(&self) -> i64 {
+        let sum = |entries: &BTreeMap<String, u64>| -> i128 {
+            entries.values().copied().map(i128::from).sum()
+        };
+        let value = sum(&self.increments) - sum(&self.decrements);
+        value.clamp(i64::MIN.into(), i64::MAX.into()) as i64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.increments.is_empty() && self.decrements.is_empty()
+    }
+}
+
+fn merge_max(entries: &mut BTreeMap<String, u64>, other: &BTreeMap<String, u64>) {
+    for (node_id, &total) in other {
+        let entry = entries.entry(node_id.clone()).or_default();
+        *entry = (*entry).max(total);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pn_counter(adds: &[(&str, i64)]) -> PnCounter {
+        let mut counter = PnCounter::new();
+        for &(node_id, delta) in adds {
+            counter.add(node_id, delta);
+        }
+        counter
+    }
+
+    fn merged(a: &PnCounter, b: &PnCounter) -> PnCounter {
+        let mut merged = a.clone();
+        merged.merge(b);
+        merged
+    }
+
+    #[test]
+    fn add_and_value() {
+        let counter = pn_counter(&[("n0", 5), ("n1", -3), ("n0", -4), ("n1", 0), ("n2", 10)]);
+        assert_eq!(counter.value(), 8);
+        assert!(!counter.is_empty());
+        assert!(PnCounter::new().is_empty());
+        assert!(pn_counter(&[("n0", 0)]).is_empty());
+    }
+
+    #[test]
+    fn large_totals_saturate() {
+        let counter = pn_counter(&[("n0", i64::MAX), ("n0", i64::MAX), ("n0", 2)]);
+        assert_eq!(counter.increments["n0"], u64::MAX);
+        assert_eq!(counter.value(), i64::MAX);
+        let counter = pn_counter(&[("n0", i64::MAX), ("n1", i64::MAX), ("n2", -1)]);
+        assert_eq!(counter.value(), i64::MAX);
+
+        let counter = pn_counter(&[("n0", i64::MIN), ("n1", i64::MIN), ("n1", i64::MIN)]);
+        assert_eq!(counter.decrements["n1"], u64::MAX);
+        assert_eq!(counter.value(), i64::MIN);
+        let counter = pn_counter(&[("n0", i64::MIN), ("n1", i64::MAX), ("n2", i64::MAX)]);
+        assert_eq!(counter.value(), i64::MAX - 1);
+    }
+
+    #[test]
+    fn merge_keeps_larger_entries() {
+        let a = pn_counter(&[("n0", 5), ("n1", 2), ("n1", -1)]);
+        let b = pn_counter(&[("n0", 3), ("n1", 4), ("n2", -6)]);
+        let merged = merged(&a, &b);
+        assert_eq!(
+            merged,
+            pn_counter(&[("n0", 5), ("n1", 4), ("n1", -1), ("n2", -6)])
+        );
+        assert_eq!(merged.value(), 2);
+    }
+
+    #[test]
+    fn merge_is_commutative_associative_and_idempotent() {
+        let a = pn_counter(&[("n0", 5), ("n1", -2)]);
+        let b = pn_counter(&[("n0", 7), ("n2", 1)]);
+        let c = pn_counter(&[("n1", 3), ("n1", -4), ("n2", -9)]);
+        assert_eq!(merged(&a, &b), merged(&b, &a));
+        assert_eq!(merged(&merged(&a, &b), &c), merged(&a, &merged(&b, &c)));
+        assert_eq!(merged(&a, &a), a);
+        let ab = merged(&a, &b);
+        assert_eq!(merged(&ab, &b), ab);
+        assert_eq!(merged(&ab, &PnCounter::new()), ab);
+    }
+
+    #[test]
+    fn stale_state_does_not_undo_adds() {
+        let stale = pn_counter(&[("n0", 5)]);
+        let mut latest = stale.clone();
+        latest.add("n0", -2);
+        latest.add("n0", 3);
+        latest.merge(&stale);
+        assert_eq!(latest.value(), 6);
+    }
+}
@@ -1,17 +1,94 @@
 use anyhow::{bail, Context};
+use crdt::PnCounter;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use std::{collections::VecDeque, time::Duration};
-use telephone_line::{main_loop, Body, EventSender, Message, Node};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    time::Duration,
+};
+use telephone_line::{
+    config::{self, Config},
+    main_loop, next_msg_id,
+    services::key_value,
+    Body, Error, EventSender, Message, Node, Output,
+};
 
-pub mod payload;
+mod crdt;
+mod payload;
+#[cfg(test)]
+mod tests;
 
 struct Counter {
+    params: Params,
     msg_id: usize,
+    event_tx: EventSender<Event>,
+    kv: key_value::Client<KvRequest, usize>,
     node_id: String,
+    node_ids: Vec<String>,
     local_counter: usize,
     central_snapshot: Option<CentralSnapshot>,
     chronological_updates: VecDeque<Snapshot>,
+    /// Outstanding CAS of the central count (in [`Mode::CentralCas`]), if any
+    cas_in_flight: Option<usize>,
+    /// Latest known total of each node (in [`Mode::PerNodeKeys`]), exact for this node
+    node_totals: BTreeMap<String, usize>,
+    /// Total of this node last known to be stored in its key
+    written_total: usize,
+    /// Outstanding write of this node's key, if any
+    write_in_flight: Option<usize>,
+    /// Whether a timed out write may yet land in this node's key, over a newer total
+    own_key_unconfirmed: bool,
+    /// Outstanding read of the keys of the other nodes, if any
+    refresh: Option<Refresh>,
+    /// Time (since the start) at which the latest successful [`Refresh`] started
+    refreshed_at: Option<Duration>,
+    /// Counts of all nodes known to this node (in [`Mode::Crdt`])
+    crdt: PnCounter,
+    /// Counts each other node is known to have, from its gossip and acknowledgements
+    known: BTreeMap<String, PnCounter>,
+}
+
+/// How the count is shared between nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Mode {
+    /// Nodes add their local counts to one shared key, by CAS
+    CentralCas,
+    /// Each node writes its own total to its own key, and reads sum the keys of all nodes
+    PerNodeKeys,
+    /// Nodes gossip a [`PnCounter`] directly (without `seq-kv`), so remain available during
+    /// partitions, and support negative deltas
+    Crdt,
+}
+
+/// Outstanding request to the `seq-kv` service
+enum KvRequest {
+    Read,
+    Write,
+    Cas {
+        local_count_to_subtract: usize,
+        counter: usize,
+        /// Whether an earlier attempt of this CAS timed out, so may have been applied
+        retry: bool,
+    },
+    ReadNodeKey {
+        node_id: String,
+    },
+    WriteNodeKey {
+        total: usize,
+    },
+}
+
+/// Read of the keys of the other nodes, on behalf of client `read`s (in [`Mode::PerNodeKeys`])
+struct Refresh {
+    started: Duration,
+    /// Nodes whose keys are still being read
+    remaining: BTreeSet<String>,
+    /// Whether a read failed, so the totals may be stale
+    failed: bool,
+    /// Client `read`s to reply to once done
+    reads: Vec<Message<()>>,
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -31,199 +108,620 @@ impl PartialOrd for CentralSnapshot {
     }
 }
 
-const CENTRAL_UPDATE_INTERVAL: Duration = Duration::from_millis(1000);
+/// Key for the centralized count
+const KEY_COUNT: &str = "c";
+
+/// Prefix of the key for each node's total, in [`Mode::PerNodeKeys`]
+const KEY_PREFIX_NODE: &str = "counter-";
+
+/// Runtime configuration of the node
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Params {
+    mode: Mode,
+    /// Interval between syncs with `seq-kv`, or gossip to other nodes (in [`Mode::Crdt`])
+    #[serde(rename = "sync_interval_ms", with = "config::millis")]
+    sync_interval: Duration,
+    /// Time to wait for a `seq-kv` reply before forgetting the request
+    #[serde(rename = "kv_timeout_ms", with = "config::millis")]
+    kv_timeout: Duration,
+}
+const PARAMS_DEFAULT: Params = Params {
+    mode: Mode::CentralCas,
+    sync_interval: Duration::from_millis(1000),
+    kv_timeout: Duration::from_millis(5000),
+};
+impl Config for Params {
+    const ENV_PREFIX: &'static str = "COUNTER";
+    const PRESETS: &'static [(&'static str, Self)] = &[("default", PARAMS_DEFAULT)];
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.sync_interval.is_zero() || self.kv_timeout.is_zero() {
+            bail!("sync_interval_ms and kv_timeout_ms must be positive");
+        }
+        Ok(())
+    }
+}
 
 static KV_CAS_ERROR_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"current value (?P<value>[\d]+) is not [\d]+").unwrap());
 
-impl Node for Counter {
-    type Payload = payload::Raw;
+impl Node<Params> for Counter {
+    type Request = payload::CountReceive;
+    type Response = payload::Response;
+    type Outbound = payload::Outbound;
     type Event = Event;
 
     fn from_init(
         init: telephone_line::Init,
         msg_id: usize,
-        _params: (),
-        mut event_tx: EventSender<Self::Payload, Self::Event>,
+        params: Params,
+        event_tx: EventSender<Self::Event>,
     ) -> Self
     where
         Self: Sized,
     {
-        std::thread::spawn(move || loop {
-            std::thread::sleep(CENTRAL_UPDATE_INTERVAL);
-            let result = event_tx.send(Event::CentralSnapshot);
-            if result.is_err() {
-                break;
-            }
-        });
+        match params.mode {
+            Mode::CentralCas => event_tx.every(params.sync_interval, || Event::CentralSnapshot),
+            Mode::PerNodeKeys => event_tx.every(params.sync_interval, || Event::SyncNodeKeys),
+            Mode::Crdt => event_tx.every(params.sync_interval, || Event::Gossip),
+        };
+        let known = init
+            .node_ids
+            .iter()
+            .filter(|&node_id| *node_id != init.node_id)
+            .map(|node_id| (node_id.clone(), PnCounter::new()))
+            .collect();
         Self {
+            params,
             msg_id,
+            event_tx,
+            kv: key_value::Client::new(key_value::Service::Seq, init.node_id.clone()),
             node_id: init.node_id,
+            node_ids: init.node_ids,
             local_counter: 0,
             central_snapshot: None,
             chronological_updates: VecDeque::new(),
+            cas_in_flight: None,
+            node_totals: BTreeMap::new(),
+            written_total: 0,
+            write_in_flight: None,
+            own_key_unconfirmed: false,
+            refresh: None,
+            refreshed_at: None,
+            crdt: PnCounter::new(),
+            known,
         }
     }
 
-    fn step_message(
+    fn step_request(
         &mut self,
-        message: Message<Self::Payload>,
-        output: &mut impl std::io::Write,
+        message: Message<Self::Request>,
+        output: &mut Output<'_, Self::Outbound>,
     ) -> anyhow::Result<()> {
-        let original_in_reply_to = message.body.in_reply_to;
-        let mut reply = message.reply(Some(&mut self.msg_id));
-
-        let receive_payload = reply
-            .body
-            .payload
-            .clone()
-            .try_into()
-            .unwrap_or_else(|payload| panic!("unexpected message {payload:?}"));
-
-        match receive_payload {
-            payload::Receive::Count(payload) => match payload {
-                payload::CountReceive::Add { delta } => {
-                    self.local_counter += delta;
-                    reply.body.payload = payload::CountSend::AddOk.into();
-                    reply.send(output)
+        match message.body.payload.clone() {
+            payload::CountReceive::Add { delta } => {
+                match (self.params.mode, usize::try_from(delta)) {
+                    (Mode::Crdt, _) => self.crdt.add(&self.node_id, delta),
+                    (Mode::CentralCas, Ok(delta)) => self.local_counter += delta,
+                    (Mode::PerNodeKeys, Ok(delta)) => {
+                        *self.node_totals.entry(self.node_id.clone()).or_default() += delta
+                    }
+                    (mode, Err(_)) => {
+                        let text = format!("negative delta {delta} in {mode:?} mode");
+                        return Err(Error::not_supported(text).into());
+                    }
                 }
-                payload::CountReceive::Read => {
-                    let value = {
+                message
+                    .reply_with(Some(&mut self.msg_id), payload::CountSend::AddOk)
+                    .send(output)
+            }
+            payload::CountReceive::Read => {
+                let value = match self.params.mode {
+                    Mode::CentralCas => {
                         let local_count = self.local_counter;
                         let global_count =
                             self.central_snapshot.map(|s| s.counter).unwrap_or_default();
-                        local_count + global_count
-                    };
-                    reply.body.payload = payload::CountSend::ReadOk { value }.into();
-                    reply.send(output)
-                }
-            },
-
-            payload::Receive::Kv(payload) => {
-                let msg_id =
-                    original_in_reply_to.expect("KeyValue response missing field in_reply_to");
-                match payload {
-                    payload::key_value::Receive::ReadOk { value } => {
-                        self.update_with_snapshot(Snapshot {
-                            local_count_to_subtract: 0,
-                            central: CentralSnapshot {
-                                counter: value,
-                                msg_id,
-                            },
-                        });
-                        Ok(())
+                        saturating_i64(local_count.saturating_add(global_count))
                     }
-                    payload::key_value::Receive::WriteOk => {
-                        self.update_with_snapshot(Snapshot {
-                            local_count_to_subtract: 0,
-                            central: CentralSnapshot {
-                                counter: 0, // only argument to KvWrite is zero (0)
-                                msg_id,
-                            },
-                        });
-                        Ok(())
-                    }
-                    payload::key_value::Receive::CasOk => {
-                        self.update_snapshot_cas_succeeded(msg_id)
+                    Mode::PerNodeKeys => return self.read_node_keys(message.header(), output),
+                    Mode::Crdt => self.crdt.value(),
+                };
+                message
+                    .reply_with(Some(&mut self.msg_id), payload::CountSend::ReadOk { value })
+                    .send(output)
+            }
+            payload::CountReceive::Gossip { counts } => {
+                let src = &message.src;
+                let known = match self.known.get_mut(src) {
+                    Some(known) if self.params.mode == Mode::Crdt => known,
+                    _ => {
+                        let mode = self.params.mode;
+                        let text = format!("gossip from {src} in {mode:?} mode");
+                        return Err(Error::not_supported(text).into());
                     }
-                    payload::key_value::Receive::Error { code, text } => match code {
-                        payload::key_value::ErrorCode::KeyNotFound => {
-                            // TODO this is woefully racy...
-                            self.kv_message(|key| payload::key_value::SendSeq::Write {
-                                key,
-                                value: 0,
-                            })
-                            .send(output)
-                        }
-                        payload::key_value::ErrorCode::CasFromMismatch => {
-                            use std::str::FromStr;
-                            // attempt to parse error message "current value {N} is not {M}"
-                            let Some(value) = KV_CAS_ERROR_REGEX
-                                             .captures(&text)
-                                             .and_then(|cap| cap.name("value")) else {
-                                bail!("failed to parse new value from Cas {code:?} error string {text:?}")
-                            };
-                            let value = value.as_str();
-                            let counter = usize::from_str(value)
-                                .context(format!("invalid number {value:?}"))
-                                .context(format!("parsing {code:?} error string {text:?}"))?;
-                            self.update_with_snapshot(Snapshot {
-                                local_count_to_subtract: 0,
-                                central: CentralSnapshot { counter, msg_id },
-                            });
-                            Ok(())
-                            // ALTERNATIVE: not parsing the error string
-                            // self.kv_message(|key| payload::key_value::Send::Read { key })
-                            //     .send(output)
-                        }
-                        payload::key_value::ErrorCode::Unknown(code) => {
-                            panic!("unknown payload::key_value::ErrorCode value {code}, {text}")
-                        }
-                    },
+                };
+                known.merge(&counts);
+                self.crdt.merge(&counts);
+                message
+                    .reply_with(
+                        Some(&mut self.msg_id),
+                        payload::CountSend::GossipOk { counts },
+                    )
+                    .send(output)
+            }
+        }
+    }
+
+    fn step_response(
+        &mut self,
+        message: Message<Self::Response>,
+        output: &mut Output<'_, Self::Outbound>,
+    ) -> anyhow::Result<()> {
+        match message.body.payload.clone() {
+            payload::Response::Kv(payload) => {
+                let Some(kv_reply) = self.kv.receive(&message, payload) else {
+                    // NOTE: the request timed out and was forgotten, so its outcome no longer
+                    // matters
+                    let in_reply_to = message.body.in_reply_to;
+                    eprintln!(
+                        "ignoring late seq-kv reply to {in_reply_to:?} from {}",
+                        message.src
+                    );
+                    return Ok(());
+                };
+                self.step_kv_reply(kv_reply, output)
+            }
+            payload::Response::Count(payload::CountSend::GossipOk { counts }) => {
+                if let Some(known) = self.known.get_mut(&message.src) {
+                    known.merge(&counts);
                 }
+                Ok(())
+            }
+            payload::Response::Count(reply) => {
+                eprintln!("ignoring unexpected reply {reply:?} from {}", message.src);
+                Ok(())
             }
         }
     }
 
-    fn step_event(&mut self, event: Event, output: &mut impl std::io::Write) -> anyhow::Result<()> {
+    fn step_event(
+        &mut self,
+        event: Event,
+        output: &mut Output<'_, Self::Outbound>,
+    ) -> anyhow::Result<()> {
         match event {
             Event::CentralSnapshot => {
                 let no_change_since_last_send = matches!(
                     self.chronological_updates.back(),
                     Some(last) if last.local_count_to_subtract == self.local_counter
                 );
-                if no_change_since_last_send || self.local_counter == 0 {
+                // NOTE: a CAS in flight may yet add the local count, so await it (retrying on
+                // timeout) rather than send another
+                if no_change_since_last_send
+                    || self.local_counter == 0
+                    || self.cas_in_flight.is_some()
+                {
                     // no update to send, read current value
-                    self.kv_message(|key| payload::key_value::SendSeq::Read { key })
-                        .send(output)
+                    let msg_id =
+                        self.kv
+                            .read(KEY_COUNT, KvRequest::Read, &mut self.msg_id, output)?;
+                    self.start_kv_timeout(msg_id);
+                    Ok(())
                 } else {
                     // update to send
                     let counter_from = self.central_snapshot.map(|s| s.counter).unwrap_or_default();
-                    let counter_to = counter_from + self.local_counter;
-
-                    let message = self.kv_message(|key| payload::key_value::SendSeq::Cas {
-                        key,
-                        from: counter_from,
-                        to: counter_to,
-                    });
-
-                    let msg_id = message.body.msg_id.expect("kv_message yields msg_id");
-                    self.chronological_updates.push_back(Snapshot {
-                        local_count_to_subtract: self.local_counter,
-                        central: CentralSnapshot {
-                            counter: counter_to,
-                            msg_id,
-                        },
-                    });
-
-                    message.send(output)
+                    self.send_cas(counter_from, self.local_counter, false, output)
+                }
+            }
+            Event::SyncNodeKeys => self.sync_node_keys(output),
+            Event::Gossip => self.gossip(output),
+            Event::KvTimeout(msg_id) => {
+                let Some(request) = self.kv.take_timed_out(msg_id) else {
+                    return Ok(());
+                };
+                eprintln!("seq-kv request {msg_id} timed out");
+                match request {
+                    KvRequest::Cas {
+                        local_count_to_subtract,
+                        counter,
+                        ..
+                    } => {
+                        // NOTE: the CAS may have been applied, so resend it as is (rather than
+                        // a new CAS, which would add its local count twice)
+                        let counter_from = counter - local_count_to_subtract;
+                        self.send_cas(counter_from, local_count_to_subtract, true, output)
+                    }
+                    KvRequest::ReadNodeKey { node_id } => {
+                        self.refreshed_key(&node_id, false, output)
+                    }
+                    KvRequest::WriteNodeKey { .. } => {
+                        if self.write_in_flight == Some(msg_id) {
+                            self.write_in_flight = None;
+                        }
+                        self.own_key_unconfirmed = true;
+                        Ok(())
+                    }
+                    _ => Ok(()),
                 }
             }
         }
     }
 }
 impl Counter {
-    fn kv_message(
+    fn step_kv_reply(
         &mut self,
-        payload_from_key_fn: impl FnOnce(String) -> payload::key_value::SendSeq,
-    ) -> Message<payload::Raw> {
-        /// Key for the centralized count
-        const KEY_COUNT: &str = "c";
-
-        let msg_id = telephone_line::next_msg_id(&mut self.msg_id);
-        let key = KEY_COUNT.to_string();
-        let payload = payload_from_key_fn(key).into();
-        Message {
-            src: self.node_id.clone(),
-            dest: payload::key_value::NODE_ID_SEQ.to_string(),
-            body: Body {
-                msg_id: Some(msg_id),
-                in_reply_to: None,
-                payload,
+        kv_reply: key_value::Reply<KvRequest, usize>,
+        output: &mut Output<'_, payload::Outbound>,
+    ) -> anyhow::Result<()> {
+        let key_value::Reply {
+            msg_id,
+            context: request,
+            result,
+        } = kv_reply;
+        match request {
+            KvRequest::ReadNodeKey { node_id } => self.step_node_key_read(node_id, result, output),
+            KvRequest::WriteNodeKey { total } => self.step_node_key_write(msg_id, total, result),
+            request => self.step_central_reply(msg_id, request, result, output),
+        }
+    }
+    fn step_central_reply(
+        &mut self,
+        msg_id: usize,
+        request: KvRequest,
+        result: Result<key_value::Response<usize>, key_value::Error>,
+        output: &mut Output<'_, payload::Outbound>,
+    ) -> anyhow::Result<()> {
+        if self.cas_in_flight == Some(msg_id) {
+            self.cas_in_flight = None;
+        }
+        match result {
+            Ok(key_value::Response::ReadOk { value }) => self.update_with_snapshot(Snapshot {
+                local_count_to_subtract: 0,
+                central: CentralSnapshot {
+                    counter: value,
+                    msg_id,
+                },
+            }),
+            Ok(key_value::Response::WriteOk) => self.update_with_snapshot(Snapshot {
+                local_count_to_subtract: 0,
+                central: CentralSnapshot {
+                    counter: 0, // only argument to KvWrite is zero (0)
+                    msg_id,
+                },
+            }),
+            Ok(key_value::Response::CasOk) => {
+                let KvRequest::Cas {
+                    local_count_to_subtract,
+                    counter,
+                    ..
+                } = request
+                else {
+                    eprintln!("ignoring unexpected cas_ok for non-cas seq-kv request {msg_id}");
+                    return Ok(());
+                };
+                self.update_with_snapshot(Snapshot {
+                    local_count_to_subtract,
+                    central: CentralSnapshot { counter, msg_id },
+                })
+            }
+            Err(key_value::Error { code, text }) => match code {
+                key_value::ErrorCode::KeyDoesNotExist => {
+                    // TODO this is woefully racy...
+                    let msg_id =
+                        self.kv
+                            .write(KEY_COUNT, 0, KvRequest::Write, &mut self.msg_id, output)?;
+                    self.start_kv_timeout(msg_id);
+                    Ok(())
+                }
+                key_value::ErrorCode::PreconditionFailed => {
+                    use std::str::FromStr;
+                    // attempt to parse error message "current value {N} is not {M}"
+                    let Some(value) = KV_CAS_ERROR_REGEX
+                        .captures(&text)
+                        .and_then(|cap| cap.name("value"))
+                    else {
+                        bail!("failed to parse new value from Cas {code:?} error string {text:?}")
+                    };
+                    let value = value.as_str();
+                    let counter = usize::from_str(value)
+                        .context(format!("invalid number {value:?}"))
+                        .context(format!("parsing {code:?} error string {text:?}"))?;
+                    let local_count_to_subtract = match request {
+                        // NOTE: the count is at this CAS's value, so an earlier attempt was
+                        // applied (unless another node's CAS reached the same value, which
+                        // cannot be told apart)
+                        KvRequest::Cas {
+                            local_count_to_subtract,
+                            counter: counter_to,
+                            retry: true,
+                        } if counter_to == counter => local_count_to_subtract,
+                        _ => 0,
+                    };
+                    self.update_with_snapshot(Snapshot {
+                        local_count_to_subtract,
+                        central: CentralSnapshot { counter, msg_id },
+                    })
+                    // ALTERNATIVE: not parsing the error string
+                    // self.kv.read(KEY_COUNT, KvRequest::Read, &mut self.msg_id, output)
+                }
+                code => {
+                    // leave the central snapshot as-is, to retry on the next interval
+                    let definite = if code.is_definite() {
+                        "definite"
+                    } else {
+                        "indefinite"
+                    };
+                    eprintln!("seq-kv request {msg_id} failed ({definite}) {code:?}: {text}");
+                    Ok(())
+                }
             },
         }
     }
-    fn update_with_snapshot(&mut self, snapshot: Snapshot) {
+    /// Sends the counts known to this node to the other nodes not known to have them
+    ///
+    /// Nodes are known to have the counts they gossiped or acknowledged, so gossip lost (e.g.
+    /// during a partition) is resent until acknowledged.
+    fn gossip(&mut self, output: &mut Output<'_, payload::Outbound>) -> anyhow::Result<()> {
+        for node_id in &self.node_ids {
+            if self
+                .known
+                .get(node_id)
+                .is_none_or(|known| *known == self.crdt)
+            {
+                continue;
+            }
+            // NOTE: send the full state, which is small (one entry per node)
+            Message {
+                src: self.node_id.clone(),
+                dest: node_id.clone(),
+                body: Body {
+                    msg_id: Some(next_msg_id(&mut self.msg_id)),
+                    in_reply_to: None,
+                    payload: payload::CountSend::Gossip {
+                        counts: self.crdt.clone(),
+                    },
+                },
+            }
+            .send(output)?;
+        }
+        Ok(())
+    }
+    /// Writes this node's total to its key (if changed, and no write is outstanding), or reads
+    /// it back while a timed out write may yet land
+    ///
+    /// NOTE: the keys of the other nodes are only read for client `read`s (see
+    /// [`Self::read_node_keys`]), rather than every node reading every key on each sync
+    fn sync_node_keys(&mut self, output: &mut Output<'_, payload::Outbound>) -> anyhow::Result<()> {
+        if self.write_in_flight.is_some() {
+            return Ok(());
+        }
+        let total = self.own_total();
+        if total != self.written_total {
+            let msg_id = self.kv.write(
+                node_key(&self.node_id),
+                total,
+                KvRequest::WriteNodeKey { total },
+                &mut self.msg_id,
+                output,
+            )?;
+            self.start_kv_timeout(msg_id);
+            self.write_in_flight = Some(msg_id);
+        } else if self.own_key_unconfirmed {
+            self.read_node_key(self.node_id.clone(), output)?;
+        }
+        Ok(())
+    }
+    /// Replies to the client `read` with the sum of the totals of all nodes, first reading the
+    /// keys of the other nodes unless done within the last sync interval
+    fn read_node_keys(
+        &mut self,
+        request: Message<()>,
+        output: &mut Output<'_, payload::Outbound>,
+    ) -> anyhow::Result<()> {
+        let now = self.event_tx.now();
+        let fresh = self
+            .refreshed_at
+            .is_some_and(|started| now.saturating_sub(started) < self.params.sync_interval);
+        if fresh {
+            let value = self.nodes_total();
+            return request
+                .reply_with(Some(&mut self.msg_id), payload::CountSend::ReadOk { value })
+                .send(output);
+        }
+        if let Some(refresh) = &mut self.refresh {
+            refresh.reads.push(request);
+            return Ok(());
+        }
+        let remaining: BTreeSet<_> = self
+            .node_ids
+            .iter()
+            .filter(|&node_id| *node_id != self.node_id)
+            .cloned()
+            .collect();
+        for node_id in &remaining {
+            self.read_node_key(node_id.clone(), output)?;
+        }
+        self.refresh = Some(Refresh {
+            started: now,
+            remaining,
+            failed: false,
+            reads: vec![request],
+        });
+        // NOTE: done already if this is the only node
+        self.refreshed_key(&self.node_id.clone(), true, output)
+    }
+    fn read_node_key(
+        &mut self,
+        node_id: String,
+        output: &mut Output<'_, payload::Outbound>,
+    ) -> anyhow::Result<()> {
+        let msg_id = self.kv.read(
+            node_key(&node_id),
+            KvRequest::ReadNodeKey { node_id },
+            &mut self.msg_id,
+            output,
+        )?;
+        self.start_kv_timeout(msg_id);
+        Ok(())
+    }
+    fn step_node_key_read(
+        &mut self,
+        node_id: String,
+        result: Result<key_value::Response<usize>, key_value::Error>,
+        output: &mut Output<'_, payload::Outbound>,
+    ) -> anyhow::Result<()> {
+        let value = match result {
+            Ok(key_value::Response::ReadOk { value }) => value,
+            Err(key_value::Error {
+                code: key_value::ErrorCode::KeyDoesNotExist,
+                ..
+            }) => 0,
+            Ok(response) => {
+                eprintln!("unexpected {response:?} reading the key of {node_id}");
+                return self.refreshed_key(&node_id, false, output);
+            }
+            Err(key_value::Error { code, text }) => {
+                eprintln!("seq-kv read of {node_id} failed {code:?}: {text}");
+                return self.refreshed_key(&node_id, false, output);
+            }
+        };
+        if node_id == self.node_id {
+            // NOTE: a timed out write may land after a newer one, so trust the stored value
+            // (rewriting on the next sync if stale)
+            if self.write_in_flight.is_none() {
+                self.written_total = value;
+                self.own_key_unconfirmed = value != self.own_total();
+            }
+            Ok(())
+        } else {
+            // NOTE: totals only grow, so ignore stale reads
+            let total = self.node_totals.entry(node_id.clone()).or_default();
+            *total = (*total).max(value);
+            self.refreshed_key(&node_id, true, output)
+        }
+    }
+    /// Records the read of the key of `node_id` as done (if part of the [`Refresh`]), replying
+    /// to the client `read`s once the keys of all other nodes are read
+    fn refreshed_key(
+        &mut self,
+        node_id: &str,
+        succeeded: bool,
+        output: &mut Output<'_, payload::Outbound>,
+    ) -> anyhow::Result<()> {
+        let Some(refresh) = &mut self.refresh else {
+            return Ok(());
+        };
+        if refresh.remaining.remove(node_id) {
+            refresh.failed |= !succeeded;
+        }
+        if !refresh.remaining.is_empty() {
+            return Ok(());
+        }
+        let Some(refresh) = self.refresh.take() else {
+            return Ok(());
+        };
+        if !refresh.failed {
+            self.refreshed_at = Some(refresh.started);
+        }
+        // NOTE: reply even after a failed read, with the totals last known
+        let value = self.nodes_total();
+        for request in refresh.reads {
+            request
+                .reply_with(Some(&mut self.msg_id), payload::CountSend::ReadOk { value })
+                .send(output)?;
+        }
+        Ok(())
+    }
+    /// Sum of the latest known totals of all nodes, saturating at `i64::MAX`
+    fn nodes_total(&self) -> i64 {
+        let total = self.node_totals.values();
+        saturating_i64(total.fold(0, |sum, &total| sum.saturating_add(total)))
+    }
+    /// Total of this node, exact (unlike its key)
+    fn own_total(&self) -> usize {
+        self.node_totals
+            .get(&self.node_id)
+            .copied()
+            .unwrap_or_default()
+    }
+    fn step_node_key_write(
+        &mut self,
+        msg_id: usize,
+        total: usize,
+        result: Result<key_value::Response<usize>, key_value::Error>,
+    ) -> anyhow::Result<()> {
+        if self.write_in_flight == Some(msg_id) {
+            self.write_in_flight = None;
+        }
+        match result {
+            Ok(key_value::Response::WriteOk) => {
+                self.written_total = total;
+                Ok(())
+            }
+            Ok(response) => {
+                // retry on the next sync
+                eprintln!("unexpected {response:?} writing total {total}");
+                Ok(())
+            }
+            Err(key_value::Error { code, text }) => {
+                // retry on the next sync
+                eprintln!("seq-kv write of total {total} failed {code:?}: {text}");
+                Ok(())
+            }
+        }
+    }
+    /// Sends a CAS adding `local_count` to the central count `counter_from`, recording it as the
+    /// latest update
+    fn send_cas(
+        &mut self,
+        counter_from: usize,
+        local_count: usize,
+        retry: bool,
+        output: &mut Output<'_, payload::Outbound>,
+    ) -> anyhow::Result<()> {
+        let counter_to = counter_from + local_count;
+        let request = KvRequest::Cas {
+            local_count_to_subtract: local_count,
+            counter: counter_to,
+            retry,
+        };
+        let msg_id = self.kv.cas(
+            KEY_COUNT,
+            counter_from,
+            counter_to,
+            request,
+            &mut self.msg_id,
+            output,
+        )?;
+        self.start_kv_timeout(msg_id);
+        self.cas_in_flight = Some(msg_id);
+
+        self.chronological_updates.push_back(Snapshot {
+            local_count_to_subtract: local_count,
+            central: CentralSnapshot {
+                counter: counter_to,
+                msg_id,
+            },
+        });
+        Ok(())
+    }
+    fn start_kv_timeout(&self, msg_id: usize) {
+        self.event_tx
+            .after(self.params.kv_timeout, Event::KvTimeout(msg_id));
+    }
+    fn update_with_snapshot(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        let Some(local_counter) = self
+            .local_counter
+            .checked_sub(snapshot.local_count_to_subtract)
+        else {
+            bail!(
+                "count to subtract is above the local counter ({} > {})",
+                snapshot.local_count_to_subtract,
+                self.local_counter
+            );
+        };
+
         // retain only elements AFTER the snapshot'd `msg_id`
         let keep_start_index = self
             .chronological_updates
@@ -233,32 +731,29 @@ impl Counter {
         self.chronological_updates.truncate(new_len);
 
         self.central_snapshot = Some(snapshot.central);
-        assert!(
-            self.local_counter >= snapshot.local_count_to_subtract,
-            "count to subtract is below snapshot's local counter ({} < {})",
-            self.local_counter,
-            snapshot.local_count_to_subtract
-        );
-        self.local_counter -= snapshot.local_count_to_subtract;
-    }
-    fn update_snapshot_cas_succeeded(&mut self, msg_id: usize) -> anyhow::Result<()> {
-        let Some(update) = self
-            .chronological_updates
-            .binary_search_by_key(&msg_id, |s| s.central.msg_id)
-            .ok()
-            .and_then(|index| self.chronological_updates.get(index).copied())
-        else {
-            bail!("no chronological_updates element matching msg_id {msg_id}");
-        };
-        self.update_with_snapshot(update);
+        self.local_counter = local_counter;
         Ok(())
     }
 }
 
+#[derive(Serialize, Deserialize)]
 enum Event {
     CentralSnapshot,
+    SyncNodeKeys,
+    Gossip,
+    KvTimeout(usize),
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<Counter, _>(())
+    let params = config::load::<Params>(std::env::args().skip(1))?;
+    main_loop::<Counter, _>(params)
+}
+
+fn node_key(node_id: &str) -> String {
+    format!("{KEY_PREFIX_NODE}{node_id}")
+}
+
+/// Converts a count to a `read_ok` value, saturating at `i64::MAX`
+fn saturating_i64(count: usize) -> i64 {
+    i64::try_from(count).unwrap_or(i64::MAX)
 }
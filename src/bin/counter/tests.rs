@@ -0,0 +1,276 @@
+//! Simulated clusters of counter nodes, sharing a `seq-kv` (except in [`Mode::Crdt`])
+
+use super::*;
+use serde_json::{json, Value};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use telephone_line::{
+    simulator::{KeyValueService, Service, Simulator},
+    RawMessage,
+};
+
+const NODE_COUNT: usize = 3;
+
+fn simulator(mode: Mode) -> Simulator<Counter, Params> {
+    let params = Params {
+        mode,
+        ..PARAMS_DEFAULT
+    };
+    let mut sim = Simulator::new(NODE_COUNT, params, 11).unwrap();
+    sim.add_service(key_value::NODE_ID_SEQ, KeyValueService::default());
+    sim
+}
+
+/// Sends `payload` from a client to `node_id`, returning the reply payload
+fn request(sim: &mut Simulator<Counter, Params>, node_id: &str, payload: Value) -> Value {
+    let msg_id = sim.send_client("c1", node_id, payload).unwrap();
+    sim.run_until_idle().unwrap();
+    sim.take_reply("c1", msg_id).unwrap().body.payload
+}
+
+/// Adds each delta at the nodes in turn, then returns the value read from each node after
+/// the nodes have synced
+fn add_and_read(mode: Mode, deltas: &[i64]) -> Vec<Value> {
+    let mut sim = simulator(mode);
+    let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+    for (index, &delta) in deltas.iter().enumerate() {
+        let node_id = &node_ids[index % node_ids.len()];
+        let reply = request(&mut sim, node_id, json!({"type": "add", "delta": delta}));
+        assert_eq!(reply, json!({"type": "add_ok"}));
+        sim.run_for(Duration::from_millis(150)).unwrap();
+    }
+    sim.run_for(Duration::from_secs(5)).unwrap();
+    node_ids
+        .iter()
+        .map(|node_id| request(&mut sim, node_id, json!({"type": "read"})))
+        .collect()
+}
+
+#[test]
+fn converges_in_each_mode() {
+    let deltas: Vec<i64> = (1..=20).collect();
+    let total: i64 = deltas.iter().sum();
+    for mode in [Mode::CentralCas, Mode::PerNodeKeys, Mode::Crdt] {
+        for read in add_and_read(mode, &deltas) {
+            assert_eq!(read, json!({"type": "read_ok", "value": total}), "{mode:?}");
+        }
+    }
+}
+
+#[test]
+fn crdt_supports_negative_deltas() {
+    for read in add_and_read(Mode::Crdt, &[5, -3, 4, -10, 1]) {
+        assert_eq!(read, json!({"type": "read_ok", "value": -3}));
+    }
+}
+
+#[test]
+fn crdt_gossip_stops_once_acknowledged() {
+    let mut sim = simulator(Mode::Crdt);
+    let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+    // NOTE: only n0 has counts to gossip, so learns what the others have from their acks
+    request(&mut sim, "n0", json!({"type": "add", "delta": 6}));
+    sim.run_for(Duration::from_secs(5)).unwrap();
+    for node_id in &node_ids {
+        let node = sim.node(node_id).unwrap();
+        assert_eq!(node.crdt.value(), 6);
+        // NOTE: so gossip no longer sends anything
+        for peer in node_ids.iter().filter(|&peer| peer != node_id) {
+            assert_eq!(
+                node.known.get(peer),
+                Some(&node.crdt),
+                "{node_id} of {peer}"
+            );
+        }
+    }
+}
+
+#[test]
+fn gossip_is_only_accepted_from_nodes_in_crdt_mode() {
+    let gossip = json!({"type": "gossip", "counts": {"increments": {"n1": 5}, "decrements": {}}});
+    for mode in [Mode::CentralCas, Mode::PerNodeKeys, Mode::Crdt] {
+        let mut sim = simulator(mode);
+        let reply = request(&mut sim, "n0", gossip.clone());
+        assert_eq!(reply["type"], "error", "{mode:?}");
+        assert_eq!(reply["code"], 10, "{mode:?}");
+        let read = request(&mut sim, "n0", json!({"type": "read"}));
+        assert_eq!(read, json!({"type": "read_ok", "value": 0}), "{mode:?}");
+    }
+}
+
+#[test]
+fn kv_modes_reject_negative_deltas() {
+    for mode in [Mode::CentralCas, Mode::PerNodeKeys] {
+        let mut sim = simulator(mode);
+        let reply = request(&mut sim, "n0", json!({"type": "add", "delta": -1}));
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], 10);
+    }
+}
+
+/// `seq-kv` counting the reads it receives
+struct CountingReads {
+    kv: KeyValueService,
+    reads: Arc<AtomicUsize>,
+}
+impl Service for CountingReads {
+    fn step_message(&mut self, message: RawMessage) -> Vec<RawMessage> {
+        if message.body.payload["type"] == "read" {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+        }
+        self.kv.step_message(message)
+    }
+}
+
+#[test]
+fn per_node_keys_are_read_only_for_client_reads() {
+    let params = Params {
+        mode: Mode::PerNodeKeys,
+        ..PARAMS_DEFAULT
+    };
+    let mut sim = Simulator::<Counter, _>::new(NODE_COUNT, params, 5).unwrap();
+    let reads = Arc::new(AtomicUsize::new(0));
+    let kv = CountingReads {
+        kv: KeyValueService::default(),
+        reads: reads.clone(),
+    };
+    sim.add_service(key_value::NODE_ID_SEQ, kv);
+    let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+    for (index, node_id) in node_ids.iter().enumerate() {
+        request(
+            &mut sim,
+            node_id,
+            json!({"type": "add", "delta": index + 1}),
+        );
+    }
+    sim.run_for(Duration::from_secs(10)).unwrap();
+    assert_eq!(reads.load(Ordering::Relaxed), 0, "idle nodes read no keys");
+
+    let read_ok = json!({"type": "read_ok", "value": 6});
+    assert_eq!(request(&mut sim, "n0", json!({"type": "read"})), read_ok);
+    assert_eq!(reads.load(Ordering::Relaxed), NODE_COUNT - 1);
+    // answered from the keys just read
+    assert_eq!(request(&mut sim, "n0", json!({"type": "read"})), read_ok);
+    assert_eq!(reads.load(Ordering::Relaxed), NODE_COUNT - 1);
+
+    request(&mut sim, "n1", json!({"type": "add", "delta": 4}));
+    sim.run_for(params.sync_interval * 2).unwrap();
+    let read_ok = json!({"type": "read_ok", "value": 10});
+    assert_eq!(request(&mut sim, "n0", json!({"type": "read"})), read_ok);
+    assert_eq!(reads.load(Ordering::Relaxed), 2 * (NODE_COUNT - 1));
+}
+
+#[test]
+fn late_kv_replies_are_settled_or_ignored() {
+    for mode in [Mode::CentralCas, Mode::PerNodeKeys] {
+        let params = Params {
+            mode,
+            kv_timeout: Duration::from_millis(50),
+            ..PARAMS_DEFAULT
+        };
+        let mut sim = Simulator::<Counter, _>::new(NODE_COUNT, params, 3).unwrap();
+        sim.add_service(key_value::NODE_ID_SEQ, KeyValueService::default());
+        let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+        // NOTE: every seq-kv reply arrives after its request timed out
+        sim.set_latency(Duration::from_millis(60)..=Duration::from_millis(80));
+        for (index, node_id) in node_ids.iter().cycle().take(12).enumerate() {
+            let payload = json!({"type": "add", "delta": index + 1});
+            sim.send_client("c1", node_id, payload).unwrap();
+            sim.run_for(Duration::from_millis(400)).unwrap();
+        }
+        sim.run_for(Duration::from_secs(3)).unwrap();
+        sim.set_latency(Duration::ZERO..=Duration::from_millis(10));
+        sim.run_for(Duration::from_secs(5)).unwrap();
+        for node_id in &node_ids {
+            let read = request(&mut sim, node_id, json!({"type": "read"}));
+            assert_eq!(read, json!({"type": "read_ok", "value": 78}), "{mode:?}");
+        }
+    }
+}
+
+/// `seq-kv` answering each request with the `ok` reply of another request type
+struct Confused {
+    msg_id: usize,
+}
+impl Service for Confused {
+    fn step_message(&mut self, message: RawMessage) -> Vec<RawMessage> {
+        let payload = match message.body.payload["type"].as_str() {
+            Some("read") => json!({"type": "cas_ok"}),
+            Some("write") => json!({"type": "read_ok", "value": 7}),
+            _ => json!({"type": "write_ok"}),
+        };
+        vec![message.reply_with(Some(&mut self.msg_id), payload)]
+    }
+}
+
+#[test]
+fn unexpected_kv_replies_are_ignored() {
+    for mode in [Mode::CentralCas, Mode::PerNodeKeys] {
+        let params = Params {
+            mode,
+            ..PARAMS_DEFAULT
+        };
+        let mut sim = Simulator::<Counter, _>::new(NODE_COUNT, params, 5).unwrap();
+        sim.add_service(key_value::NODE_ID_SEQ, Confused { msg_id: 0 });
+        let reply = request(&mut sim, "n0", json!({"type": "add", "delta": 2}));
+        assert_eq!(reply, json!({"type": "add_ok"}), "{mode:?}");
+        sim.run_for(params.sync_interval * 3).unwrap();
+
+        // NOTE: the nodes keep serving, from what they know locally
+        for node_id in ["n0", "n1"] {
+            let read = request(&mut sim, node_id, json!({"type": "read"}));
+            assert_eq!(read["type"], "read_ok", "{mode:?}");
+        }
+    }
+}
+
+/// `seq-kv` losing its reply to the first CAS, which it applies if `apply`
+struct LosingFirstCasReply {
+    kv: KeyValueService,
+    apply: bool,
+    lost: bool,
+}
+impl Service for LosingFirstCasReply {
+    fn step_message(&mut self, message: RawMessage) -> Vec<RawMessage> {
+        if message.body.payload["type"] != "cas" || self.lost {
+            return self.kv.step_message(message);
+        }
+        self.lost = true;
+        if self.apply {
+            self.kv.step_message(message);
+        }
+        vec![]
+    }
+}
+
+#[test]
+fn timed_out_cas_is_retried() {
+    for apply in [false, true] {
+        let params = Params {
+            kv_timeout: Duration::from_millis(300),
+            ..PARAMS_DEFAULT
+        };
+        let mut sim = Simulator::<Counter, _>::new(NODE_COUNT, params, 5).unwrap();
+        let kv = LosingFirstCasReply {
+            kv: KeyValueService::default(),
+            apply,
+            lost: false,
+        };
+        sim.add_service(key_value::NODE_ID_SEQ, kv);
+        let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+        for (index, node_id) in node_ids.iter().enumerate() {
+            request(
+                &mut sim,
+                node_id,
+                json!({"type": "add", "delta": index + 1}),
+            );
+        }
+        sim.run_for(Duration::from_secs(5)).unwrap();
+        for node_id in &node_ids {
+            let read = request(&mut sim, node_id, json!({"type": "read"}));
+            assert_eq!(read, json!({"type": "read_ok", "value": 6}), "{apply}");
+        }
+    }
+}
@@ -1,20 +1,21 @@
-use anyhow::bail;
 use serde::{Deserialize, Serialize};
-use telephone_line::{main_loop, Message, Never, NeverSender, Node};
+use telephone_line::{main_loop, Message, Never, NeverSender, Node, Output};
 
 struct Echo {
     msg_id: usize,
 }
 
 impl Node for Echo {
-    type Payload = Payload;
+    type Request = Request;
+    type Response = Never;
+    type Outbound = Reply;
     type Event = Never;
 
     fn from_init(
         _init: telephone_line::Init,
         msg_id: usize,
         _start: (),
-        _event_tx: NeverSender<Self::Payload>,
+        _event_tx: NeverSender,
     ) -> Self
     where
         Self: Sized,
@@ -22,25 +23,31 @@ impl Node for Echo {
         Self { msg_id }
     }
 
-    fn step_message(
+    fn step_request(
         &mut self,
-        message: Message<Self::Payload>,
-        output: &mut impl std::io::Write,
+        message: Message<Self::Request>,
+        output: &mut Output<'_, Self::Outbound>,
     ) -> anyhow::Result<()> {
-        let mut reply = message.reply(Some(&mut self.msg_id));
-        match reply.body.payload {
-            Payload::Echo { echo } => {
-                reply.body.payload = Payload::EchoOk { echo };
-                reply.send(output)
-            }
-            Payload::EchoOk { .. } => bail!("unexpected EchoOk from {}", reply.dest),
+        let request = message.header();
+        match message.body.payload {
+            Request::Echo { echo } => request
+                .reply_with(Some(&mut self.msg_id), Reply::EchoOk { echo })
+                .send(output),
         }
     }
 
+    fn step_response(
+        &mut self,
+        message: Message<Self::Response>,
+        _output: &mut Output<'_, Self::Outbound>,
+    ) -> anyhow::Result<()> {
+        match message.body.payload {}
+    }
+
     fn step_event(
         &mut self,
         event: Self::Event,
-        _output: &mut impl std::io::Write,
+        _output: &mut Output<'_, Self::Outbound>,
     ) -> anyhow::Result<()> {
         match event {}
     }
@@ -48,11 +55,41 @@ impl Node for Echo {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
-pub enum Payload {
+pub enum Request {
     Echo { echo: String },
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
     EchoOk { echo: String },
 }
 
 fn main() -> anyhow::Result<()> {
     main_loop::<Echo, _>(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use telephone_line::simulator::Simulator;
+
+    #[test]
+    fn echoes_each_request() {
+        let mut sim = Simulator::<Echo>::new(2, (), 0).unwrap();
+        let requests: Vec<_> = ["n0", "n1", "n0"]
+            .into_iter()
+            .enumerate()
+            .map(|(index, node_id)| {
+                let echo = format!("echo {index}");
+                let payload = json!({"type": "echo", "echo": echo});
+                (sim.send_client("c1", node_id, payload).unwrap(), echo)
+            })
+            .collect();
+        sim.run_until_idle().unwrap();
+        for (msg_id, echo) in requests {
+            let reply = sim.take_reply("c1", msg_id).unwrap();
+            assert_eq!(reply.body.payload, json!({"type": "echo_ok", "echo": echo}));
+        }
+    }
+}
@@ -1,153 +1,726 @@
-use std::collections::{BTreeMap, HashMap};
-use telephone_line::{main_loop, services::key_value, Body, Message, Never, Node};
+use anyhow::bail;
+use payload::{key_value, LogsReceive, LogsSend, Outbound};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+use storage::{Retention, Storage, RETENTION_DEFAULT};
+use telephone_line::{
+    config::{self, Config},
+    main_loop, next_msg_id,
+    routing::Router,
+    timer::TimerHandle,
+    ErrorCode, EventSender, Message, Node, Output,
+};
 
 mod payload;
+mod storage;
+#[cfg(test)]
+mod tests;
 
 struct Logs {
-    node_id: String,
+    params: Params,
     msg_id: usize,
-    logs: HashMap<String, Log>,
+    event_tx: EventSender<Timeout>,
+    kv: key_value::Client<KvRequest, usize>,
+    router: Router<Forwarded>,
+    /// Logs owned by this node
+    storage: Storage,
+    /// Client requests awaiting replies from `lin-kv` or other nodes
+    gathers: HashMap<usize, Gather>,
+    next_gather_id: usize,
+    /// Polls which found no messages, waiting for some to arrive
+    parked: HashMap<usize, ParkedPoll>,
+    /// Ids of the parked polls for each log
+    parked_by_key: HashMap<String, Vec<usize>>,
+    next_poll_id: usize,
 }
 
-#[derive(Default)]
-struct Log {
-    committed_offset: Option<usize>,
-    messages: BTreeMap<usize, usize>,
+/// Runtime configuration of the node
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Params {
+    retention: Retention,
+    /// Maximum number of messages to return for each "log" in a poll
+    poll_max_each: usize,
+    /// Maximum number of messages to return in a poll, across all logs
+    poll_max_total: usize,
+    /// Time to park a poll finding no messages (if enabled), before replying empty
+    #[serde(rename = "long_poll_ms", with = "config::millis::option")]
+    long_poll: Option<Duration>,
+    /// Time to await a reply from another node or `lin-kv`, before failing the client request
+    /// as temporarily unavailable (a forwarded poll may also be parked for `long_poll`)
+    #[serde(rename = "request_timeout_ms", with = "config::millis")]
+    request_timeout: Duration,
 }
+const PARAMS_DEFAULT: Params = Params {
+    retention: RETENTION_DEFAULT,
+    poll_max_each: 5,
+    poll_max_total: usize::MAX,
+    long_poll: None,
+    request_timeout: Duration::from_secs(1),
+};
+impl Config for Params {
+    const ENV_PREFIX: &'static str = "LOGS";
+    const PRESETS: &'static [(&'static str, Self)] = &[("default", PARAMS_DEFAULT)];
 
-/// Maximum number of messages to return for each "log" in a poll
-const MAX_POLL_MESSAGE_EACH: usize = 5;
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.retention.segment_size == 0 {
+            bail!("retention.segment_size must be positive");
+        }
+        if self.poll_max_each == 0 || self.poll_max_total == 0 {
+            bail!("poll_max_each and poll_max_total must be positive");
+        }
+        if self.long_poll.is_some_and(|long_poll| long_poll.is_zero()) {
+            bail!("long_poll_ms must be positive (or null to disable long-polling)");
+        }
+        if self.request_timeout.is_zero() {
+            bail!("request_timeout_ms must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// Client request to reply to once all replies for its keys have arrived
+struct Gather {
+    request: Message<()>,
+    remaining: usize,
+    reply: LogsSend,
+}
+
+/// Poll of logs owned by this node, to reply to once any has new messages
+struct ParkedPoll {
+    request: Message<()>,
+    offsets: Vec<(String, usize)>,
+    timeout: TimerHandle,
+}
+
+/// Request forwarded to the owner of its keys
+enum Forwarded {
+    /// Client `send`, to reply to with the owner's reply
+    Send { request: Message<()> },
+    /// Part of a client `poll`
+    Poll { gather_id: usize },
+    /// Part of a client `commit_offsets`
+    Commit { gather_id: usize },
+}
+
+/// Outstanding request to `lin-kv`
+enum KvRequest {
+    /// Read of a log's committed offset, before raising it to `offset`
+    ReadForCommit {
+        gather_id: usize,
+        key: String,
+        offset: usize,
+    },
+    /// Raise of a log's committed offset to `offset`
+    CommitOffset {
+        gather_id: usize,
+        key: String,
+        offset: usize,
+    },
+    ReadCommittedOffset {
+        gather_id: usize,
+        key: String,
+    },
+}
+
+/// Prefix of the `lin-kv` keys holding the committed offset of each log
+const KEY_PREFIX_COMMITTED: &str = "committed/";
+
+/// Timer events, all of which are timeouts
+#[derive(Serialize, Deserialize)]
+enum Timeout {
+    LongPoll { poll_id: usize },
+    Forward { msg_id: usize },
+    Kv { msg_id: usize },
+}
 
-impl Node for Logs {
-    type Payload = payload::Raw;
-    type Event = Never;
+impl Node<Params> for Logs {
+    type Request = LogsReceive;
+    type Response = payload::Response;
+    type Outbound = Outbound;
+    type Event = Timeout;
 
     fn from_init(
         init: telephone_line::Init,
         msg_id: usize,
-        _start: (),
-        _event_tx: telephone_line::EventSender<Self::Payload, Self::Event>,
+        params: Params,
+        event_tx: EventSender<Self::Event>,
     ) -> Self
     where
         Self: Sized,
     {
         Self {
-            node_id: init.node_id,
+            params,
             msg_id,
-            logs: HashMap::new(),
+            event_tx,
+            kv: key_value::Client::new(key_value::Service::Lin, init.node_id.clone()),
+            router: Router::new(init.node_id, &init.node_ids),
+            storage: Storage::new(params.retention),
+            gathers: HashMap::new(),
+            next_gather_id: 0,
+            parked: HashMap::new(),
+            parked_by_key: HashMap::new(),
+            next_poll_id: 0,
         }
     }
 
-    fn step_message(
+    fn step_request(
         &mut self,
-        message: telephone_line::Message<Self::Payload>,
-        output: &mut impl std::io::Write,
+        message: Message<Self::Request>,
+        output: &mut Output<'_, Self::Outbound>,
     ) -> anyhow::Result<()> {
-        // TODO: let original_in_reply_to = message.body.in_reply_to;
-        let mut reply = message.reply(Some(&mut self.msg_id));
-
-        let receive_payload = reply
-            .body
-            .payload
-            .clone()
-            .try_into()
-            .unwrap_or_else(|payload| panic!("unexpected message {payload:?}"));
-        match receive_payload {
-            payload::Receive::Logs(payload) => match payload {
-                payload::LogsReceive::Send { key, msg } => {
-                    let log = self.logs.entry(key).or_insert_with(Log::default);
-                    let offset = log
-                        .messages
-                        .last_key_value()
-                        .map(|(offset, _msg)| offset + 1)
-                        .unwrap_or_default();
-                    log.messages.insert(offset, msg);
-
-                    reply.body.payload = payload::LogsSend::SendOk { offset }.into();
-                    reply.send(output)
+        match message.body.payload.clone() {
+            LogsReceive::Send { key, msg } => {
+                if !self.router.is_local(&key) {
+                    let owner = self.router.owner(&key).to_string();
+                    let context = Forwarded::Send {
+                        request: message.header(),
+                    };
+                    let forward = LogsReceive::Send { key, msg };
+                    return self.forward(&owner, forward, context, output);
+                }
+                let offset = self.storage.append(key.clone(), msg);
+
+                message
+                    .reply_with(
+                        Some(&mut self.msg_id),
+                        Outbound::Logs(LogsSend::SendOk { offset }),
+                    )
+                    .send(output)?;
+                self.wake_polls(&key, output)
+            }
+            LogsReceive::Poll { offsets } => {
+                let mut partitions = self.router.partition(offsets, |(key, _)| key);
+                let local = partitions.remove(self.router.node_id()).unwrap_or_default();
+                let msgs = self.poll_local(&local);
+                if let Some(timeout) = self.params.long_poll {
+                    if partitions.is_empty() && msgs.values().all(Vec::is_empty) {
+                        self.park_poll(message.header(), local, timeout);
+                        return Ok(());
+                    }
                 }
-                payload::LogsReceive::Poll { offsets } => {
-                    let msgs = offsets
-                        .into_iter()
-                        .filter_map(|(key, offset)| {
-                            let log = self.logs.get(&key)?;
-                            let messages: Vec<_> = log
-                                .messages
-                                .range(offset..)
-                                .map(|(&offset, &message)| (offset, message))
-                                .take(MAX_POLL_MESSAGE_EACH)
-                                .collect();
-                            Some((key, messages))
-                        })
-                        .collect();
-
-                    reply.body.payload = payload::LogsSend::PollOk { msgs }.into();
-                    reply.send(output)
+                let reply = LogsSend::PollOk { msgs };
+                let gather_id = self.start_gather(message.header(), partitions.len(), reply);
+                for (owner, offsets) in partitions {
+                    let forward = LogsReceive::Poll {
+                        offsets: offsets.into_iter().collect(),
+                    };
+                    let context = Forwarded::Poll { gather_id };
+                    self.forward(&owner, forward, context, output)?;
                 }
-                payload::LogsReceive::CommitOffsets { offsets } => {
-                    for (key, offset) in offsets {
-                        if let Some(log) = self.logs.get_mut(&key) {
-                            log.committed_offset = Some(offset);
+                self.finish_gather(gather_id, output)
+            }
+            LogsReceive::CommitOffsets { offsets } => {
+                // NOTE: the owner writes the offset to `lin-kv`, then truncates the log
+                let mut partitions = self.router.partition(offsets, |(key, _)| key);
+                let local = partitions.remove(self.router.node_id()).unwrap_or_default();
+                let count = local.len() + partitions.len();
+                let reply = LogsSend::CommitOffsetsOk;
+                let gather_id = self.start_gather(message.header(), count, reply);
+                for (owner, offsets) in partitions {
+                    let forward = LogsReceive::CommitOffsets {
+                        offsets: offsets.into_iter().collect(),
+                    };
+                    let context = Forwarded::Commit { gather_id };
+                    self.forward(&owner, forward, context, output)?;
+                }
+                for (key, offset) in local {
+                    self.read_for_commit(gather_id, key, offset, output)?;
+                }
+                self.finish_gather(gather_id, output)
+            }
+            LogsReceive::ListCommittedOffsets { keys } => {
+                let reply = LogsSend::ListCommittedOffsetsOk {
+                    offsets: HashMap::new(),
+                };
+                let gather_id = self.start_gather(message.header(), keys.len(), reply);
+                for key in keys {
+                    let msg_id = self.kv.read(
+                        format!("{KEY_PREFIX_COMMITTED}{key}"),
+                        KvRequest::ReadCommittedOffset { gather_id, key },
+                        &mut self.msg_id,
+                        output,
+                    )?;
+                    self.start_kv_timeout(msg_id);
+                }
+                self.finish_gather(gather_id, output)
+            }
+        }
+    }
+
+    fn step_response(
+        &mut self,
+        message: Message<Self::Response>,
+        output: &mut Output<'_, Self::Outbound>,
+    ) -> anyhow::Result<()> {
+        match message.body.payload.clone() {
+            payload::Response::Kv(payload) => {
+                let Some(kv_reply) = self.kv.receive(&message, payload) else {
+                    // NOTE: the request timed out, and its client request already failed
+                    eprintln!(
+                        "ignoring late lin-kv reply to {:?}",
+                        message.body.in_reply_to
+                    );
+                    return Ok(());
+                };
+                self.step_kv_reply(kv_reply, output)
+            }
+            payload::Response::Logs(reply) => {
+                let Some(forwarded) = self.router.take_reply(&message) else {
+                    eprintln!("ignoring late reply {reply:?} from {}", message.src);
+                    return Ok(());
+                };
+                match (forwarded, reply) {
+                    (Forwarded::Send { request }, reply @ LogsSend::SendOk { .. }) => request
+                        .reply_with(Some(&mut self.msg_id), Outbound::Logs(reply))
+                        .send(output),
+                    (Forwarded::Poll { gather_id }, LogsSend::PollOk { msgs }) => {
+                        let found = msgs.values().any(|messages| !messages.is_empty());
+                        if let Some(Gather {
+                            reply: LogsSend::PollOk { msgs: gathered },
+                            ..
+                        }) = self.gathers.get_mut(&gather_id)
+                        {
+                            gathered.extend(msgs);
+                        }
+                        // NOTE: other owners may have parked their part, so reply without it
+                        if found && self.params.long_poll.is_some() {
+                            self.reply_gather(gather_id, output)
+                        } else {
+                            self.step_gather(gather_id, output)
                         }
                     }
-
-                    reply.body.payload = payload::LogsSend::CommitOffsetsOk.into();
-                    reply.send(output)
-                }
-                payload::LogsReceive::ListCommittedOffsets { keys } => {
-                    let offsets = keys
-                        .into_iter()
-                        .filter_map(|key| {
-                            let log = self.logs.get(&key)?;
-                            Some((key, log.committed_offset?))
-                        })
-                        .collect();
-
-                    reply.body.payload =
-                        payload::LogsSend::ListCommittedOffsetsOk { offsets }.into();
-                    reply.send(output)
+                    (Forwarded::Commit { gather_id }, LogsSend::CommitOffsetsOk) => {
+                        self.step_gather(gather_id, output)
+                    }
+                    (forwarded, reply) => {
+                        let text = format!("unexpected reply {reply:?} from {}", message.src);
+                        eprintln!("{text}");
+                        let error = telephone_line::Error::new(ErrorCode::Crash, text);
+                        self.fail_forwarded(forwarded, error, output)
+                    }
                 }
-            },
-            payload::Receive::Kv(payload) => match payload {
-                key_value::Receive::ReadOk { value } => todo!(),
-                key_value::Receive::WriteOk => todo!(),
-                key_value::Receive::CasOk => todo!(),
-                key_value::Receive::Error { code, text } => todo!(),
-            },
+            }
+            payload::Response::Error(error) => {
+                let Some(forwarded) = self.router.take_reply(&message) else {
+                    eprintln!("ignoring late {error} from {}", message.src);
+                    return Ok(());
+                };
+                self.fail_forwarded(forwarded, error, output)
+            }
         }
     }
 
     fn step_event(
         &mut self,
-        never: Self::Event,
-        _output: &mut impl std::io::Write,
+        event: Self::Event,
+        output: &mut Output<'_, Self::Outbound>,
     ) -> anyhow::Result<()> {
-        match never {}
+        match event {
+            Timeout::LongPoll { poll_id } => self.reply_parked(poll_id, true, output),
+            Timeout::Forward { msg_id } => {
+                let Some(forwarded) = self.router.take_timed_out(msg_id) else {
+                    return Ok(());
+                };
+                let error = telephone_line::Error::temporarily_unavailable(format!(
+                    "no reply to forwarded request {msg_id} in time"
+                ));
+                self.fail_forwarded(forwarded, error, output)
+            }
+            Timeout::Kv { msg_id } => {
+                let Some(
+                    KvRequest::ReadForCommit { gather_id, .. }
+                    | KvRequest::CommitOffset { gather_id, .. }
+                    | KvRequest::ReadCommittedOffset { gather_id, .. },
+                ) = self.kv.take_timed_out(msg_id)
+                else {
+                    return Ok(());
+                };
+                let error = telephone_line::Error::temporarily_unavailable(format!(
+                    "no reply to lin-kv request {msg_id} in time"
+                ));
+                self.fail_gather(gather_id, error, output)
+            }
+        }
+    }
+
+    fn shutdown(&mut self, _output: &mut Output<'_, Self::Outbound>) -> anyhow::Result<()> {
+        eprintln!("storage: {}", self.storage.metrics());
+        Ok(())
     }
 }
 impl Logs {
-    fn kv_message(
+    /// Returns the messages from each requested offset, for logs owned by this node
+    fn poll_local(&self, offsets: &[(String, usize)]) -> HashMap<String, Vec<(usize, usize)>> {
+        // NOTE: sort for the total limit to apply deterministically
+        let mut offsets: Vec<_> = offsets.iter().collect();
+        offsets.sort();
+        let mut remaining = self.params.poll_max_total;
+        offsets
+            .into_iter()
+            .filter_map(|(key, offset)| {
+                let limit = self.params.poll_max_each.min(remaining);
+                let messages = self.storage.read(key, *offset, limit)?;
+                remaining -= messages.len();
+                Some((key.clone(), messages))
+            })
+            .collect()
+    }
+
+    /// Parks a poll of logs owned by this node, until messages arrive or `timeout` elapses
+    fn park_poll(
+        &mut self,
+        request: Message<()>,
+        offsets: Vec<(String, usize)>,
+        timeout: Duration,
+    ) {
+        let poll_id = next_msg_id(&mut self.next_poll_id);
+        for (key, _) in &offsets {
+            self.parked_by_key
+                .entry(key.clone())
+                .or_default()
+                .push(poll_id);
+        }
+        let timeout = self.event_tx.after(timeout, Timeout::LongPoll { poll_id });
+        let parked = ParkedPoll {
+            request,
+            offsets,
+            timeout,
+        };
+        self.parked.insert(poll_id, parked);
+    }
+
+    /// Replies to the polls parked on the log `key` which now find messages
+    fn wake_polls(&mut self, key: &str, output: &mut Output<'_, Outbound>) -> anyhow::Result<()> {
+        let Some(poll_ids) = self.parked_by_key.get(key) else {
+            return Ok(());
+        };
+        for poll_id in poll_ids.clone() {
+            self.reply_parked(poll_id, false, output)?;
+        }
+        Ok(())
+    }
+
+    /// Replies to the parked poll if it finds messages (or if `force`)
+    fn reply_parked(
+        &mut self,
+        poll_id: usize,
+        force: bool,
+        output: &mut Output<'_, Outbound>,
+    ) -> anyhow::Result<()> {
+        let Some(parked) = self.parked.get(&poll_id) else {
+            return Ok(());
+        };
+        let msgs = self.poll_local(&parked.offsets);
+        if !force && msgs.values().all(Vec::is_empty) {
+            return Ok(());
+        }
+        let Some(ParkedPoll {
+            request,
+            offsets,
+            timeout,
+        }) = self.parked.remove(&poll_id)
+        else {
+            return Ok(());
+        };
+        timeout.cancel();
+        for (key, _) in offsets {
+            if let Some(poll_ids) = self.parked_by_key.get_mut(&key) {
+                poll_ids.retain(|&id| id != poll_id);
+                if poll_ids.is_empty() {
+                    self.parked_by_key.remove(&key);
+                }
+            }
+        }
+        request
+            .reply_with(
+                Some(&mut self.msg_id),
+                Outbound::Logs(LogsSend::PollOk { msgs }),
+            )
+            .send(output)
+    }
+
+    fn step_kv_reply(
         &mut self,
-        payload_from_key_fn: impl FnOnce(String) -> key_value::SendLin,
-    ) -> Message<payload::Raw> {
-        /// Key for the centralized count
-        const KEY_COUNT: &str = "c";
-
-        let msg_id = telephone_line::next_msg_id(&mut self.msg_id);
-        let key = KEY_COUNT.to_string();
-        let payload = payload_from_key_fn(key).into();
-        Message {
-            src: self.node_id.clone(),
-            dest: payload::key_value::NODE_ID_LIN.to_string(),
-            body: Body {
-                msg_id: Some(msg_id),
-                in_reply_to: None,
-                payload,
+        kv_reply: key_value::Reply<KvRequest, usize>,
+        output: &mut Output<'_, Outbound>,
+    ) -> anyhow::Result<()> {
+        let key_value::Reply {
+            msg_id,
+            context,
+            result,
+        } = kv_reply;
+        match (context, result) {
+            (
+                KvRequest::ReadForCommit {
+                    gather_id,
+                    key,
+                    offset,
+                },
+                Ok(key_value::Response::ReadOk { value }),
+            ) => {
+                if value >= offset {
+                    self.storage.commit(&key, offset);
+                    return self.step_gather(gather_id, output);
+                }
+                self.commit_offset(gather_id, key, Some(value), offset, output)
+            }
+            (
+                KvRequest::ReadForCommit {
+                    gather_id,
+                    key,
+                    offset,
+                },
+                Err(error),
+            ) if error.code == key_value::ErrorCode::KeyDoesNotExist => {
+                self.commit_offset(gather_id, key, None, offset, output)
+            }
+            (
+                KvRequest::CommitOffset {
+                    gather_id,
+                    key,
+                    offset,
+                },
+                Ok(key_value::Response::CasOk),
+            ) => {
+                // NOTE: truncate only once the offset is stored, in case the commit fails
+                self.storage.commit(&key, offset);
+                self.step_gather(gather_id, output)
+            }
+            (
+                KvRequest::CommitOffset {
+                    gather_id,
+                    key,
+                    offset,
+                },
+                Err(error),
+            ) if matches!(
+                error.code,
+                key_value::ErrorCode::PreconditionFailed | key_value::ErrorCode::KeyDoesNotExist
+            ) =>
+            {
+                // NOTE: another commit raced this one, so re-read to keep the higher offset
+                self.read_for_commit(gather_id, key, offset, output)
+            }
+            (
+                KvRequest::ReadCommittedOffset { gather_id, key },
+                Ok(key_value::Response::ReadOk { value }),
+            ) => {
+                if let Some(Gather {
+                    reply: LogsSend::ListCommittedOffsetsOk { offsets },
+                    ..
+                }) = self.gathers.get_mut(&gather_id)
+                {
+                    offsets.insert(key, value);
+                }
+                self.step_gather(gather_id, output)
+            }
+            (KvRequest::ReadCommittedOffset { gather_id, .. }, Err(error))
+                if error.code == key_value::ErrorCode::KeyDoesNotExist =>
+            {
+                self.step_gather(gather_id, output)
+            }
+            (
+                KvRequest::ReadForCommit { gather_id, .. }
+                | KvRequest::CommitOffset { gather_id, .. }
+                | KvRequest::ReadCommittedOffset { gather_id, .. },
+                Err(error),
+            ) => self.fail_gather(gather_id, error, output),
+            (
+                KvRequest::ReadForCommit { gather_id, .. }
+                | KvRequest::CommitOffset { gather_id, .. }
+                | KvRequest::ReadCommittedOffset { gather_id, .. },
+                Ok(response),
+            ) => {
+                let text = format!("unexpected lin-kv response {response:?} to {msg_id}");
+                eprintln!("{text}");
+                let error = telephone_line::Error::new(ErrorCode::Crash, text);
+                self.fail_gather(gather_id, error, output)
+            }
+        }
+    }
+
+    /// Reads the committed offset of the log `key` from `lin-kv`, to raise it to `offset`
+    fn read_for_commit(
+        &mut self,
+        gather_id: usize,
+        key: String,
+        offset: usize,
+        output: &mut Output<'_, Outbound>,
+    ) -> anyhow::Result<()> {
+        let msg_id = self.kv.read(
+            format!("{KEY_PREFIX_COMMITTED}{key}"),
+            KvRequest::ReadForCommit {
+                gather_id,
+                key,
+                offset,
             },
+            &mut self.msg_id,
+            output,
+        )?;
+        self.start_kv_timeout(msg_id);
+        Ok(())
+    }
+
+    /// Raises the committed offset of the log `key` in `lin-kv` from `current` (`None` if not
+    /// yet committed) to `offset`
+    ///
+    /// Writing unconditionally could lower the offset, if commits of the log race.
+    fn commit_offset(
+        &mut self,
+        gather_id: usize,
+        key: String,
+        current: Option<usize>,
+        offset: usize,
+        output: &mut Output<'_, Outbound>,
+    ) -> anyhow::Result<()> {
+        let request = key_value::Request::Cas {
+            key: format!("{KEY_PREFIX_COMMITTED}{key}"),
+            // NOTE: if created meanwhile, an equal offset is left as-is (and others re-read)
+            from: current.unwrap_or(offset),
+            to: offset,
+            create_if_not_exists: current.is_none(),
+        };
+        let context = KvRequest::CommitOffset {
+            gather_id,
+            key,
+            offset,
+        };
+        let msg_id = self.kv.send(request, context, &mut self.msg_id, output)?;
+        self.start_kv_timeout(msg_id);
+        Ok(())
+    }
+
+    fn start_kv_timeout(&self, msg_id: usize) {
+        self.event_tx
+            .after(self.params.request_timeout, Timeout::Kv { msg_id });
+    }
+
+    /// Forwards `request` to the owner of its keys, failing the client request (per `context`)
+    /// if the owner does not reply in time
+    fn forward(
+        &mut self,
+        owner: &str,
+        request: LogsReceive,
+        context: Forwarded,
+        output: &mut Output<'_, Outbound>,
+    ) -> anyhow::Result<()> {
+        let mut timeout = self.params.request_timeout;
+        if let LogsReceive::Poll { .. } = request {
+            timeout += self.params.long_poll.unwrap_or_default();
         }
+        let forward = Outbound::Forward(request);
+        let msg_id = self
+            .router
+            .forward(owner, forward, context, &mut self.msg_id, output)?;
+        self.event_tx.after(timeout, Timeout::Forward { msg_id });
+        Ok(())
+    }
+
+    /// Replies to the client request of a forwarded request with `error`
+    fn fail_forwarded(
+        &mut self,
+        forwarded: Forwarded,
+        error: telephone_line::Error,
+        output: &mut Output<'_, Outbound>,
+    ) -> anyhow::Result<()> {
+        match forwarded {
+            Forwarded::Send { request } => request.error_reply(error).send(output),
+            Forwarded::Poll { gather_id } | Forwarded::Commit { gather_id } => {
+                self.fail_gather(gather_id, error, output)
+            }
+        }
+    }
+
+    /// Starts gathering `count` replies for `request`, which is answered with `reply`
+    fn start_gather(&mut self, request: Message<()>, count: usize, reply: LogsSend) -> usize {
+        let gather_id = next_msg_id(&mut self.next_gather_id);
+        let gather = Gather {
+            request,
+            remaining: count,
+            reply,
+        };
+        self.gathers.insert(gather_id, gather);
+        gather_id
+    }
+
+    /// Counts one reply for the gather, replying once all have arrived
+    fn step_gather(
+        &mut self,
+        gather_id: usize,
+        output: &mut Output<'_, Outbound>,
+    ) -> anyhow::Result<()> {
+        if let Some(gather) = self.gathers.get_mut(&gather_id) {
+            gather.remaining -= 1;
+        }
+        self.finish_gather(gather_id, output)
+    }
+
+    /// Replies to the gather's request with `error`, ignoring the replies still to arrive
+    fn fail_gather(
+        &mut self,
+        gather_id: usize,
+        error: telephone_line::Error,
+        output: &mut Output<'_, Outbound>,
+    ) -> anyhow::Result<()> {
+        let Some(Gather { request, .. }) = self.gathers.remove(&gather_id) else {
+            return Ok(());
+        };
+        request.error_reply(error).send(output)
+    }
+
+    /// Replies to the gather's request, if no replies remain
+    fn finish_gather(
+        &mut self,
+        gather_id: usize,
+        output: &mut Output<'_, Outbound>,
+    ) -> anyhow::Result<()> {
+        if self
+            .gathers
+            .get(&gather_id)
+            .is_none_or(|gather| gather.remaining > 0)
+        {
+            return Ok(());
+        }
+        self.reply_gather(gather_id, output)
+    }
+
+    /// Replies to the gather's request, ignoring the replies still to arrive
+    fn reply_gather(
+        &mut self,
+        gather_id: usize,
+        output: &mut Output<'_, Outbound>,
+    ) -> anyhow::Result<()> {
+        let Some(Gather {
+            request, mut reply, ..
+        }) = self.gathers.remove(&gather_id)
+        else {
+            return Ok(());
+        };
+        if let LogsSend::PollOk { msgs } = &mut reply {
+            limit_total(msgs, self.params.poll_max_total);
+        }
+        request
+            .reply_with(Some(&mut self.msg_id), Outbound::Logs(reply))
+            .send(output)
+    }
+}
+
+/// Drops the latest messages beyond `max_total` (taking logs in order of key)
+fn limit_total(msgs: &mut HashMap<String, Vec<(usize, usize)>>, max_total: usize) {
+    let mut keys: Vec<_> = msgs.keys().cloned().collect();
+    keys.sort();
+    let mut remaining = max_total;
+    for key in keys {
+        let Some(messages) = msgs.get_mut(&key) else {
+            continue;
+        };
+        messages.truncate(remaining);
+        remaining -= messages.len();
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<Logs, _>(())
+    let params = config::load::<Params>(std::env::args().skip(1))?;
+    main_loop::<Logs, _>(params)
 }
@@ -0,0 +1,307 @@
+//! Segmented storage of the logs owned by a node, truncated below committed offsets
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+/// Limits on the messages kept in memory
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    /// Number of consecutive messages in each segment (the unit of truncation)
+    pub segment_size: usize,
+    /// Maximum number of messages retained by the node, if any
+    ///
+    /// When exceeded, the oldest segments of the largest logs are evicted, even if not yet
+    /// committed (so polls no longer see them).
+    pub max_messages: Option<usize>,
+}
+pub const RETENTION_DEFAULT: Retention = Retention {
+    segment_size: 64,
+    max_messages: None,
+};
+
+/// Counts of messages stored and dropped, reported on shutdown
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Metrics {
+    pub appended: usize,
+    /// Messages dropped below a committed offset
+    pub truncated: usize,
+    /// Messages dropped to stay within [`Retention::max_messages`]
+    pub evicted: usize,
+    /// Largest number of messages retained at once
+    pub peak_retained: usize,
+}
+impl std::fmt::Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            appended,
+            truncated,
+            evicted,
+            peak_retained,
+        } = self;
+        let retained = appended - truncated - evicted;
+        write!(f, "{appended} appended, {truncated} truncated, {evicted} evicted, {retained} retained (peak {peak_retained})")
+    }
+}
+
+pub struct Storage {
+    retention: Retention,
+    logs: BTreeMap<String, Log>,
+    /// Number of messages across all logs
+    retained: usize,
+    metrics: Metrics,
+}
+impl Storage {
+    pub fn new(retention: Retention) -> Self {
+        Self {
+            retention,
+            logs: BTreeMap::new(),
+            retained: 0,
+            metrics: Metrics::default(),
+        }
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics
+    }
+
+    /// Appends `msg` to the log `key`, returning its offset
+    pub fn append(&mut self, key: String, msg: usize) -> usize {
+        let log = self.logs.entry(key).or_default();
+        let offset = log.append(msg, self.retention.segment_size);
+        self.retained += 1;
+        self.metrics.appended += 1;
+        self.enforce_max_messages();
+        // NOTE: measured after eviction, as the cap is never exceeded between operations
+        self.metrics.peak_retained = self.metrics.peak_retained.max(self.retained);
+        offset
+    }
+
+    /// Returns up to `limit` messages of the log `key`, from `offset` or the earliest retained
+    /// offset (if later), or `None` if the log is unknown
+    pub fn read(&self, key: &str, offset: usize, limit: usize) -> Option<Vec<(usize, usize)>> {
+        let log = self.logs.get(key)?;
+        Some(log.read(offset, limit))
+    }
+
+    /// Records that the log `key` is committed up to `offset`, dropping the segments before it
+    pub fn commit(&mut self, key: &str, offset: usize) {
+        let Some(log) = self.logs.get_mut(key) else {
+            return;
+        };
+        let truncated = log.truncate_before(offset);
+        self.retained -= truncated;
+        self.metrics.truncated += truncated;
+    }
+
+    fn enforce_max_messages(&mut self) {
+        let Some(max_messages) = self.retention.max_messages else {
+            return;
+        };
+        while self.retained > max_messages {
+            let Some(log) = self.logs.values_mut().max_by_key(|log| log.len()) else {
+                break;
+            };
+            let evicted = log.evict_oldest_segment();
+            if evicted == 0 {
+                break;
+            }
+            self.retained -= evicted;
+            self.metrics.evicted += evicted;
+        }
+    }
+}
+
+/// Messages of one log, in segments of consecutive offsets
+#[derive(Default)]
+struct Log {
+    segments: VecDeque<Segment>,
+    next_offset: usize,
+    /// Number of messages across all segments
+    len: usize,
+}
+struct Segment {
+    base_offset: usize,
+    messages: Vec<usize>,
+}
+impl Segment {
+    fn end_offset(&self) -> usize {
+        self.base_offset + self.messages.len()
+    }
+}
+impl Log {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn append(&mut self, msg: usize, segment_size: usize) -> usize {
+        let offset = self.next_offset;
+        match self.segments.back_mut() {
+            Some(segment) if segment.messages.len() < segment_size => segment.messages.push(msg),
+            _ => self.segments.push_back(Segment {
+                base_offset: offset,
+                messages: vec![msg],
+            }),
+        }
+        self.next_offset += 1;
+        self.len += 1;
+        offset
+    }
+
+    fn read(&self, offset: usize, limit: usize) -> Vec<(usize, usize)> {
+        // NOTE: segments may be missing (evicted), so search rather than index by offset
+        let first = self
+            .segments
+            .partition_point(|segment| segment.end_offset() <= offset);
+        self.segments
+            .range(first..)
+            .flat_map(|segment| (segment.base_offset..).zip(segment.messages.iter().copied()))
+            .skip_while(|&(message_offset, _)| message_offset < offset)
+            .take(limit)
+            .collect()
+    }
+
+    /// Drops the segments entirely before `offset`, returning the number of messages dropped
+    fn truncate_before(&mut self, offset: usize) -> usize {
+        let mut truncated = 0;
+        while let Some(segment) = self.segments.front() {
+            if segment.end_offset() > offset {
+                break;
+            }
+            truncated += segment.messages.len();
+            self.segments.pop_front();
+        }
+        self.len -= truncated;
+        truncated
+    }
+
+    /// Drops the oldest segment, returning the number of messages dropped
+    fn evict_oldest_segment(&mut self) -> usize {
+        let evicted = self
+            .segments
+            .pop_front()
+            .map_or(0, |segment| segment.messages.len());
+        self.len -= evicted;
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(segment_size: usize, max_messages: Option<usize>) -> Storage {
+        Storage::new(Retention {
+            segment_size,
+            max_messages,
+        })
+    }
+
+    /// Appends `count` messages to the log `key`, each message being its offset
+    fn append(storage: &mut Storage, key: &str, count: usize) {
+        for _ in 0..count {
+            let offset = storage.logs.get(key).map_or(0, |log| log.next_offset);
+            assert_eq!(storage.append(key.to_string(), offset), offset);
+        }
+    }
+
+    fn read_offsets(storage: &Storage, key: &str, offset: usize) -> Vec<usize> {
+        let messages = storage.read(key, offset, usize::MAX).unwrap();
+        assert!(messages.iter().all(|(offset, msg)| offset == msg));
+        messages.into_iter().map(|(offset, _)| offset).collect()
+    }
+
+    #[test]
+    fn reads_across_segment_boundaries() {
+        let mut storage = storage(3, None);
+        append(&mut storage, "k1", 8);
+        let log = &storage.logs["k1"];
+        let segments: Vec<_> = log
+            .segments
+            .iter()
+            .map(|segment| (segment.base_offset, segment.messages.len()))
+            .collect();
+        assert_eq!(segments, [(0, 3), (3, 3), (6, 2)]);
+
+        assert_eq!(
+            storage.read("k1", 2, 4).unwrap(),
+            [(2, 2), (3, 3), (4, 4), (5, 5)]
+        );
+        assert_eq!(read_offsets(&storage, "k1", 6), [6, 7]);
+        assert!(storage.read("k1", 8, 5).unwrap().is_empty());
+        assert!(storage.read("k2", 0, 5).is_none());
+    }
+
+    #[test]
+    fn truncates_whole_segments_before_commit() {
+        let mut storage = storage(3, None);
+        append(&mut storage, "k1", 8);
+
+        // the segment holding the committed offset is kept
+        storage.commit("k1", 4);
+        assert_eq!(read_offsets(&storage, "k1", 4), [4, 5, 6, 7]);
+        assert_eq!(storage.metrics().truncated, 3);
+
+        // reads below the retained range start at the earliest retained offset
+        assert_eq!(read_offsets(&storage, "k1", 0), [3, 4, 5, 6, 7]);
+
+        // committing a lower offset (or an unknown log) keeps everything
+        storage.commit("k1", 1);
+        storage.commit("k2", 5);
+        assert_eq!(read_offsets(&storage, "k1", 0), [3, 4, 5, 6, 7]);
+
+        // offsets continue after truncating every segment
+        storage.commit("k1", 8);
+        assert!(read_offsets(&storage, "k1", 0).is_empty());
+        append(&mut storage, "k1", 1);
+        assert_eq!(read_offsets(&storage, "k1", 0), [8]);
+        assert_eq!(storage.retained, 1);
+    }
+
+    #[test]
+    fn evicts_oldest_segments_of_largest_logs() {
+        let mut storage = storage(2, Some(6));
+        append(&mut storage, "k1", 5);
+        append(&mut storage, "k2", 1);
+        assert_eq!(storage.metrics().evicted, 0);
+
+        // k1 is the largest log, so loses its oldest segment
+        append(&mut storage, "k2", 1);
+        assert_eq!(read_offsets(&storage, "k1", 0), [2, 3, 4]);
+        assert_eq!(read_offsets(&storage, "k2", 0), [0, 1]);
+
+        // now k2 is the largest log
+        append(&mut storage, "k2", 2);
+        assert_eq!(read_offsets(&storage, "k1", 0), [2, 3, 4]);
+        assert_eq!(read_offsets(&storage, "k2", 0), [2, 3]);
+
+        let metrics = storage.metrics();
+        assert_eq!(metrics.appended, 9);
+        assert_eq!(metrics.evicted, 4);
+        assert_eq!(metrics.truncated, 0);
+        assert_eq!(metrics.peak_retained, 6);
+        assert_eq!(storage.retained, 5);
+        assert_eq!(
+            metrics.to_string(),
+            "9 appended, 0 truncated, 4 evicted, 5 retained (peak 6)"
+        );
+    }
+
+    #[test]
+    fn peak_retained_stays_within_max_messages() {
+        for (segment_size, max_messages) in [(1, 0), (2, 5), (3, 6), (4, 3)] {
+            let mut storage = storage(segment_size, Some(max_messages));
+            for round in 0..10 {
+                append(&mut storage, &format!("k{}", round % 3), round + 1);
+                assert!(storage.retained <= max_messages);
+            }
+            let metrics = storage.metrics();
+            assert!(
+                metrics.peak_retained <= max_messages,
+                "peak {} over cap {max_messages}",
+                metrics.peak_retained
+            );
+            assert!(metrics.evicted > 0);
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! Simulated clusters of logs nodes, sharing a `lin-kv`
+
+use super::*;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use telephone_line::{
+    simulator::{KeyValueService, Service, Simulator},
+    RawMessage,
+};
+
+const NODE_COUNT: usize = 3;
+const KEYS: [&str; 4] = ["k1", "k2", "k3", "k4"];
+
+fn simulator(params: Params) -> Simulator<Logs, Params> {
+    let mut sim = Simulator::new(NODE_COUNT, params, 5).unwrap();
+    sim.add_service(key_value::NODE_ID_LIN, KeyValueService::default());
+    sim
+}
+
+/// Sends `payload` from a client to `node_id`, returning the reply payload
+fn request(sim: &mut Simulator<Logs, Params>, node_id: &str, payload: Value) -> Value {
+    let msg_id = sim.send_client("c1", node_id, payload).unwrap();
+    sim.run_until_idle().unwrap();
+    sim.take_reply("c1", msg_id).unwrap().body.payload
+}
+
+/// Sends `count` messages to each key, through the nodes in turn, returning the offsets of
+/// each key's messages
+fn send_messages(sim: &mut Simulator<Logs, Params>, count: usize) -> Vec<Vec<usize>> {
+    let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+    let mut offsets = vec![vec![]; KEYS.len()];
+    for index in 0..count * KEYS.len() {
+        let key_index = index % KEYS.len();
+        let node_id = &node_ids[index % node_ids.len()];
+        let payload = json!({"type": "send", "key": KEYS[key_index], "msg": index});
+        let reply = request(sim, node_id, payload);
+        assert_eq!(reply["type"], "send_ok", "{reply}");
+        offsets[key_index].push(reply["offset"].as_u64().unwrap() as usize);
+    }
+    offsets
+}
+
+#[test]
+fn offsets_increase_for_each_key() {
+    let mut sim = simulator(PARAMS_DEFAULT);
+    for offsets in send_messages(&mut sim, 5) {
+        assert!(
+            offsets.windows(2).all(|pair| pair[0] < pair[1]),
+            "{offsets:?}"
+        );
+    }
+}
+
+#[test]
+fn polls_from_any_node() {
+    let mut sim = simulator(PARAMS_DEFAULT);
+    let offsets = send_messages(&mut sim, 3);
+    let poll_offsets: serde_json::Map<_, _> =
+        KEYS.iter().map(|key| (key.to_string(), json!(0))).collect();
+    for node_id in ["n0", "n1", "n2"] {
+        let reply = request(
+            &mut sim,
+            node_id,
+            json!({"type": "poll", "offsets": poll_offsets}),
+        );
+        assert_eq!(reply["type"], "poll_ok");
+        for (key, key_offsets) in KEYS.iter().zip(&offsets) {
+            let polled: Vec<_> = reply["msgs"][key]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry[0].as_u64().unwrap() as usize)
+                .collect();
+            assert_eq!(&polled, key_offsets, "{key} polled from {node_id}");
+        }
+    }
+}
+
+#[test]
+fn committed_offsets_are_listed_by_every_node() {
+    let mut sim = simulator(PARAMS_DEFAULT);
+    let offsets = send_messages(&mut sim, 2);
+    let commits: serde_json::Map<_, _> = KEYS
+        .iter()
+        .zip(&offsets)
+        .map(|(key, key_offsets)| (key.to_string(), json!(key_offsets[1])))
+        .collect();
+    let reply = request(
+        &mut sim,
+        "n1",
+        json!({"type": "commit_offsets", "offsets": commits}),
+    );
+    assert_eq!(reply, json!({"type": "commit_offsets_ok"}));
+    for node_id in ["n0", "n1", "n2"] {
+        let reply = request(
+            &mut sim,
+            node_id,
+            json!({"type": "list_committed_offsets", "keys": KEYS}),
+        );
+        assert_eq!(
+            reply,
+            json!({"type": "list_committed_offsets_ok", "offsets": commits})
+        );
+    }
+}
+
+#[test]
+fn committed_offsets_never_decrease() {
+    let mut sim = simulator(PARAMS_DEFAULT);
+    send_messages(&mut sim, 4);
+    // NOTE: concurrent commits of the same log race on `lin-kv`
+    let msg_ids: Vec<_> = [("n0", 3), ("n1", 1), ("n2", 2)]
+        .into_iter()
+        .map(|(node_id, offset)| {
+            let commit = json!({"type": "commit_offsets", "offsets": {"k1": offset}});
+            sim.send_client("c1", node_id, commit).unwrap()
+        })
+        .collect();
+    sim.run_until_idle().unwrap();
+    for msg_id in msg_ids {
+        let reply = sim.take_reply("c1", msg_id).unwrap().body.payload;
+        assert_eq!(reply, json!({"type": "commit_offsets_ok"}));
+    }
+    let lower = json!({"type": "commit_offsets", "offsets": {"k1": 0}});
+    request(&mut sim, "n1", lower);
+
+    let reply = request(
+        &mut sim,
+        "n2",
+        json!({"type": "list_committed_offsets", "keys": ["k1"]}),
+    );
+    assert_eq!(
+        reply,
+        json!({"type": "list_committed_offsets_ok", "offsets": {"k1": 3}})
+    );
+}
+
+#[test]
+fn long_poll_waits_for_messages() {
+    let mut sim = simulator(Params {
+        long_poll: Some(Duration::from_secs(1)),
+        ..PARAMS_DEFAULT
+    });
+    let owner = owner("k1");
+    let poll = json!({"type": "poll", "offsets": {"k1": 0}});
+    let msg_id = sim.send_client("c2", &owner, poll).unwrap();
+    sim.run_until_idle().unwrap();
+    assert!(sim.take_reply("c2", msg_id).is_none());
+
+    request(
+        &mut sim,
+        "n0",
+        json!({"type": "send", "key": "k1", "msg": 42}),
+    );
+    let reply = sim.take_reply("c2", msg_id).unwrap().body.payload;
+    assert_eq!(reply, json!({"type": "poll_ok", "msgs": {"k1": [[0, 42]]}}));
+
+    // times out without messages
+    let poll = json!({"type": "poll", "offsets": {"k1": 1}});
+    let msg_id = sim.send_client("c2", &owner, poll).unwrap();
+    sim.run_for(Duration::from_millis(1100)).unwrap();
+    let reply = sim.take_reply("c2", msg_id).unwrap().body.payload;
+    assert_eq!(reply, json!({"type": "poll_ok", "msgs": {"k1": []}}));
+}
+
+#[test]
+fn unanswered_requests_are_temporarily_unavailable() {
+    let mut sim = simulator(Params {
+        request_timeout: Duration::from_millis(100),
+        ..PARAMS_DEFAULT
+    });
+    // NOTE: round trips between nodes (or to lin-kv) take longer than the timeout
+    sim.set_latency(Duration::from_millis(80)..=Duration::from_millis(80));
+    let router = Router::<()>::new("n0".to_string(), &["n0", "n1", "n2"].map(String::from));
+    let other = ["n0", "n1", "n2"]
+        .into_iter()
+        .find(|node_id| *node_id != router.owner("k1"))
+        .unwrap();
+
+    for payload in [
+        json!({"type": "send", "key": "k1", "msg": 1}),
+        json!({"type": "poll", "offsets": {"k1": 0}}),
+        json!({"type": "commit_offsets", "offsets": {"k1": 0}}),
+        json!({"type": "list_committed_offsets", "keys": ["k1"]}),
+    ] {
+        let reply = request(&mut sim, other, payload.clone());
+        assert_eq!(reply["type"], "error", "{payload} => {reply}");
+        assert_eq!(reply["code"], 11, "{payload} => {reply}");
+    }
+
+    // late replies are ignored, and the node recovers once replies arrive in time
+    sim.set_latency(Duration::from_millis(10)..=Duration::from_millis(10));
+    let reply = request(
+        &mut sim,
+        other,
+        json!({"type": "send", "key": "k1", "msg": 2}),
+    );
+    assert_eq!(reply["type"], "send_ok", "{reply}");
+}
+
+/// Returns the offsets polled from `node_id` for the log `key`, from `offset`
+fn poll_offsets(
+    sim: &mut Simulator<Logs, Params>,
+    node_id: &str,
+    key: &str,
+    offset: usize,
+) -> Vec<usize> {
+    let reply = request(
+        sim,
+        node_id,
+        json!({"type": "poll", "offsets": {key: offset}}),
+    );
+    assert_eq!(reply["type"], "poll_ok", "{reply}");
+    reply["msgs"][key]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry[0].as_u64().unwrap() as usize)
+        .collect()
+}
+
+fn owner(key: &str) -> String {
+    Router::<()>::new("n0".to_string(), &["n0", "n1", "n2"].map(String::from))
+        .owner(key)
+        .to_string()
+}
+
+#[test]
+fn commit_truncates_the_owners_log() {
+    let mut sim = simulator(Params {
+        retention: Retention {
+            segment_size: 2,
+            max_messages: None,
+        },
+        ..PARAMS_DEFAULT
+    });
+    send_messages(&mut sim, 4);
+    let commit = json!({"type": "commit_offsets", "offsets": {"k1": 3}});
+    assert_eq!(
+        request(&mut sim, "n0", commit),
+        json!({"type": "commit_offsets_ok"})
+    );
+    // polls start from the segment holding the committed offset
+    for node_id in ["n0", "n1", "n2"] {
+        assert_eq!(poll_offsets(&mut sim, node_id, "k1", 0), [2, 3]);
+    }
+    assert_eq!(poll_offsets(&mut sim, "n0", "k2", 0), [0, 1, 2, 3]);
+}
+
+#[test]
+fn failed_commit_keeps_the_log() {
+    let mut sim = simulator(Params {
+        retention: Retention {
+            segment_size: 1,
+            max_messages: None,
+        },
+        request_timeout: Duration::from_millis(100),
+        ..PARAMS_DEFAULT
+    });
+    send_messages(&mut sim, 3);
+    let owner = owner("k1");
+
+    // NOTE: `lin-kv` replies arrive after the timeout
+    sim.set_latency(Duration::from_millis(80)..=Duration::from_millis(80));
+    let commit = json!({"type": "commit_offsets", "offsets": {"k1": 2}});
+    let reply = request(&mut sim, &owner, commit);
+    assert_eq!(reply["code"], 11, "{reply}");
+
+    sim.set_latency(Duration::from_millis(10)..=Duration::from_millis(10));
+    assert_eq!(poll_offsets(&mut sim, &owner, "k1", 0), [0, 1, 2]);
+    let list = json!({"type": "list_committed_offsets", "keys": ["k1"]});
+    assert_eq!(
+        request(&mut sim, &owner, list),
+        json!({"type": "list_committed_offsets_ok", "offsets": {}})
+    );
+}
+
+#[test]
+fn max_messages_evicts_the_oldest_messages() {
+    let mut sim = simulator(Params {
+        retention: Retention {
+            segment_size: 2,
+            max_messages: Some(4),
+        },
+        ..PARAMS_DEFAULT
+    });
+    let owner = owner("k1");
+    for msg in 0..7 {
+        let send = json!({"type": "send", "key": "k1", "msg": msg});
+        assert_eq!(request(&mut sim, &owner, send)["offset"], msg);
+    }
+    assert_eq!(poll_offsets(&mut sim, &owner, "k1", 0), [4, 5, 6]);
+    assert_eq!(poll_offsets(&mut sim, &owner, "k1", 5), [5, 6]);
+}
+
+#[test]
+fn polls_are_limited_per_key_and_in_total() {
+    let mut sim = simulator(Params {
+        poll_max_each: 3,
+        poll_max_total: 5,
+        ..PARAMS_DEFAULT
+    });
+    let offsets = send_messages(&mut sim, 5);
+
+    let mut next_offsets: BTreeMap<_, _> = KEYS.iter().map(|key| (key.to_string(), 0)).collect();
+    let mut counts = vec![];
+    for _ in 0..KEYS.len() * 2 {
+        let reply = request(
+            &mut sim,
+            "n1",
+            json!({"type": "poll", "offsets": next_offsets}),
+        );
+        assert_eq!(reply["type"], "poll_ok", "{reply}");
+        let key_counts: Vec<_> = KEYS
+            .iter()
+            .map(|key| {
+                let polled = reply["msgs"][key].as_array().map_or(&[][..], Vec::as_slice);
+                let next_offset = next_offsets.get_mut(*key).unwrap();
+                for entry in polled {
+                    assert_eq!(entry[0], json!(*next_offset), "{key} in {reply}");
+                    *next_offset += 1;
+                }
+                polled.len()
+            })
+            .collect();
+        if key_counts.iter().all(|&count| count == 0) {
+            break;
+        }
+        counts.push(key_counts);
+    }
+    // NOTE: the total limit takes logs in order of key
+    assert_eq!(
+        counts,
+        [[3, 2, 0, 0], [2, 3, 0, 0], [0, 0, 3, 2], [0, 0, 2, 3],]
+    );
+    for (key, key_offsets) in KEYS.iter().zip(&offsets) {
+        assert_eq!(next_offsets[*key], key_offsets.len(), "{key}");
+    }
+}
+
+/// `lin-kv` answering each request with `cas_ok`, whatever its type
+struct Confused {
+    msg_id: usize,
+}
+impl Service for Confused {
+    fn step_message(&mut self, message: RawMessage) -> Vec<RawMessage> {
+        vec![message.reply_with(Some(&mut self.msg_id), json!({"type": "cas_ok"}))]
+    }
+}
+
+#[test]
+fn unexpected_kv_replies_fail_the_request() {
+    let mut sim = Simulator::<Logs, _>::new(NODE_COUNT, PARAMS_DEFAULT, 5).unwrap();
+    sim.add_service(key_value::NODE_ID_LIN, Confused { msg_id: 0 });
+    send_messages(&mut sim, 1);
+    for payload in [
+        json!({"type": "commit_offsets", "offsets": {"k1": 0}}),
+        json!({"type": "list_committed_offsets", "keys": ["k1"]}),
+    ] {
+        let reply = request(&mut sim, "n0", payload.clone());
+        assert_eq!(reply["code"], 13, "{payload} => {reply}");
+    }
+    // the nodes keep serving
+    assert_eq!(poll_offsets(&mut sim, "n1", "k1", 0), [0]);
+}
@@ -1,6 +1,5 @@
-use anyhow::bail;
 use serde::{Deserialize, Serialize};
-use telephone_line::{main_loop, Message, Never, NeverSender, Node};
+use telephone_line::{main_loop, Message, Never, NeverSender, Node, Output};
 
 struct Unique {
     msg_id: usize,
@@ -8,14 +7,16 @@ struct Unique {
 }
 
 impl Node for Unique {
-    type Payload = Payload;
+    type Request = Request;
+    type Response = Never;
+    type Outbound = Reply;
     type Event = Never;
 
     fn from_init(
         init: telephone_line::Init,
         msg_id: usize,
         _start: (),
-        _event_tx: NeverSender<Self::Payload>,
+        _event_tx: NeverSender,
     ) -> Self
     where
         Self: Sized,
@@ -26,26 +27,33 @@ impl Node for Unique {
         }
     }
 
-    fn step_message(
+    fn step_request(
         &mut self,
-        message: Message<Self::Payload>,
-        output: &mut impl std::io::Write,
+        message: Message<Self::Request>,
+        output: &mut Output<'_, Self::Outbound>,
     ) -> anyhow::Result<()> {
-        let mut reply = message.reply(Some(&mut self.msg_id));
-        match reply.body.payload {
-            Payload::Generate => {
+        match message.body.payload {
+            Request::Generate => {
                 let id = format!("{}-{}", &self.node_id, self.msg_id);
-                reply.body.payload = Payload::GenerateOk { id };
-                reply.send(output)
+                message
+                    .reply_with(Some(&mut self.msg_id), Reply::GenerateOk { id })
+                    .send(output)
             }
-            Payload::GenerateOk { .. } => bail!("unexpected GenerateOk from {}", reply.dest),
         }
     }
 
+    fn step_response(
+        &mut self,
+        message: Message<Self::Response>,
+        _output: &mut Output<'_, Self::Outbound>,
+    ) -> anyhow::Result<()> {
+        match message.body.payload {}
+    }
+
     fn step_event(
         &mut self,
         event: Self::Event,
-        _output: &mut impl std::io::Write,
+        _output: &mut Output<'_, Self::Outbound>,
     ) -> anyhow::Result<()> {
         match event {}
     }
@@ -53,11 +61,49 @@ impl Node for Unique {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
-pub enum Payload {
+pub enum Request {
     Generate,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
     GenerateOk { id: String },
 }
 
 fn main() -> anyhow::Result<()> {
     main_loop::<Unique, _>(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashSet;
+    use telephone_line::simulator::Simulator;
+
+    #[test]
+    fn generates_unique_ids_across_nodes() {
+        let mut sim = Simulator::<Unique>::new(3, (), 0).unwrap();
+        let node_ids: Vec<_> = sim.node_ids().map(str::to_string).collect();
+        let msg_ids: Vec<_> = (0..30)
+            .map(|index| {
+                let node_id = &node_ids[index % node_ids.len()];
+                let client = format!("c{}", index % 2);
+                let msg_id = sim
+                    .send_client(&client, node_id, json!({"type": "generate"}))
+                    .unwrap();
+                (client, msg_id)
+            })
+            .collect();
+        sim.run_until_idle().unwrap();
+        let ids: HashSet<_> = msg_ids
+            .into_iter()
+            .map(|(client, msg_id)| {
+                let reply = sim.take_reply(&client, msg_id).unwrap();
+                assert_eq!(reply.body.payload["type"], "generate_ok");
+                reply.body.payload["id"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(ids.len(), 30);
+    }
+}
@@ -0,0 +1,510 @@
+//! Layered runtime configuration of a node's parameters
+//!
+//! Parameters start from a named preset, then are overridden field by field from (in order) a
+//! TOML or JSON config file, environment variables, and command-line flags:
+//!
+//! - `--preset NAME` picks the preset, defaulting to the first
+//! - `--config FILE` reads a `.toml` or `.json` file of fields
+//! - `<PREFIX>_FIELD=VALUE` sets a field, with `__` separating nested fields (e.g.
+//!   `BROADCAST_ADAPTIVE__BATCH_SIZE=30`), while `<PREFIX>_PRESET` and `<PREFIX>_CONFIG` pick
+//!   the preset and config file
+//! - `--field VALUE` (or `--field=VALUE`) sets a field, with `.` separating nested fields (e.g.
+//!   `--adaptive.batch-size 30`)
+//!
+//! Field names may use `-` in place of `_`. Values from the environment and flags are parsed as
+//! JSON if the field accepts that (so `null` unsets an optional field), or else taken as strings
+//! (so `--name 123` sets a string field).
+
+use anyhow::{anyhow, bail, Context};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, path::Path};
+
+/// Parameters of a node which may be configured at startup
+///
+/// Implementors should use `#[serde(deny_unknown_fields)]`, so that misspelled fields are
+/// reported rather than ignored.
+pub trait Config: Serialize + DeserializeOwned + Clone + 'static {
+    /// Prefix of the environment variables configuring the node, e.g. `BROADCAST`
+    const ENV_PREFIX: &'static str;
+    /// Named presets, the first being the default
+    const PRESETS: &'static [(&'static str, Self)];
+
+    /// Checks the combination of values, after all layers are applied
+    fn validate(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+const ARG_PRESET: &str = "--preset";
+const ARG_CONFIG: &str = "--config";
+const ENV_PRESET: &str = "PRESET";
+const ENV_CONFIG: &str = "CONFIG";
+
+/// Loads the configuration from the environment and the command-line arguments (excluding the
+/// executable name)
+pub fn load<C: Config>(args: impl IntoIterator<Item = String>) -> anyhow::Result<C> {
+    let env = std::env::vars().filter_map(|(name, value)| {
+        let field = name.strip_prefix(C::ENV_PREFIX)?.strip_prefix('_')?;
+        Some((field.to_string(), value))
+    });
+    load_from(args, env).map_err(|error| anyhow!("{error:#}\n\n{}", usage::<C>()))
+}
+
+/// Loads the configuration from `args` and `env` (variables with the prefix stripped)
+fn load_from<C: Config>(
+    args: impl IntoIterator<Item = String>,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> anyhow::Result<C> {
+    let Some((_, default)) = C::PRESETS.first() else {
+        bail!("no presets defined");
+    };
+
+    let mut preset = None;
+    let mut config_file = None;
+    let mut env_fields = vec![];
+    for (name, value) in env {
+        match name.as_str() {
+            ENV_PRESET => preset = Some(value),
+            ENV_CONFIG => config_file = Some(value),
+            _ => {
+                let path = name.split("__").map(normalize).collect::<Vec<_>>();
+                env_fields.push((path, value));
+            }
+        }
+    }
+
+    let mut arg_fields = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(name) = arg.strip_prefix("--") else {
+            bail!("unexpected argument {arg:?}");
+        };
+        let (name, value) = match name.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (name, None),
+        };
+        let Some(value) = value.or_else(|| args.next()) else {
+            bail!("missing value for argument {arg:?}");
+        };
+        match format!("--{name}").as_str() {
+            ARG_PRESET => preset = Some(value),
+            ARG_CONFIG => config_file = Some(value),
+            _ => arg_fields.push((name.split('.').map(normalize).collect::<Vec<_>>(), value)),
+        }
+    }
+
+    let params = match preset {
+        Some(name) => {
+            let Some((_, params)) = C::PRESETS.iter().find(|&(preset, _)| *preset == name) else {
+                bail!("unknown preset {name:?}");
+            };
+            params
+        }
+        None => default,
+    };
+    let mut value = serde_json::to_value(params).context("serialize preset")?;
+
+    if let Some(path) = config_file {
+        let file = read_config_file(Path::new(&path))?;
+        merge(&mut value, file);
+        check::<C>(&value).with_context(|| format!("invalid config file {path:?}"))?;
+    }
+    for (path, field_value) in env_fields {
+        let name = format!("{}_{}", C::ENV_PREFIX, path.join("__").to_uppercase());
+        set_parsed::<C>(&mut value, &path, &field_value)
+            .with_context(|| format!("invalid environment variable {name}={field_value:?}"))?;
+    }
+    for (path, field_value) in arg_fields {
+        let name = format!("--{}", path.join(".").replace('_', "-"));
+        set_parsed::<C>(&mut value, &path, &field_value)
+            .with_context(|| format!("invalid argument {name} {field_value:?}"))?;
+    }
+
+    let params = check::<C>(&value)?;
+    params.validate().context("invalid configuration")?;
+    Ok(params)
+}
+
+/// Deserializes the parameters, so each layer is checked as it is applied
+fn check<C: Config>(value: &Value) -> anyhow::Result<C> {
+    Ok(C::deserialize(value)?)
+}
+
+fn read_config_file(path: &Path) -> anyhow::Result<Value> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("read config file {}", path.display()))?;
+    let value = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str(&contents)
+            .with_context(|| format!("parse TOML config file {}", path.display()))?,
+        Some("json") => serde_json::from_str(&contents)
+            .with_context(|| format!("parse JSON config file {}", path.display()))?,
+        _ => bail!(
+            "unknown config file format {}, expected .toml or .json",
+            path.display()
+        ),
+    };
+    Ok(normalize_keys(value))
+}
+
+/// Converts a field name to its serialized form (lowercase, `_` separated)
+fn normalize(name: &str) -> String {
+    name.to_lowercase().replace('-', "_")
+}
+
+fn normalize_keys(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (normalize(&name), normalize_keys(value)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+/// Sets the nested field at `path` to a value given as text, parsed as JSON if the parameters
+/// then deserialize, or else as a string
+fn set_parsed<C: Config>(value: &mut Value, path: &[String], text: &str) -> anyhow::Result<()> {
+    let mut parsed = value.clone();
+    set(&mut parsed, path, parse_value(text));
+    let error = match check::<C>(&parsed) {
+        Ok(_) => {
+            *value = parsed;
+            return Ok(());
+        }
+        Err(error) => error,
+    };
+    // NOTE: e.g. `123` for a string field, but report the JSON error if the string fails too
+    set(value, path, Value::String(text.to_string()));
+    check::<C>(value).map(|_| ()).map_err(|_| error)
+}
+
+/// Parses a value given as text, as JSON if possible or else as a string
+fn parse_value(text: &str) -> Value {
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string()))
+}
+
+/// Merges the fields of `overrides` into `value`, recursively for nested fields
+fn merge(value: &mut Value, overrides: Value) {
+    match (value, overrides) {
+        (Value::Object(fields), Value::Object(overrides)) => {
+            for (name, override_value) in overrides {
+                match fields.get_mut(&name) {
+                    Some(field) => merge(field, override_value),
+                    None => {
+                        fields.insert(name, override_value);
+                    }
+                }
+            }
+        }
+        (value, overrides) => *value = overrides,
+    }
+}
+
+/// Sets the nested field at `path` to `field_value`, replacing non-objects along the way
+fn set(value: &mut Value, path: &[String], field_value: Value) {
+    let Some((name, rest)) = path.split_first() else {
+        *value = field_value;
+        return;
+    };
+    if !value.is_object() {
+        *value = Value::Object(Map::new());
+    }
+    let Value::Object(fields) = value else {
+        unreachable!("replaced with an object above");
+    };
+    set(
+        fields.entry(name.clone()).or_insert(Value::Null),
+        rest,
+        field_value,
+    );
+}
+
+/// Describes the arguments, presets and fields (with their values in the default preset)
+fn usage<C: Config>() -> String {
+    let executable_name = std::env::args().next();
+    let executable_name = executable_name.as_deref().unwrap_or("[binary]");
+    let presets: Vec<_> = C::PRESETS.iter().map(|&(name, _)| name).collect();
+    // NOTE: include fields nested in options which only some presets set
+    let mut fields = BTreeMap::new();
+    for (index, (_, preset)) in C::PRESETS.iter().enumerate() {
+        if let Ok(value) = serde_json::to_value(preset) {
+            describe_fields(&value, "", index == 0, &mut fields);
+        }
+    }
+    let fields: Vec<_> = fields
+        .into_iter()
+        .map(|(name, default)| match default {
+            Some(default) => format!("{name} (default {default})"),
+            None => name,
+        })
+        .collect();
+    format!(
+        "USAGE {executable_name} [{ARG_PRESET} PRESET] [{ARG_CONFIG} FILE] [--FIELD VALUE]..., \
+        where preset is one of {presets}, and fields (also set by environment variables {prefix}_FIELD) are {fields}",
+        presets = presets.join(", "),
+        prefix = C::ENV_PREFIX,
+        fields = fields.join(", "),
+    )
+}
+
+fn describe_fields(
+    value: &Value,
+    prefix: &str,
+    is_default: bool,
+    fields: &mut BTreeMap<String, Option<Value>>,
+) {
+    let Value::Object(object) = value else {
+        let default = fields.entry(prefix.to_string()).or_default();
+        if is_default {
+            *default = Some(value.clone());
+        }
+        return;
+    };
+    for (name, value) in object {
+        let name = name.replace('_', "-");
+        let name = if prefix.is_empty() {
+            name
+        } else {
+            format!("{prefix}.{name}")
+        };
+        describe_fields(value, &name, is_default, fields);
+    }
+}
+
+/// (De)serializes a [`Duration`](std::time::Duration) as a number of milliseconds, for use with
+/// `#[serde(with = "...")]`
+pub mod millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+
+    /// (De)serializes an optional [`Duration`] as a number of milliseconds or `null`
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use std::time::Duration;
+
+        pub fn serialize<S: Serializer>(
+            duration: &Option<Duration>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match duration {
+                Some(duration) => super::serialize(duration, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Duration>, D::Error> {
+            Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_millis))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Params {
+        count: u32,
+        name: String,
+        #[serde(rename = "timeout_ms", with = "millis::option")]
+        timeout: Option<Duration>,
+        nested: Nested,
+    }
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Nested {
+        inner_value: u32,
+        flag: bool,
+    }
+    const PARAMS_DEFAULT: Params = Params {
+        count: 1,
+        name: String::new(),
+        timeout: None,
+        nested: NESTED_DEFAULT,
+    };
+    const PARAMS_FAST: Params = Params {
+        count: 100,
+        name: String::new(),
+        timeout: Some(Duration::from_millis(5)),
+        nested: NESTED_DEFAULT,
+    };
+    const NESTED_DEFAULT: Nested = Nested {
+        inner_value: 10,
+        flag: false,
+    };
+    impl Config for Params {
+        const ENV_PREFIX: &'static str = "TEST";
+        const PRESETS: &'static [(&'static str, Self)] =
+            &[("default", PARAMS_DEFAULT), ("fast", PARAMS_FAST)];
+
+        fn validate(&self) -> anyhow::Result<()> {
+            if self.count == 0 {
+                bail!("count must be positive");
+            }
+            Ok(())
+        }
+    }
+
+    fn load(args: &[&str], env: &[(&str, &str)]) -> anyhow::Result<Params> {
+        let args = args.iter().map(|arg| arg.to_string());
+        let env = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        load_from(args, env)
+    }
+
+    fn load_error(args: &[&str], env: &[(&str, &str)]) -> String {
+        format!("{:#}", load(args, env).unwrap_err())
+    }
+
+    /// Writes `contents` to a temporary config file named `name`, returning its path
+    fn config_file(name: &str, contents: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("telephone-line-{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn defaults_to_first_preset() {
+        assert_eq!(load(&[], &[]).unwrap(), PARAMS_DEFAULT);
+    }
+
+    #[test]
+    fn selects_preset() {
+        assert_eq!(load(&["--preset", "fast"], &[]).unwrap(), PARAMS_FAST);
+        assert_eq!(load(&["--preset=fast"], &[]).unwrap(), PARAMS_FAST);
+        assert_eq!(load(&[], &[("PRESET", "fast")]).unwrap(), PARAMS_FAST);
+        // the flag wins over the environment
+        let params = load(&["--preset", "default"], &[("PRESET", "fast")]).unwrap();
+        assert_eq!(params, PARAMS_DEFAULT);
+        assert!(load_error(&["--preset", "slow"], &[]).contains("unknown preset \"slow\""));
+        // preset names are not flags, so cannot collide with fields
+        let error = load_error(&["--fast"], &[]);
+        assert!(
+            error.contains("missing value for argument \"--fast\""),
+            "{error}"
+        );
+        let error = load_error(&["--fast", "1"], &[]);
+        assert!(error.contains("unknown field `fast`"), "{error}");
+    }
+
+    #[test]
+    fn layers_apply_in_order() {
+        let file = config_file("layers.toml", "count = 2\nname = \"file\"\n");
+        let env = [("CONFIG", file.as_str()), ("COUNT", "3")];
+
+        let params = load(&["--preset", "fast"], &env).unwrap();
+        assert_eq!((params.count, params.name.as_str()), (3, "file"));
+        // the preset is the base layer
+        assert_eq!(params.timeout, PARAMS_FAST.timeout);
+
+        let params = load(&["--count", "4"], &env).unwrap();
+        assert_eq!(params.count, 4);
+
+        let params = load(&["--config", &file], &[]).unwrap();
+        assert_eq!(params.count, 2);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn sets_nested_fields() {
+        let params = load(&[], &[("NESTED__INNER_VALUE", "5")]).unwrap();
+        assert_eq!(params.nested.inner_value, 5);
+        let params = load(&["--nested.inner-value", "6"], &[]).unwrap();
+        assert_eq!(params.nested.inner_value, 6);
+        let params = load(&["--nested.flag=true", "--nested.inner_value=7"], &[]).unwrap();
+        assert_eq!(
+            params.nested,
+            Nested {
+                inner_value: 7,
+                flag: true
+            }
+        );
+
+        let file = config_file(
+            "nested.json",
+            r#"{"nested": {"inner-value": 8}, "timeout-ms": 30}"#,
+        );
+        let params = load(&["--config", &file], &[]).unwrap();
+        assert_eq!(params.nested.inner_value, 8);
+        assert!(!params.nested.flag, "other nested fields keep the preset");
+        assert_eq!(params.timeout, Some(Duration::from_millis(30)));
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn parses_values_as_json_or_strings() {
+        let args = ["--preset", "fast", "--timeout-ms", "null", "--name", "n1"];
+        let params = load(&args, &[]).unwrap();
+        assert_eq!(params.timeout, None);
+        assert_eq!(params.name, "n1");
+        let params = load(&["--name", "\"quoted\""], &[]).unwrap();
+        assert_eq!(params.name, "quoted");
+        // JSON which the field does not accept is taken as a string
+        let params = load(&["--name", "123"], &[]).unwrap();
+        assert_eq!(params.name, "123");
+        let params = load(&["--name", "null"], &[("NAME", "true")]).unwrap();
+        assert_eq!(params.name, "null");
+        assert_eq!(load(&[], &[("NAME", "true")]).unwrap().name, "true");
+        let error = load_error(&["--count", "many"], &[]);
+        assert!(
+            error.contains("invalid argument --count \"many\""),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let error = load_error(&["--bogus", "1"], &[]);
+        assert!(error.contains("invalid argument --bogus \"1\""), "{error}");
+        assert!(error.contains("unknown field `bogus`"), "{error}");
+
+        let error = load_error(&[], &[("NESTED__BOGUS", "1")]);
+        assert!(error.contains("TEST_NESTED__BOGUS"), "{error}");
+        assert!(error.contains("unknown field `bogus`"), "{error}");
+
+        let file = config_file("unknown.toml", "[nested]\nbogus = 1\n");
+        let error = load_error(&["--config", &file], &[]);
+        assert!(error.contains("invalid config file"), "{error}");
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed_arguments() {
+        assert!(load_error(&["count"], &[]).contains("unexpected argument \"count\""));
+        assert!(load_error(&["--count"], &[]).contains("missing value for argument \"--count\""));
+        let error = load_error(&["--config", "params.yaml"], &[]);
+        assert!(error.contains("read config file"), "{error}");
+    }
+
+    #[test]
+    fn validates_the_result() {
+        let error = load_error(&["--count", "0"], &[]);
+        assert!(
+            error.contains("invalid configuration: count must be positive"),
+            "{error}"
+        );
+        // NOTE: only the final combination is validated
+        assert_eq!(
+            load(&["--count", "0", "--count", "2"], &[]).unwrap().count,
+            2
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Maelstrom error codes, and the standard `error` reply payload
+
+use serde::{Deserialize, Serialize};
+
+/// Error code, from the maelstrom protocol error table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u32", into = "u32")]
+pub enum Code {
+    /// Request timed out, and may or may not have been applied
+    Timeout,
+    /// Destination node does not exist
+    NodeNotFound,
+    /// Request type is not supported by the node
+    NotSupported,
+    /// Node cannot currently serve the request (e.g. no leader), try again later
+    TemporarilyUnavailable,
+    /// Request was malformed, or is missing required fields
+    MalformedRequest,
+    /// Node crashed while serving the request, and may or may not have applied it
+    Crash,
+    /// Operation was aborted, and was not applied
+    Abort,
+    /// Key does not exist (e.g. for a key-value `read` or `cas`)
+    KeyDoesNotExist,
+    /// Key already exists, and the operation only creates new keys
+    KeyAlreadyExists,
+    /// Precondition was not met (e.g. a key-value `cas` found a different `from` value)
+    PreconditionFailed,
+    /// Transaction was aborted due to a conflict with another transaction
+    TxnConflict,
+    Unknown(u32),
+}
+impl Code {
+    const TIMEOUT: u32 = 0;
+    const NODE_NOT_FOUND: u32 = 1;
+    const NOT_SUPPORTED: u32 = 10;
+    const TEMPORARILY_UNAVAILABLE: u32 = 11;
+    const MALFORMED_REQUEST: u32 = 12;
+    const CRASH: u32 = 13;
+    const ABORT: u32 = 14;
+    const KEY_DOES_NOT_EXIST: u32 = 20;
+    const KEY_ALREADY_EXISTS: u32 = 21;
+    const PRECONDITION_FAILED: u32 = 22;
+    const TXN_CONFLICT: u32 = 30;
+
+    /// Returns `true` if the error guarantees the request was NOT applied
+    ///
+    /// Indefinite errors (`Timeout`, `Crash`, and unknown codes) leave the request's effect
+    /// unknown, so the request may or may not have been applied.
+    pub fn is_definite(self) -> bool {
+        match self {
+            Code::Timeout | Code::Crash | Code::Unknown(_) => false,
+            Code::NodeNotFound
+            | Code::NotSupported
+            | Code::TemporarilyUnavailable
+            | Code::MalformedRequest
+            | Code::Abort
+            | Code::KeyDoesNotExist
+            | Code::KeyAlreadyExists
+            | Code::PreconditionFailed
+            | Code::TxnConflict => true,
+        }
+    }
+}
+impl From<u32> for Code {
+    fn from(code: u32) -> Self {
+        match code {
+            Self::TIMEOUT => Self::Timeout,
+            Self::NODE_NOT_FOUND => Self::NodeNotFound,
+            Self::NOT_SUPPORTED => Self::NotSupported,
+            Self::TEMPORARILY_UNAVAILABLE => Self::TemporarilyUnavailable,
+            Self::MALFORMED_REQUEST => Self::MalformedRequest,
+            Self::CRASH => Self::Crash,
+            Self::ABORT => Self::Abort,
+            Self::KEY_DOES_NOT_EXIST => Self::KeyDoesNotExist,
+            Self::KEY_ALREADY_EXISTS => Self::KeyAlreadyExists,
+            Self::PRECONDITION_FAILED => Self::PreconditionFailed,
+            Self::TXN_CONFLICT => Self::TxnConflict,
+            unknown => Self::Unknown(unknown),
+        }
+    }
+}
+impl From<Code> for u32 {
+    fn from(code: Code) -> Self {
+        match code {
+            Code::Timeout => Code::TIMEOUT,
+            Code::NodeNotFound => Code::NODE_NOT_FOUND,
+            Code::NotSupported => Code::NOT_SUPPORTED,
+            Code::TemporarilyUnavailable => Code::TEMPORARILY_UNAVAILABLE,
+            Code::MalformedRequest => Code::MALFORMED_REQUEST,
+            Code::Crash => Code::CRASH,
+            Code::Abort => Code::ABORT,
+            Code::KeyDoesNotExist => Code::KEY_DOES_NOT_EXIST,
+            Code::KeyAlreadyExists => Code::KEY_ALREADY_EXISTS,
+            Code::PreconditionFailed => Code::PRECONDITION_FAILED,
+            Code::TxnConflict => Code::TXN_CONFLICT,
+            Code::Unknown(unknown) => unknown,
+        }
+    }
+}
+
+/// Error sent (or received) as an `error` reply
+///
+/// Returning this error (via [`anyhow`]) from [`Node::step_request`](crate::Node::step_request)
+/// sends it to the requester, rather than stopping the node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "error")]
+pub struct Error {
+    pub code: Code,
+    /// Description of the error, which the protocol allows to be left out (so may be empty)
+    #[serde(default)]
+    pub text: String,
+}
+impl Error {
+    pub fn new(code: Code, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+    pub fn not_supported(text: impl Into<String>) -> Self {
+        Self::new(Code::NotSupported, text)
+    }
+    pub fn malformed_request(text: impl Into<String>) -> Self {
+        Self::new(Code::MalformedRequest, text)
+    }
+    pub fn temporarily_unavailable(text: impl Into<String>) -> Self {
+        Self::new(Code::TemporarilyUnavailable, text)
+    }
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { code, text } = self;
+        write!(f, "error {code:?}: {text}")
+    }
+}
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const KNOWN: [(Code, u32); 11] = [
+        (Code::Timeout, 0),
+        (Code::NodeNotFound, 1),
+        (Code::NotSupported, 10),
+        (Code::TemporarilyUnavailable, 11),
+        (Code::MalformedRequest, 12),
+        (Code::Crash, 13),
+        (Code::Abort, 14),
+        (Code::KeyDoesNotExist, 20),
+        (Code::KeyAlreadyExists, 21),
+        (Code::PreconditionFailed, 22),
+        (Code::TxnConflict, 30),
+    ];
+
+    #[test]
+    fn codes_round_trip() {
+        for (code, number) in KNOWN {
+            assert_eq!(Code::from(number), code);
+            assert_eq!(u32::from(code), number);
+            assert_eq!(serde_json::to_value(code).unwrap(), json!(number));
+            assert_eq!(serde_json::from_value::<Code>(json!(number)).unwrap(), code);
+        }
+        for number in [2, 15, 1000] {
+            let code = Code::from(number);
+            assert_eq!(code, Code::Unknown(number));
+            assert_eq!(u32::from(code), number);
+            assert_eq!(serde_json::from_value::<Code>(json!(number)).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn only_timeout_crash_and_unknown_codes_are_indefinite() {
+        for (code, _) in KNOWN {
+            let indefinite = matches!(code, Code::Timeout | Code::Crash);
+            assert_eq!(code.is_definite(), !indefinite, "{code:?}");
+        }
+        assert!(!Code::Unknown(1000).is_definite());
+    }
+
+    #[test]
+    fn errors_use_the_error_reply_format() {
+        let error = Error::new(Code::KeyDoesNotExist, "not found");
+        let value = json!({"type": "error", "code": 20, "text": "not found"});
+        assert_eq!(serde_json::to_value(&error).unwrap(), value);
+        assert_eq!(serde_json::from_value::<Error>(value).unwrap(), error);
+
+        let error: Error = serde_json::from_value(json!({"type": "error", "code": 1000})).unwrap();
+        assert_eq!(error, Error::new(Code::Unknown(1000), ""));
+        assert!(serde_json::from_value::<Error>(json!({"type": "error"})).is_err());
+    }
+}
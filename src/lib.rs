@@ -1,12 +1,32 @@
 use anyhow::{bail, Context};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::io::BufRead;
+use std::{
+    io::{BufRead, Write},
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
 
 pub mod services {
     pub mod key_value;
 }
+#[cfg(feature = "tokio")]
+pub mod async_node;
+pub mod config;
+pub mod error;
+pub use error::{Code as ErrorCode, Error};
+pub mod protocol;
+pub use telephone_line_derive::Protocol;
+// NOTE: lets `derive(Protocol)` refer to `::telephone_line` within this crate
+extern crate self as telephone_line;
+pub mod range_set;
+pub mod record;
+pub mod routing;
+pub mod rpc;
+pub mod simulator;
+pub mod timer;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 #[must_use]
 pub struct Message<P> {
     pub src: String,
@@ -14,7 +34,7 @@ pub struct Message<P> {
     pub body: Body<P>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Body<P> {
     pub msg_id: Option<usize>,
     pub in_reply_to: Option<usize>,
@@ -22,6 +42,29 @@ pub struct Body<P> {
     pub payload: P,
 }
 
+/// Deserializes the payload with the sender as the [`protocol::current_peer`]
+impl<'de, P> Deserialize<'de> for Message<P>
+where
+    P: DeserializeOwned,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // NOTE: not a `RawMessage`, whose deserialization is this impl
+        #[derive(Deserialize)]
+        struct Fields {
+            src: String,
+            dest: String,
+            body: Body<serde_json::Value>,
+        }
+        let Fields { src, dest, body } = Fields::deserialize(deserializer)?;
+        Message { src, dest, body }
+            .deserialize_payload()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 /// Increments the supplied counter and returns the next message id
 pub fn next_msg_id(id: &mut usize) -> usize {
     let current = *id;
@@ -57,16 +100,132 @@ impl<P> Message<P> {
             },
         }
     }
-    pub fn send(self, output: &mut impl std::io::Write) -> anyhow::Result<()>
+    /// Returns a copy of the message without its payload
+    pub fn header(&self) -> Message<()> {
+        Message {
+            src: self.src.clone(),
+            dest: self.dest.clone(),
+            body: Body {
+                msg_id: self.body.msg_id,
+                in_reply_to: self.body.in_reply_to,
+                payload: (),
+            },
+        }
+    }
+    /// Builds a reply to this message with `payload`, as in [`Message::reply`]
+    pub fn reply_with<Q>(self, id: Option<&mut usize>, payload: Q) -> Message<Q> {
+        Message {
+            src: self.dest,
+            dest: self.src,
+            body: Body {
+                msg_id: id.map(next_msg_id),
+                in_reply_to: self.body.msg_id,
+                payload,
+            },
+        }
+    }
+    /// Builds an `error` reply to this message
+    pub fn error_reply(&self, error: Error) -> Message<Error> {
+        Message {
+            src: self.dest.clone(),
+            dest: self.src.clone(),
+            body: Body {
+                msg_id: None,
+                in_reply_to: self.body.msg_id,
+                payload: error,
+            },
+        }
+    }
+    /// Sends the message to `output`, e.g. a node's [`Output`]
+    pub fn send(self, output: &mut impl MessageSink<P>) -> anyhow::Result<()> {
+        output.send_message(self)
+    }
+}
+
+/// Destination of the messages sent by a node
+pub trait MessageSink<P> {
+    fn send_message(&mut self, message: Message<P>) -> anyhow::Result<()>;
+}
+/// Writes each message as a line of JSON
+impl<W, P> MessageSink<P> for W
+where
+    W: std::io::Write + ?Sized,
+    P: Serialize,
+{
+    fn send_message(&mut self, message: Message<P>) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut *self, &message).context("write message")?;
+        self.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// Output of a [`Node`], which only sends messages with payloads converting into its
+/// [`Node::Outbound`] payload
+pub struct Output<'a, O> {
+    writer: &'a mut dyn std::io::Write,
+    _outbound: PhantomData<fn(O)>,
+}
+impl<'a, O> Output<'a, O> {
+    fn new(writer: &'a mut dyn std::io::Write) -> Self {
+        Self {
+            writer,
+            _outbound: PhantomData,
+        }
+    }
+}
+impl<O, P> MessageSink<P> for Output<'_, O>
+where
+    O: Serialize,
+    P: Into<O>,
+{
+    fn send_message(&mut self, message: Message<P>) -> anyhow::Result<()> {
+        let Message { src, dest, body } = message;
+        let message = Message::<O> {
+            src,
+            dest,
+            body: Body {
+                msg_id: body.msg_id,
+                in_reply_to: body.in_reply_to,
+                payload: body.payload.into(),
+            },
+        };
+        self.writer.send_message(message)
+    }
+}
+
+/// Message with an untyped payload, as read from the input, routed by the [`simulator`] and
+/// recorded in traces (see [`record`])
+pub type RawMessage = Message<serde_json::Value>;
+
+impl RawMessage {
+    /// Deserializes the payload, with the sender as the [`protocol::current_peer`]
+    fn deserialize_payload<P>(self) -> Result<Message<P>, serde_json::Error>
     where
-        P: Serialize,
+        P: DeserializeOwned,
     {
-        serde_json::to_writer(&mut *output, &self).context("write message")?;
-        output.write_all(b"\n")?;
-        Ok(())
+        let Message { src, dest, body } = self;
+        let payload = protocol::with_peer(&src, || serde_json::from_value(body.payload))?;
+        Ok(Message {
+            src,
+            dest,
+            body: Body {
+                msg_id: body.msg_id,
+                in_reply_to: body.in_reply_to,
+                payload,
+            },
+        })
     }
 }
 
+/// Parses the messages written by a node, one per line
+pub(crate) fn parse_output(output: &[u8]) -> anyhow::Result<Vec<RawMessage>> {
+    let output = std::str::from_utf8(output).context("node output is not utf-8")?;
+    output
+        .lines()
+        .map(|line| Message::from_json(line).context("node output"))
+        .collect()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum InitPayload {
@@ -80,126 +239,452 @@ pub struct Init {
     pub node_ids: Vec<String>,
 }
 
-enum MessageEvent<P, U = Never> {
-    Message(Message<P>),
+enum MessageEvent<U = Never> {
+    /// Line read from the input, parsed only once recorded (see [`record`])
+    Line(String),
     Event(U),
 }
-// impl<P> MessageEvent<P, Never> {
-//     pub fn into_message(self) -> Message<P> {
-//         match self {
-//             MessageEvent::Message(message) => message,
-//             MessageEvent::Event(never) => match never {},
-//         }
-//     }
-// }
 
-pub type NeverSender<P> = EventSender<P, Never>;
+pub type NeverSender = EventSender<Never>;
+#[derive(Serialize, Deserialize)]
 pub enum Never {}
 
 struct Shutdown;
 
-pub struct EventSender<P, T>(
-    std::sync::mpsc::Sender<anyhow::Result<Result<MessageEvent<P, T>, Shutdown>>>,
-);
+type InputSender<T> = std::sync::mpsc::Sender<anyhow::Result<Result<MessageEvent<T>, Shutdown>>>;
+type InputReceiver<T> =
+    std::sync::mpsc::Receiver<anyhow::Result<Result<MessageEvent<T>, Shutdown>>>;
+
+/// Handle for delivering [`Node::Event`]s to the node, and for runtime-supplied time, timers
+/// and randomness (so that simulation can control all three)
+pub struct EventSender<T> {
+    tx: InputSender<T>,
+    timers: Arc<timer::Timers<T>>,
+    node_index: usize,
+    rng: Arc<Mutex<StdRng>>,
+}
 pub struct EventSendError;
-impl<P, T> EventSender<P, T> {
+impl<T> EventSender<T> {
+    fn new(
+        tx: InputSender<T>,
+        timers: Arc<timer::Timers<T>>,
+        node_index: usize,
+        rng: StdRng,
+    ) -> Self {
+        Self {
+            tx,
+            timers,
+            node_index,
+            rng: Arc::new(Mutex::new(rng)),
+        }
+    }
     pub fn send(&mut self, event: T) -> Result<(), EventSendError> {
-        self.0
+        self.tx
             .send(Ok(Ok(MessageEvent::Event(event))))
             .map_err(|_| EventSendError)
     }
+    /// Returns a random number generator for the node
+    ///
+    /// Under simulation, the generator is seeded from the simulation seed
+    pub fn rng(&self) -> StdRng {
+        let mut rng = self.rng.lock().expect("rng lock poisoned");
+        StdRng::seed_from_u64(rng.gen())
+    }
+    /// Returns the current time of the runtime's [`Clock`](timer::Clock)
+    pub fn now(&self) -> std::time::Duration {
+        self.timers.now()
+    }
+}
+impl<T> EventSender<T>
+where
+    T: Send + 'static,
+{
+    /// Sends the event returned by `event_fn` on every `interval`, until cancelled
+    ///
+    /// Periods outside [`Interval::MIN_PERIOD`](timer::Interval::MIN_PERIOD) to
+    /// [`Interval::MAX_PERIOD`](timer::Interval::MAX_PERIOD) are raised or lowered into that
+    /// range, for the first firing as for later ones.
+    pub fn every(
+        &self,
+        interval: impl Into<timer::Interval>,
+        event_fn: impl FnMut() -> T + Send + 'static,
+    ) -> timer::TimerHandle {
+        let interval = interval.into().clamped();
+        self.timers.schedule(
+            self.node_index,
+            interval.period,
+            Some(interval),
+            Box::new(event_fn),
+        )
+    }
+    /// Sends `event` once, after `delay` (unless cancelled)
+    pub fn after(&self, delay: std::time::Duration, event: T) -> timer::TimerHandle {
+        let mut event = Some(event);
+        let event_fn = move || event.take().expect("one-shot timer fired more than once");
+        self.timers
+            .schedule(self.node_index, delay, None, Box::new(event_fn))
+    }
+}
+
+/// Handling of errors returned from stepping a [`Node`] (or from an `AsyncNode`'s handlers)
+///
+/// Typed [`Error`]s returned from [`Node::step_request`] are always sent as an `error` reply,
+/// regardless of the policy. Replies are never answered: errors from [`Node::step_response`]
+/// are only logged (or abort the node, per the policy).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop the node, returning the error from [`main_loop`]
+    #[default]
+    Abort,
+    /// Log the error to stderr, and continue with the next input
+    Log,
+    /// Log the error to stderr, reply to the message (unless it is a reply) with a
+    /// [`Crash`](ErrorCode::Crash) error, and continue with the next input
+    Reply,
 }
 
 pub trait Node<S = ()> {
-    type Payload: Serialize + DeserializeOwned + Send + 'static;
-    type Event: Send + 'static;
-
-    fn from_init(
-        init: Init,
-        msg_id: usize,
-        start: S,
-        event_tx: EventSender<Self::Payload, Self::Event>,
-    ) -> Self
+    /// Payload of requests the node receives (messages without `in_reply_to`)
+    type Request: DeserializeOwned + Send + 'static;
+    /// Payload of replies the node receives to its own requests (messages with `in_reply_to`)
+    type Response: DeserializeOwned + Send + 'static;
+    /// Payload of replies and requests the node sends (the only payload its [`Output`] sends)
+    type Outbound: Serialize;
+    /// Event delivered to the node, serializable to record it in a trace (see [`record`])
+    type Event: Serialize + DeserializeOwned + Send + 'static;
+
+    fn from_init(init: Init, msg_id: usize, start: S, event_tx: EventSender<Self::Event>) -> Self
     where
         Self: Sized;
 
-    fn step_message(
+    fn step_request(
         &mut self,
-        message: Message<Self::Payload>,
-        output: &mut impl std::io::Write,
+        message: Message<Self::Request>,
+        output: &mut Output<'_, Self::Outbound>,
+    ) -> anyhow::Result<()>;
+
+    fn step_response(
+        &mut self,
+        message: Message<Self::Response>,
+        output: &mut Output<'_, Self::Outbound>,
     ) -> anyhow::Result<()>;
 
     fn step_event(
         &mut self,
         event: Self::Event,
-        output: &mut impl std::io::Write,
+        output: &mut Output<'_, Self::Outbound>,
     ) -> anyhow::Result<()>;
+
+    /// Returns how errors from stepping the node are handled
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::default()
+    }
+
+    /// Called once after the last input (e.g. to flush or persist state) before the node stops
+    fn shutdown(&mut self, _output: &mut Output<'_, Self::Outbound>) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
-fn parse_message<P>(line_result: Result<String, std::io::Error>) -> anyhow::Result<Message<P>>
+/// Steps the node with `message` as a [`Node::Request`], or as a [`Node::Response`] if it has
+/// `in_reply_to`
+///
+/// Rejects a message that is neither with a [`NotSupported`](ErrorCode::NotSupported) error,
+/// sends an `error` reply for any [`Error`] returned, and handles other errors per the node's
+/// [`ErrorPolicy`].
+fn step_message<N, S>(
+    node: &mut N,
+    message: RawMessage,
+    output: &mut impl std::io::Write,
+) -> anyhow::Result<()>
 where
-    P: DeserializeOwned,
+    N: Node<S>,
+{
+    let request = message.header();
+    let result = if request.body.in_reply_to.is_some() {
+        match message.deserialize_payload() {
+            Ok(message) => node.step_response(message, &mut Output::new(output)),
+            Err(err) => Err(reject_message(&request, "reply", err)),
+        }
+    } else {
+        match message.deserialize_payload() {
+            Ok(message) => node.step_request(message, &mut Output::new(output)),
+            Err(err) => Err(reject_message(&request, "request", err)),
+        }
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => handle_message_error(node.error_policy(), &request, err, output),
+    }
+}
+
+/// Steps the node with the message on the input `line`, handling a line which is not a message
+/// per the node's [`ErrorPolicy`]
+fn step_line<N, S>(node: &mut N, line: &str, output: &mut impl std::io::Write) -> anyhow::Result<()>
+where
+    N: Node<S>,
 {
-    let line = line_result.context("read from stdin")?;
-    let message = Message::from_json(&line).context("message")?;
-    Ok(message)
+    match Message::from_json(line).context("message") {
+        Ok(message) => step_message(node, message, output),
+        Err(err) => match node.error_policy() {
+            ErrorPolicy::Abort => Err(err),
+            ErrorPolicy::Log | ErrorPolicy::Reply => {
+                eprintln!("skipping input: {err:#}");
+                Ok(())
+            }
+        },
+    }
+}
+
+/// Sends an `error` reply to `request` if `err` is an [`Error`], otherwise handles it per
+/// `policy`
+///
+/// If `request` is itself a reply, the error is logged instead, as its sender awaits nothing.
+fn handle_message_error(
+    policy: ErrorPolicy,
+    request: &Message<()>,
+    err: anyhow::Error,
+    output: &mut impl std::io::Write,
+) -> anyhow::Result<()> {
+    let is_reply = request.body.in_reply_to.is_some();
+    let err = match err.downcast::<Error>() {
+        Ok(error) if is_reply => {
+            eprintln!("{error} (for reply from {})", request.src);
+            return Ok(());
+        }
+        Ok(error) => return send_error_reply(request, error, output),
+        Err(err) => err,
+    };
+    match policy {
+        ErrorPolicy::Abort => Err(err),
+        ErrorPolicy::Log => {
+            eprintln!("failed to handle message from {}: {err:#}", request.src);
+            Ok(())
+        }
+        ErrorPolicy::Reply => {
+            eprintln!("failed to handle message from {}: {err:#}", request.src);
+            if is_reply {
+                return Ok(());
+            }
+            let error = Error::new(ErrorCode::Crash, format!("{err:#}"));
+            send_error_reply(request, error, output)
+        }
+    }
+}
+
+/// Returns a [`NotSupported`](ErrorCode::NotSupported) error for an unexpected `kind` of message
+fn reject_message(request: &Message<()>, kind: &str, err: serde_json::Error) -> anyhow::Error {
+    let text = format!("unexpected {kind} from {}: {err}", request.src);
+    Error::not_supported(text).into()
+}
+
+/// Steps the node with `event`, handling errors per the node's [`ErrorPolicy`]
+fn step_event<N, S>(
+    node: &mut N,
+    event: N::Event,
+    output: &mut impl std::io::Write,
+) -> anyhow::Result<()>
+where
+    N: Node<S>,
+{
+    let Err(err) = node.step_event(event, &mut Output::new(output)) else {
+        return Ok(());
+    };
+    match node.error_policy() {
+        ErrorPolicy::Abort => Err(err),
+        ErrorPolicy::Log | ErrorPolicy::Reply => {
+            eprintln!("failed to handle event: {err:#}");
+            Ok(())
+        }
+    }
+}
+
+/// Calls [`Node::shutdown`] after the last input
+fn step_shutdown<N, S>(node: &mut N, output: &mut impl std::io::Write) -> anyhow::Result<()>
+where
+    N: Node<S>,
+{
+    node.shutdown(&mut Output::new(output))
+}
+
+/// Sends `error` in reply to `request`, or logs it if the request has no `msg_id`
+fn send_error_reply(
+    request: &Message<()>,
+    error: Error,
+    output: &mut impl std::io::Write,
+) -> anyhow::Result<()> {
+    if request.body.msg_id.is_some() {
+        request.error_reply(error).send(output)
+    } else {
+        eprintln!("{error} (for message from {} without msg_id)", request.src);
+        Ok(())
+    }
+}
+
+/// Sends the `init_ok` reply for the initial message, returning the `Init` contents
+fn reply_init(
+    init_message: Message<InitPayload>,
+    msg_id: &mut usize,
+    output: &mut impl std::io::Write,
+) -> anyhow::Result<Init> {
+    let mut reply = init_message.reply(Some(msg_id));
+    let InitPayload::Init(init) = std::mem::replace(&mut reply.body.payload, InitPayload::InitOk)
+    else {
+        bail!("initial message not Init")
+    };
+
+    reply.send(output).context("init_ok reply")?;
+    Ok(init)
 }
 
 pub fn main_loop<N, S>(start: S) -> anyhow::Result<()>
 where
     N: Node<S>,
 {
+    if let Some(trace_path) = std::env::var_os(record::REPLAY_ENV) {
+        return record::replay_file::<N, S>(trace_path.as_ref(), start);
+    }
+
     let mut stdout = std::io::stdout().lock();
     let mut msg_id = 0;
 
+    let seed = rand::random();
+    let (timers_rng, node_rng) = runtime_rngs(seed);
     let (input_tx, input_rx) = std::sync::mpsc::channel();
-    let event_tx = EventSender(input_tx.clone());
+    let timers = Arc::new(timer::Timers::new(
+        Arc::new(timer::SystemClock::default()),
+        timers_rng,
+    ));
+    let event_tx = EventSender::new(input_tx.clone(), Arc::clone(&timers), 0, node_rng);
+    let timer_tx = input_tx.clone();
+    let thread_timers = Arc::clone(&timers);
+    std::thread::spawn(move || {
+        thread_timers.run_thread(|_node_index, event| {
+            timer_tx.send(Ok(Ok(MessageEvent::Event(event)))).is_ok()
+        });
+    });
 
-    let mut node: N = {
+    let (mut node, mut recorder) = {
         let stdin = std::io::stdin().lock();
         let mut stdin = stdin.lines();
 
-        let init_message = stdin
+        let init_line = stdin
             .next()
             .expect("initial message not present on stdin")
             .context("read from stdin")?;
+        let at = timers.now();
         let init_message: Message<InitPayload> =
-            Message::from_json(&init_message).context("initial message")?;
-        let mut reply = init_message.reply(Some(&mut msg_id));
-        let InitPayload::Init(init) =
-            std::mem::replace(&mut reply.body.payload, InitPayload::InitOk) else {
-                bail!("initial message not Init")
-            };
+            Message::from_json(&init_line).context("initial message")?;
+        let mut output = vec![];
+        let init = reply_init(init_message, &mut msg_id, &mut output)?;
 
-        reply.send(&mut stdout).context("init_ok reply")?;
-
-        Node::from_init(init, msg_id, start, event_tx)
+        let mut recorder = record::Recorder::from_env(&init.node_id, seed)?;
+        if let Some(recorder) = &mut recorder {
+            recorder.record(&record::Entry::<()>::Input {
+                at,
+                line: init_line,
+            })?;
+        }
+        let node: N = Node::from_init(init, msg_id, start, event_tx);
+        write_output(&output, &mut stdout, &mut recorder)?;
+        (node, recorder)
     };
 
     std::thread::spawn(move || {
         let stdin = std::io::stdin().lock();
         let stdin = stdin.lines();
         for line_result in stdin {
-            let result = parse_message(line_result)
-                .map(MessageEvent::Message)
-                .map(Ok);
-            if input_tx.send(result).is_err() {
+            let line = match line_result.context("read from stdin") {
+                Ok(line) => line,
+                Err(err) => {
+                    let _ = input_tx.send(Err(err));
+                    break;
+                }
+            };
+            if input_tx.send(Ok(Ok(MessageEvent::Line(line)))).is_err() {
                 break;
             }
         }
-        println!("end of input");
+        eprintln!("end of input");
         let _ = input_tx.send(Ok(Err(Shutdown)));
     });
 
+    let result = step_inputs(&mut node, &input_rx, &timers, &mut stdout, &mut recorder);
+
+    let mut output = vec![];
+    if let Some(recorder) = &mut recorder {
+        let at = timers.now();
+        recorder.record(&record::Entry::<()>::Shutdown { at })?;
+    }
+    let shutdown_result = step_shutdown(&mut node, &mut output).context("shutdown");
+    write_output(&output, &mut stdout, &mut recorder)?;
+    stdout.flush().context("flush stdout")?;
+    result.and(shutdown_result)
+}
+
+/// Returns the random number generators for the timers and the node, derived from `seed`
+fn runtime_rngs(seed: u64) -> (StdRng, StdRng) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let timers_rng = StdRng::seed_from_u64(rng.gen());
+    let node_rng = StdRng::seed_from_u64(rng.gen());
+    (timers_rng, node_rng)
+}
+
+/// Steps the node with each input, until end of input or an aborting error
+fn step_inputs<N, S>(
+    node: &mut N,
+    input_rx: &InputReceiver<N::Event>,
+    timers: &timer::Timers<N::Event>,
+    stdout: &mut impl std::io::Write,
+    recorder: &mut Option<record::Recorder<impl std::io::Write>>,
+) -> anyhow::Result<()>
+where
+    N: Node<S>,
+{
     while let Ok(input_result) = input_rx.recv() {
-        match input_result? {
-            Ok(input) => match input {
-                MessageEvent::Message(message) => node.step_message(message, &mut stdout)?,
-                MessageEvent::Event(event) => node.step_event(event, &mut stdout)?,
+        let input = match input_result {
+            Ok(Ok(input)) => input,
+            Ok(Err(Shutdown)) => break,
+            Err(err) => match node.error_policy() {
+                ErrorPolicy::Abort => return Err(err),
+                ErrorPolicy::Log | ErrorPolicy::Reply => {
+                    eprintln!("skipping input: {err:#}");
+                    continue;
+                }
             },
-            Err(Shutdown) => break,
-        }
+        };
+        let at = timers.now();
+        let mut output = vec![];
+        let result = match input {
+            MessageEvent::Line(line) => {
+                if let Some(recorder) = recorder {
+                    let line = line.clone();
+                    recorder.record(&record::Entry::<()>::Input { at, line })?;
+                }
+                step_line(node, &line, &mut output)
+            }
+            MessageEvent::Event(event) => {
+                if let Some(recorder) = recorder {
+                    recorder.record(&record::Entry::Event { at, event: &event })?;
+                }
+                step_event(node, event, &mut output)
+            }
+        };
+        write_output(&output, stdout, recorder)?;
+        result?;
     }
+    Ok(())
+}
 
+/// Writes the node's `output` to stdout, and to the trace (if recording)
+fn write_output(
+    output: &[u8],
+    stdout: &mut impl std::io::Write,
+    recorder: &mut Option<record::Recorder<impl std::io::Write>>,
+) -> anyhow::Result<()> {
+    stdout.write_all(output).context("write to stdout")?;
+    if let Some(recorder) = recorder {
+        recorder.record_output(output)?;
+    }
     Ok(())
 }
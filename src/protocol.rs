@@ -0,0 +1,189 @@
+//! Support for [`derive(Protocol)`](crate::Protocol), composing several protocol enums into
+//! one wire payload
+
+use std::cell::RefCell;
+
+thread_local! {
+    static PEER: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Returns the sender of the message whose payload is being deserialized, if any
+pub fn current_peer() -> Option<String> {
+    PEER.with(|peer| peer.borrow().clone())
+}
+
+/// Calls `f` with `peer` as the [`current_peer`]
+pub(crate) fn with_peer<T>(peer: &str, f: impl FnOnce() -> T) -> T {
+    let previous = PEER.with(|current| current.replace(Some(peer.to_string())));
+    let _restore = RestorePeer(previous);
+    f()
+}
+
+/// Restores the previous [`current_peer`] when dropped, even if unwinding from a panic
+struct RestorePeer(Option<String>);
+impl Drop for RestorePeer {
+    fn drop(&mut self) {
+        PEER.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+#[doc(hidden)]
+pub mod __private {
+    pub use serde;
+    pub use serde_json;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Message, Protocol};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum KvRequest {
+        Read { key: String },
+    }
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum KvReply {
+        ReadOk { value: Value },
+    }
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum CountRequest {
+        Read,
+    }
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum CountReply {
+        ReadOk { value: i64 },
+    }
+
+    #[derive(Clone, Debug, PartialEq, Protocol)]
+    #[protocol(request = "Request", response = "Response")]
+    enum Payload {
+        #[protocol(request)]
+        Kv(KvRequest),
+        #[protocol(request)]
+        Count(CountRequest),
+        #[protocol(response, peer = "seq-kv")]
+        KvOk(KvReply),
+        #[protocol(response)]
+        CountOk(CountReply),
+    }
+
+    fn from_peer<P: serde::de::DeserializeOwned>(src: &str, payload: Value) -> P {
+        let message = json!({ "src": src, "dest": "n0", "body": payload });
+        Message::<P>::from_json(&message.to_string())
+            .unwrap()
+            .body
+            .payload
+    }
+
+    #[test]
+    fn colliding_tags_are_resolved_by_field_shape() {
+        let payload: Payload =
+            serde_json::from_value(json!({ "type": "read", "key": "k" })).unwrap();
+        assert_eq!(payload, Payload::Kv(KvRequest::Read { key: "k".into() }));
+
+        let payload: Payload = serde_json::from_value(json!({ "type": "read" })).unwrap();
+        assert_eq!(payload, Payload::Count(CountRequest::Read));
+    }
+
+    #[test]
+    fn colliding_tags_are_resolved_by_peer() {
+        let read_ok = json!({ "type": "read_ok", "value": 3 });
+
+        let payload: Payload = from_peer("seq-kv", read_ok.clone());
+        assert_eq!(payload, Payload::KvOk(KvReply::ReadOk { value: json!(3) }));
+        let payload: Payload = from_peer("n1", read_ok.clone());
+        assert_eq!(payload, Payload::CountOk(CountReply::ReadOk { value: 3 }));
+        // NOTE: without a sender, variants are tried in declaration order
+        let payload: Payload = serde_json::from_value(read_ok).unwrap();
+        assert_eq!(payload, Payload::KvOk(KvReply::ReadOk { value: json!(3) }));
+    }
+
+    #[test]
+    fn peer_variants_only_match_their_peers() {
+        let read_ok = json!({ "type": "read_ok", "value": "text" });
+        let message = json!({ "src": "n1", "dest": "n0", "body": read_ok });
+
+        let err = Message::<Payload>::from_json(&message.to_string()).unwrap_err();
+        let err = format!("{err:#}");
+        assert!(err.contains("no Payload variant matches"), "{err}");
+        // NOTE: the error of each variant tried is reported, except for `KvOk` (for `seq-kv`)
+        assert!(err.contains("Kv: unknown variant `read_ok`"), "{err}");
+        assert!(err.contains("CountOk: invalid type: string"), "{err}");
+        assert!(!err.contains("KvOk:"), "{err}");
+    }
+
+    #[test]
+    fn peer_is_restored_after_a_panic() {
+        let result = std::panic::catch_unwind(|| {
+            super::with_peer("n1", || {
+                assert_eq!(super::current_peer().as_deref(), Some("n1"));
+                panic!("deserialization panicked");
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(super::current_peer(), None);
+
+        super::with_peer("n1", || {
+            super::with_peer("n2", || ());
+            assert_eq!(super::current_peer().as_deref(), Some("n1"));
+        });
+        assert_eq!(super::current_peer(), None);
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        let payloads = [
+            Payload::Kv(KvRequest::Read { key: "k".into() }),
+            Payload::Count(CountRequest::Read),
+            Payload::KvOk(KvReply::ReadOk { value: json!([1]) }),
+            Payload::CountOk(CountReply::ReadOk { value: -1 }),
+        ];
+        for payload in payloads {
+            let value = serde_json::to_value(&payload).unwrap();
+            let src = match payload {
+                Payload::KvOk(_) => "seq-kv",
+                _ => "n1",
+            };
+            assert_eq!(from_peer::<Payload>(src, value), payload);
+        }
+    }
+
+    #[test]
+    fn payloads_convert_to_and_from_wrapped_types() {
+        let payload = Payload::from(CountRequest::Read);
+        assert_eq!(payload, Payload::Count(CountRequest::Read));
+        assert_eq!(
+            CountRequest::try_from(payload.clone()),
+            Ok(CountRequest::Read)
+        );
+        assert_eq!(KvRequest::try_from(payload.clone()), Err(payload));
+    }
+
+    #[test]
+    fn payloads_split_into_requests_and_responses() {
+        let request: Request = serde_json::from_value(json!({ "type": "read" })).unwrap();
+        assert!(matches!(request, Request::Count(CountRequest::Read)));
+        assert!(
+            serde_json::from_value::<Request>(json!({ "type": "read_ok", "value": 1 })).is_err()
+        );
+
+        let response: Response = from_peer("seq-kv", json!({ "type": "read_ok", "value": 1 }));
+        assert!(matches!(response, Response::KvOk(_)));
+        let response: Response = from_peer("n1", json!({ "type": "read_ok", "value": 1 }));
+        assert!(matches!(response, Response::CountOk(_)));
+
+        let payload = Payload::from(request);
+        assert_eq!(payload, Payload::Count(CountRequest::Read));
+        assert!(matches!(
+            Request::try_from(payload.clone()),
+            Ok(Request::Count(_))
+        ));
+        assert_eq!(Response::try_from(payload.clone()).unwrap_err(), payload);
+    }
+}
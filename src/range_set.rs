@@ -0,0 +1,338 @@
+//! Set of integers stored as disjoint ranges, compact for dense runs of values
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, ops::RangeInclusive};
+
+/// Set of `usize` values, stored (and serialized) as disjoint, non-adjacent ranges
+///
+/// Serializes as a JSON array of single values and inclusive `[start, end]` ranges, e.g.
+/// `[1, [3, 7], 10]` for the set `{1, 3, 4, 5, 6, 7, 10}`.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct RangeSet {
+    /// End (inclusive) of each range, by start
+    ranges: BTreeMap<usize, usize>,
+}
+impl RangeSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts `value`, returning true if it was not already present
+    pub fn insert(&mut self, value: usize) -> bool {
+        let inserted = !self.contains(value);
+        self.insert_range(value..=value);
+        inserted
+    }
+
+    /// Inserts all values in `range`
+    pub fn insert_range(&mut self, range: RangeInclusive<usize>) {
+        let (mut start, mut end) = range.into_inner();
+        if start > end {
+            return;
+        }
+        // merge with the range before, if it overlaps or is adjacent
+        if let Some((&before_start, &before_end)) = self.ranges.range(..start).next_back() {
+            if before_end.saturating_add(1) >= start {
+                start = before_start;
+                end = end.max(before_end);
+            }
+        }
+        // merge with the ranges after, which overlap or are adjacent
+        let after: Vec<_> = self
+            .ranges
+            .range(start..=end.saturating_add(1))
+            .map(|(&after_start, &after_end)| (after_start, after_end))
+            .collect();
+        for (after_start, after_end) in after {
+            self.ranges.remove(&after_start);
+            end = end.max(after_end);
+        }
+        self.ranges.insert(start, end);
+    }
+
+    pub fn contains(&self, value: usize) -> bool {
+        self.ranges
+            .range(..=value)
+            .next_back()
+            .is_some_and(|(_, &end)| value <= end)
+    }
+
+    /// Inserts all values of `other`
+    pub fn union(&mut self, other: &Self) {
+        for range in other.ranges() {
+            self.insert_range(range);
+        }
+    }
+
+    /// Returns the values in `self` which are not in `other`
+    pub fn difference(&self, other: &Self) -> Self {
+        let mut difference = Self::new();
+        for (&start, &end) in &self.ranges {
+            // first value of the range not yet compared to `other`
+            let mut next = Some(start);
+            let overlapping = other
+                .ranges
+                .range(..start)
+                .next_back()
+                .into_iter()
+                .chain(other.ranges.range(start..=end));
+            for (&other_start, &other_end) in overlapping {
+                let Some(current) = next else {
+                    break;
+                };
+                if other_end < current {
+                    continue;
+                }
+                if other_start > current {
+                    difference.insert_range(current..=other_start - 1);
+                }
+                next = other_end.checked_add(1);
+            }
+            if let Some(current) = next.filter(|&current| current <= end) {
+                difference.insert_range(current..=end);
+            }
+        }
+        difference
+    }
+
+    /// Returns the values in both `self` and `other`
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.difference(&self.difference(other))
+    }
+
+    /// Returns the number of values, saturating at `usize::MAX` (e.g. for `0..=usize::MAX`)
+    pub fn len(&self) -> usize {
+        self.ranges.iter().fold(0, |len: usize, (start, end)| {
+            len.saturating_add((end - start).saturating_add(1))
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Iterates over the disjoint ranges, in ascending order
+    pub fn ranges(&self) -> impl Iterator<Item = RangeInclusive<usize>> + '_ {
+        self.ranges.iter().map(|(&start, &end)| start..=end)
+    }
+
+    /// Iterates over the values, in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.ranges().flatten()
+    }
+}
+impl std::fmt::Debug for RangeSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.ranges()).finish()
+    }
+}
+impl FromIterator<usize> for RangeSet {
+    fn from_iter<T: IntoIterator<Item = usize>>(iter: T) -> Self {
+        let mut set = Self::new();
+        set.extend(iter);
+        set
+    }
+}
+impl Extend<usize> for RangeSet {
+    fn extend<T: IntoIterator<Item = usize>>(&mut self, iter: T) {
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
+
+/// Serialized element of a [`RangeSet`]
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Element {
+    Value(usize),
+    Range([usize; 2]),
+}
+impl Serialize for RangeSet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_seq(self.ranges.iter().map(|(&start, &end)| {
+            if start == end {
+                Element::Value(start)
+            } else {
+                Element::Range([start, end])
+            }
+        }))
+    }
+}
+impl<'de> Deserialize<'de> for RangeSet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let mut set = Self::new();
+        for element in Vec::<Element>::deserialize(deserializer)? {
+            match element {
+                Element::Value(value) => set.insert_range(value..=value),
+                Element::Range([start, end]) if start <= end => set.insert_range(start..=end),
+                Element::Range([start, end]) => {
+                    return Err(serde::de::Error::custom(format!(
+                        "range start {start} is after its end {end}"
+                    )))
+                }
+            }
+        }
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use serde_json::json;
+    use std::collections::BTreeSet;
+
+    fn ranges(set: &RangeSet) -> Vec<RangeInclusive<usize>> {
+        set.ranges().collect()
+    }
+
+    fn from_ranges(ranges: impl IntoIterator<Item = RangeInclusive<usize>>) -> RangeSet {
+        let mut set = RangeSet::new();
+        for range in ranges {
+            set.insert_range(range);
+        }
+        set
+    }
+
+    #[test]
+    fn insert_merges_adjacent_values() {
+        let mut set = RangeSet::new();
+        assert!(set.insert(5));
+        assert!(set.insert(7));
+        assert!(!set.insert(5));
+        assert_eq!(ranges(&set), [5..=5, 7..=7]);
+        assert!(set.insert(6));
+        assert_eq!(ranges(&set), [5..=7]);
+        assert!(set.insert(4));
+        assert!(set.insert(8));
+        assert_eq!(ranges(&set), [4..=8]);
+        assert_eq!(set.len(), 5);
+        assert!(set.contains(4) && set.contains(8) && !set.contains(3) && !set.contains(9));
+    }
+
+    #[test]
+    fn insert_range_merges_adjacent_and_overlapping_ranges() {
+        let set = from_ranges([1..=3, 4..=5]);
+        assert_eq!(ranges(&set), [1..=5]);
+
+        let set = from_ranges([1..=5, 3..=8]);
+        assert_eq!(ranges(&set), [1..=8]);
+
+        let set = from_ranges([3..=8, 1..=5]);
+        assert_eq!(ranges(&set), [1..=8]);
+
+        let set = from_ranges([1..=2, 4..=5, 7..=8, 10..=10, 2..=7]);
+        assert_eq!(ranges(&set), [1..=8, 10..=10]);
+
+        let set = from_ranges([0..=10, 3..=4]);
+        assert_eq!(ranges(&set), [0..=10]);
+    }
+
+    #[test]
+    fn empty_ranges_are_ignored() {
+        #[allow(clippy::reversed_empty_ranges)]
+        let set = from_ranges([5..=4, 1..=0]);
+        assert!(set.is_empty());
+        assert_eq!(set.len(), 0);
+
+        let full = from_ranges([1..=3]);
+        assert_eq!(full.difference(&RangeSet::new()), full);
+        assert!(RangeSet::new().difference(&full).is_empty());
+        assert!(full.difference(&full).is_empty());
+    }
+
+    #[test]
+    fn difference_removes_overlaps() {
+        let set = from_ranges([0..=10, 20..=30]);
+        let other = from_ranges([0..=0, 3..=4, 9..=22, 25..=25, 30..=40]);
+        assert_eq!(
+            ranges(&set.difference(&other)),
+            [1..=2, 5..=8, 23..=24, 26..=29]
+        );
+        // a range of `other` starting before the compared range
+        let other = from_ranges([0..=5]);
+        assert_eq!(ranges(&from_ranges([3..=8]).difference(&other)), [6..=8]);
+    }
+
+    #[test]
+    fn handles_usize_max_bounds() {
+        let mut set = RangeSet::new();
+        set.insert(usize::MAX);
+        set.insert(usize::MAX - 1);
+        assert_eq!(ranges(&set), [usize::MAX - 1..=usize::MAX]);
+        assert!(set.contains(usize::MAX));
+
+        let full = from_ranges([0..=usize::MAX]);
+        assert_eq!(full.len(), usize::MAX);
+        assert!(full.contains(0) && full.contains(usize::MAX));
+        let ends = from_ranges([0..=0, usize::MAX..=usize::MAX]);
+        assert_eq!(ranges(&full.difference(&ends)), [1..=usize::MAX - 1]);
+        assert!(ends.difference(&full).is_empty());
+        assert_eq!(
+            ranges(&full.difference(&from_ranges([5..=usize::MAX]))),
+            [0..=4]
+        );
+    }
+
+    #[test]
+    fn matches_btree_set() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..100 {
+            let mut sets = [RangeSet::new(), RangeSet::new()];
+            let mut expected = [BTreeSet::new(), BTreeSet::new()];
+            for (set, expected) in sets.iter_mut().zip(&mut expected) {
+                for _ in 0..rng.gen_range(0..10) {
+                    let start = rng.gen_range(0..60);
+                    let end = start + rng.gen_range(0..6);
+                    set.insert_range(start..=end);
+                    expected.extend(start..=end);
+                }
+            }
+            let [a, b] = &sets;
+            let difference: BTreeSet<_> = expected[0].difference(&expected[1]).copied().collect();
+            assert_eq!(a.difference(b).iter().collect::<BTreeSet<_>>(), difference);
+            assert_eq!(a.difference(b).len(), difference.len());
+
+            let intersection: BTreeSet<_> =
+                expected[0].intersection(&expected[1]).copied().collect();
+            assert_eq!(
+                a.intersection(b).iter().collect::<BTreeSet<_>>(),
+                intersection
+            );
+
+            let mut union = a.clone();
+            union.union(b);
+            let expected_union: BTreeSet<_> = expected[0].union(&expected[1]).copied().collect();
+            assert_eq!(union.iter().collect::<BTreeSet<_>>(), expected_union);
+            // NOTE: ranges stay disjoint and non-adjacent
+            assert!(ranges(&union)
+                .windows(2)
+                .all(|pair| pair[0].end() + 1 < *pair[1].start()));
+        }
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let set: RangeSet = [1, 3, 4, 5, 6, 7, 10, usize::MAX].into_iter().collect();
+        let value = serde_json::to_value(&set).unwrap();
+        assert_eq!(value, json!([1, [3, 7], 10, usize::MAX]));
+        assert_eq!(serde_json::from_value::<RangeSet>(value).unwrap(), set);
+        assert_eq!(serde_json::to_value(RangeSet::new()).unwrap(), json!([]));
+
+        // unordered, overlapping and adjacent elements are merged
+        let set: RangeSet = serde_json::from_value(json!([[5, 8], 1, [2, 6], 9])).unwrap();
+        assert_eq!(ranges(&set), [1..=9]);
+
+        assert!(serde_json::from_value::<RangeSet>(json!([[3, 1]])).is_err());
+        assert!(serde_json::from_value::<RangeSet>(json!([[1, 2, 3]])).is_err());
+        assert!(serde_json::from_value::<RangeSet>(json!([-1])).is_err());
+    }
+}
@@ -0,0 +1,477 @@
+//! Recording of a node's inputs, events and outputs to a trace, and replay of a trace into a
+//! fresh node for post-mortem debugging
+//!
+//! Set [`RECORD_DIR_ENV`] to have [`main_loop`](crate::main_loop) write each node's trace to
+//! `<dir>/<node_id>.jsonl`, then run the same binary with [`REPLAY_ENV`] set to a trace file to
+//! replay it (instead of reading stdin), reporting any outputs that differ from the recording.
+
+use crate::{timer, EventSender, InitPayload, Message, Node, RawMessage};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Environment variable naming the directory to write traces into
+pub const RECORD_DIR_ENV: &str = "TELEPHONE_LINE_RECORD";
+/// Environment variable naming a trace file to replay
+pub const REPLAY_ENV: &str = "TELEPHONE_LINE_REPLAY";
+
+/// Entry in a trace, stored one per line
+///
+/// Each `input`, `event` and `shutdown` entry is followed by the `output` entries the node
+/// wrote while handling it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry<T> {
+    /// Seed of the node's random number generators (the first entry)
+    Start { seed: u64 },
+    /// Line read from stdin
+    Input { at: Duration, line: String },
+    /// Event delivered to the node
+    Event { at: Duration, event: T },
+    /// End of input
+    Shutdown { at: Duration },
+    /// Message written by the node
+    Output { message: RawMessage },
+    /// Line written by the node which is not a message, kept verbatim
+    UnparsedOutput { line: String },
+}
+
+/// Line written by the node, as recorded in (or replayed from) a trace
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    Message(RawMessage),
+    /// Line which is not a message
+    Unparsed(String),
+}
+impl Output {
+    /// Parses each line of the node's `output`, keeping lines which are not messages verbatim
+    fn parse_all(output: &[u8]) -> Vec<Self> {
+        String::from_utf8_lossy(output)
+            .lines()
+            .map(|line| match Message::from_json(line) {
+                Ok(message) => Self::Message(message),
+                Err(_) => Self::Unparsed(line.to_string()),
+            })
+            .collect()
+    }
+}
+impl std::fmt::Display for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Message(message) => {
+                let message = serde_json::to_string(message).map_err(|_| std::fmt::Error)?;
+                write!(f, "{message}")
+            }
+            Self::Unparsed(line) => write!(f, "{line} (not a message)"),
+        }
+    }
+}
+
+/// Writer of a trace file
+pub(crate) struct Recorder<W = BufWriter<File>> {
+    path: PathBuf,
+    file: W,
+}
+impl Recorder {
+    /// Creates the trace for `node_id`, if [`RECORD_DIR_ENV`] is set
+    pub fn from_env(node_id: &str, seed: u64) -> anyhow::Result<Option<Self>> {
+        let Some(dir) = std::env::var_os(RECORD_DIR_ENV) else {
+            return Ok(None);
+        };
+        let path = Path::new(&dir).join(format!("{node_id}.jsonl"));
+        let file = File::create(&path).with_context(|| format!("create trace {path:?}"))?;
+        Recorder::new(path, BufWriter::new(file), seed).map(Some)
+    }
+}
+impl<W> Recorder<W>
+where
+    W: Write,
+{
+    /// Creates a trace written to `file` (named `path` in errors), starting with `seed`
+    fn new(path: PathBuf, file: W, seed: u64) -> anyhow::Result<Self> {
+        let mut recorder = Self { path, file };
+        recorder.record(&Entry::<()>::Start { seed })?;
+        Ok(recorder)
+    }
+    pub fn record<T>(&mut self, entry: &Entry<T>) -> anyhow::Result<()>
+    where
+        T: Serialize,
+    {
+        serde_json::to_writer(&mut self.file, entry)
+            .with_context(|| format!("write trace {:?}", self.path))?;
+        self.file.write_all(b"\n")?;
+        Ok(())
+    }
+    /// Records each message in `output` (written by the node), then flushes the trace
+    ///
+    /// Lines which are not messages are logged, and recorded verbatim.
+    pub fn record_output(&mut self, output: &[u8]) -> anyhow::Result<()> {
+        for output in Output::parse_all(output) {
+            match output {
+                Output::Message(message) => self.record(&Entry::<()>::Output { message })?,
+                Output::Unparsed(line) => {
+                    eprintln!("recording node output which is not a message: {line:?}");
+                    self.record(&Entry::<()>::UnparsedOutput { line })?;
+                }
+            }
+        }
+        self.file
+            .flush()
+            .with_context(|| format!("flush trace {:?}", self.path))
+    }
+}
+
+/// Difference between the recorded and replayed outputs for one trace entry
+#[derive(Debug)]
+pub struct Divergence {
+    /// Line number (starting at 1) of the input or event in the trace
+    pub line: usize,
+    pub at: Duration,
+    pub expected: Vec<Output>,
+    pub actual: Vec<Output>,
+}
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            line,
+            at,
+            expected,
+            actual,
+        } = self;
+        writeln!(f, "outputs differ for trace line {line} (at {at:?})")?;
+        for output in expected {
+            writeln!(f, "- {output}")?;
+        }
+        for output in actual {
+            writeln!(f, "+ {output}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Replays the trace file at `path` into a fresh node, failing if any outputs differ
+pub(crate) fn replay_file<N, S>(path: &Path, start: S) -> anyhow::Result<()>
+where
+    N: Node<S>,
+{
+    let file = File::open(path).with_context(|| format!("open trace {path:?}"))?;
+    let divergences = replay::<N, S>(std::io::BufReader::new(file), start)
+        .with_context(|| format!("replay trace {path:?}"))?;
+    for divergence in &divergences {
+        eprint!("{divergence}");
+    }
+    match divergences.len() {
+        0 => {
+            eprintln!("replay of {path:?} matches the recording");
+            Ok(())
+        }
+        count => bail!("replay of {path:?} diverged from the recording {count} times"),
+    }
+}
+
+/// Replays a recorded trace into a fresh node, returning where its outputs differ from the
+/// recording
+///
+/// The node sees the recorded times and seed, and recorded events are delivered in place of
+/// its own timers and sent events.
+pub fn replay<N, S>(trace: impl BufRead, start: S) -> anyhow::Result<Vec<Divergence>>
+where
+    N: Node<S>,
+{
+    let mut entries = trace
+        .lines()
+        .enumerate()
+        .map(|(index, line)| -> anyhow::Result<_> {
+            let line_number = index + 1;
+            let line = line.context("read trace")?;
+            let entry = serde_json::from_str::<Entry<N::Event>>(&line)
+                .with_context(|| format!("trace line {line_number}"))?;
+            Ok((line_number, entry))
+        })
+        .peekable();
+
+    let Some((_, Entry::Start { seed })) = entries.next().transpose()? else {
+        bail!("trace does not begin with a start entry");
+    };
+    let Some((init_line_number, Entry::Input { at, line })) = entries.next().transpose()? else {
+        bail!("trace is missing the initial message");
+    };
+
+    let clock = Arc::new(timer::ManualClock::default());
+    clock.set(at);
+    let (timers_rng, node_rng) = crate::runtime_rngs(seed);
+    let timers = Arc::new(timer::Timers::new(clock.clone(), timers_rng));
+    // NOTE: events sent by the node are ignored, as the recorded events are replayed instead
+    let (input_tx, _input_rx) = std::sync::mpsc::channel();
+    let event_tx = EventSender::new(input_tx, timers, 0, node_rng);
+
+    let mut msg_id = 0;
+    let mut output = vec![];
+    let init_message: Message<InitPayload> =
+        Message::from_json(&line).context("initial message")?;
+    let init = crate::reply_init(init_message, &mut msg_id, &mut output)?;
+    let mut node = N::from_init(init, msg_id, start, event_tx);
+
+    let mut divergences = vec![];
+    let mut step = (init_line_number, at, output);
+    loop {
+        let (line_number, at, output) = step;
+        let mut expected = vec![];
+        while let Some(Ok((_, Entry::Output { .. } | Entry::UnparsedOutput { .. }))) =
+            entries.peek()
+        {
+            match entries.next() {
+                Some(Ok((_, Entry::Output { message }))) => expected.push(Output::Message(message)),
+                Some(Ok((_, Entry::UnparsedOutput { line }))) => {
+                    expected.push(Output::Unparsed(line));
+                }
+                _ => unreachable!("peeked output missing"),
+            }
+        }
+        let actual = Output::parse_all(&output);
+        if actual != expected {
+            divergences.push(Divergence {
+                line: line_number,
+                at,
+                expected,
+                actual,
+            });
+        }
+
+        let Some((line_number, entry)) = entries.next().transpose()? else {
+            break;
+        };
+        let mut output = vec![];
+        let at = match entry {
+            Entry::Input { at, line } => {
+                clock.set(at);
+                crate::step_line(&mut node, &line, &mut output)
+                    .with_context(|| format!("replay trace line {line_number}"))?;
+                at
+            }
+            Entry::Event { at, event } => {
+                clock.set(at);
+                crate::step_event(&mut node, event, &mut output)
+                    .with_context(|| format!("replay trace line {line_number}"))?;
+                at
+            }
+            Entry::Shutdown { at } => {
+                clock.set(at);
+                crate::step_shutdown(&mut node, &mut output)
+                    .with_context(|| format!("replay trace line {line_number}"))?;
+                at
+            }
+            Entry::Start { .. } | Entry::Output { .. } | Entry::UnparsedOutput { .. } => {
+                bail!("unexpected entry on trace line {line_number}")
+            }
+        };
+        step = (line_number, at, output);
+    }
+    Ok(divergences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorPolicy, MessageEvent, Never, Shutdown};
+    use serde_json::json;
+
+    const SEED: u64 = 3;
+
+    /// Node which replies to each `add` with the running total (skipping input which is not a
+    /// message)
+    struct Adder {
+        msg_id: usize,
+        total: u64,
+    }
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        Add { delta: u64 },
+    }
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Outbound {
+        AddOk { total: u64 },
+    }
+    impl Node for Adder {
+        type Request = Request;
+        type Response = Never;
+        type Outbound = Outbound;
+        type Event = Never;
+
+        fn from_init(_init: crate::Init, msg_id: usize, _start: (), _: EventSender<Never>) -> Self {
+            Self { msg_id, total: 0 }
+        }
+
+        fn step_request(
+            &mut self,
+            message: Message<Request>,
+            output: &mut crate::Output<'_, Self::Outbound>,
+        ) -> anyhow::Result<()> {
+            match message.body.payload {
+                Request::Add { delta } => {
+                    self.total += delta;
+                    let total = self.total;
+                    message
+                        .reply_with(Some(&mut self.msg_id), Outbound::AddOk { total })
+                        .send(output)
+                }
+            }
+        }
+
+        fn step_response(
+            &mut self,
+            message: Message<Never>,
+            _output: &mut crate::Output<'_, Self::Outbound>,
+        ) -> anyhow::Result<()> {
+            match message.body.payload {}
+        }
+
+        fn step_event(
+            &mut self,
+            event: Never,
+            _output: &mut crate::Output<'_, Self::Outbound>,
+        ) -> anyhow::Result<()> {
+            match event {}
+        }
+
+        fn error_policy(&self) -> ErrorPolicy {
+            ErrorPolicy::Log
+        }
+    }
+
+    /// Returns the input line of a request from a client, with `payload`
+    fn request(msg_id: usize, payload: serde_json::Value) -> String {
+        let mut body = payload;
+        body["msg_id"] = json!(msg_id);
+        json!({"src": "c1", "dest": "n0", "body": body}).to_string()
+    }
+
+    /// Records a session of an [`Adder`] reading the input `lines`, as in
+    /// [`main_loop`](crate::main_loop)
+    fn record_session(lines: Vec<String>) -> Vec<u8> {
+        let clock = Arc::new(timer::ManualClock::default());
+        let (timers_rng, node_rng) = crate::runtime_rngs(SEED);
+        let timers = Arc::new(timer::Timers::new(clock.clone(), timers_rng));
+        let (input_tx, input_rx) = std::sync::mpsc::channel();
+        let event_tx = EventSender::new(input_tx.clone(), Arc::clone(&timers), 0, node_rng);
+        let mut recorder = Some(Recorder::new(PathBuf::from("trace"), vec![], SEED).unwrap());
+
+        let init_line = json!({"src": "c0", "dest": "n0", "body": {
+            "msg_id": 0, "type": "init", "node_id": "n0", "node_ids": ["n0"]
+        }})
+        .to_string();
+        let init_message = Message::from_json(&init_line).unwrap();
+        if let Some(recorder) = &mut recorder {
+            let at = timers.now();
+            recorder
+                .record(&Entry::<()>::Input {
+                    at,
+                    line: init_line,
+                })
+                .unwrap();
+        }
+        let mut msg_id = 0;
+        let mut output = vec![];
+        let init = crate::reply_init(init_message, &mut msg_id, &mut output).unwrap();
+        let mut node = Adder::from_init(init, msg_id, (), event_tx);
+        crate::write_output(&output, &mut std::io::sink(), &mut recorder).unwrap();
+
+        for line in lines {
+            input_tx.send(Ok(Ok(MessageEvent::Line(line)))).unwrap();
+        }
+        input_tx.send(Ok(Err(Shutdown))).unwrap();
+        crate::step_inputs(
+            &mut node,
+            &input_rx,
+            &timers,
+            &mut std::io::sink(),
+            &mut recorder,
+        )
+        .unwrap();
+
+        let mut recorder = recorder.unwrap();
+        let at = timers.now();
+        recorder.record(&Entry::<()>::Shutdown { at }).unwrap();
+        recorder.file
+    }
+
+    #[test]
+    fn replays_recorded_session() {
+        let trace = record_session(vec![
+            request(1, json!({"type": "add", "delta": 2})),
+            "not a message".to_string(),
+            request(2, json!({"type": "add", "delta": 3})),
+        ]);
+        let trace = String::from_utf8(trace).unwrap();
+        let lines: Vec<_> = trace.lines().collect();
+        let types: Vec<_> = lines
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["type"].clone())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                "start", "input", "output", "input", "output", "input", "input", "output",
+                "shutdown"
+            ]
+        );
+
+        // NOTE: input which is not a message is recorded, so replayed as well
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(lines[5]).unwrap()["line"],
+            "not a message"
+        );
+        let divergences = replay::<Adder, ()>(trace.as_bytes(), ()).unwrap();
+        assert!(divergences.is_empty(), "{divergences:?}");
+
+        // the last reply differs from the one recorded
+        let changed = trace.replacen(r#""total":5"#, r#""total":6"#, 1);
+        assert_ne!(changed, trace);
+        let divergences = replay::<Adder, ()>(changed.as_bytes(), ()).unwrap();
+        assert_eq!(divergences.len(), 1, "{divergences:?}");
+        let Divergence {
+            line,
+            expected,
+            actual,
+            ..
+        } = &divergences[0];
+        assert_eq!(*line, 7);
+        let [Output::Message(expected)] = &expected[..] else {
+            panic!("unexpected recorded output {expected:?}");
+        };
+        let [Output::Message(actual)] = &actual[..] else {
+            panic!("unexpected replayed output {actual:?}");
+        };
+        assert_eq!(expected.body.payload, json!({"type": "add_ok", "total": 6}));
+        assert_eq!(actual.body.payload, json!({"type": "add_ok", "total": 5}));
+    }
+
+    #[test]
+    fn output_which_is_not_a_message_is_recorded_verbatim() {
+        let mut recorder = Recorder::new(PathBuf::from("trace"), vec![], SEED).unwrap();
+        let message = json!({"src": "n0", "dest": "c1", "body": {"type": "add_ok", "total": 1}});
+        let output = format!("{message}\ngarbled\n");
+        recorder.record_output(output.as_bytes()).unwrap();
+
+        let trace = String::from_utf8(recorder.file).unwrap();
+        let entries: Vec<_> = trace
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1]["type"], "output");
+        assert_eq!(entries[1]["message"]["body"]["total"], 1);
+        assert_eq!(
+            entries[2],
+            json!({"type": "unparsed_output", "line": "garbled"})
+        );
+    }
+}
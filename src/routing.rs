@@ -0,0 +1,243 @@
+//! Assignment of keys to owning nodes by consistent hashing, and forwarding of requests to
+//! the owner
+
+use crate::{rpc::Rpc, Body, Message, MessageSink};
+use std::collections::BTreeMap;
+
+/// Points on the ring for each node, to spread keys evenly
+const VIRTUAL_NODES: usize = 64;
+
+/// Consistent hash ring over the nodes of a cluster
+///
+/// Every node builds the same ring from [`Init::node_ids`](crate::Init::node_ids) (in any
+/// order), so all nodes agree on the owner of each key without coordination.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    /// Sorted by hash
+    points: Vec<(u64, String)>,
+}
+impl HashRing {
+    pub fn new<'a>(node_ids: impl IntoIterator<Item = &'a String>) -> Self {
+        let mut points: Vec<_> = node_ids
+            .into_iter()
+            .flat_map(|node_id| {
+                (0..VIRTUAL_NODES)
+                    .map(move |index| (hash(&format!("{node_id}#{index}")), node_id.clone()))
+            })
+            .collect();
+        points.sort();
+        Self { points }
+    }
+
+    /// Returns the node owning `key`, the first point on the ring at or after its hash
+    ///
+    /// # Panics
+    /// Panics if the ring has no nodes
+    pub fn owner(&self, key: &str) -> &str {
+        let key_hash = hash(key);
+        let index = self.points.partition_point(|(point, _)| *point < key_hash);
+        let (_, node_id) = &self.points[index % self.points.len()];
+        node_id
+    }
+}
+
+/// FNV-1a, as the hash must be identical on every node (and build), then mixed
+///
+/// FNV-1a alone clusters similar short keys (e.g. `n1#0`, `n1#1`) on the ring, leaving some
+/// nodes with few or no keys, so its result is mixed with the SplitMix64 finalizer.
+fn hash(key: &str) -> u64 {
+    let fnv = key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    mix(fnv)
+}
+
+/// SplitMix64 finalizer, spreading every input bit over the whole output
+fn mix(mut hash: u64) -> u64 {
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Routes requests for keys to their owning node, remembering a context `C` for each
+/// forwarded request until the owner replies
+pub struct Router<C> {
+    node_id: String,
+    ring: HashRing,
+    forwarded: Rpc<C>,
+}
+impl<C> Router<C> {
+    /// Creates a router for `node_id` (the current node) in the cluster `node_ids`
+    pub fn new(node_id: String, node_ids: &[String]) -> Self {
+        Self {
+            node_id,
+            ring: HashRing::new(node_ids),
+            forwarded: Rpc::new(),
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    pub fn owner(&self, key: &str) -> &str {
+        self.ring.owner(key)
+    }
+
+    /// Returns true if the current node owns `key`
+    pub fn is_local(&self, key: &str) -> bool {
+        self.owner(key) == self.node_id
+    }
+
+    /// Groups `items` by the owner of the key returned by `key_fn`
+    pub fn partition<T>(
+        &self,
+        items: impl IntoIterator<Item = T>,
+        key_fn: impl Fn(&T) -> &str,
+    ) -> BTreeMap<String, Vec<T>> {
+        let mut partitions = BTreeMap::<_, Vec<_>>::new();
+        for item in items {
+            let owner = self.owner(key_fn(&item)).to_string();
+            partitions.entry(owner).or_default().push(item);
+        }
+        partitions
+    }
+
+    /// Sends the request `payload` to `owner`, returning its `msg_id`
+    ///
+    /// The owner may be unreachable, so schedule a timeout for the request, and use
+    /// [`Router::take_timed_out`] when it elapses.
+    pub fn forward<P>(
+        &mut self,
+        owner: &str,
+        payload: P,
+        context: C,
+        msg_id: &mut usize,
+        output: &mut impl MessageSink<P>,
+    ) -> anyhow::Result<usize> {
+        let message = Message {
+            src: self.node_id.clone(),
+            dest: owner.to_string(),
+            body: Body {
+                msg_id: Some(crate::next_msg_id(msg_id)),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        self.forwarded.send(message, context, output)
+    }
+
+    /// Removes the forwarded request that `reply` responds to, returning its context
+    ///
+    /// Returns `None` if `reply` is not the reply to a forwarded request
+    pub fn take_reply<P>(&mut self, reply: &Message<P>) -> Option<C> {
+        let (_msg_id, context) = self.forwarded.take_reply(reply)?;
+        Some(context)
+    }
+
+    /// Forgets the forwarded request `msg_id` after its timeout elapsed, returning its context
+    ///
+    /// Returns `None` if the owner already replied
+    pub fn take_timed_out(&mut self, msg_id: usize) -> Option<C> {
+        self.forwarded.take_timed_out(msg_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_ids(count: usize) -> Vec<String> {
+        (0..count).map(|index| format!("n{index}")).collect()
+    }
+
+    fn keys() -> impl Iterator<Item = String> {
+        (0..3000).map(|index| format!("k{index}"))
+    }
+
+    #[test]
+    fn hash_is_mixed_fnv_1a() {
+        // NOTE: FNV-1a test vectors, so the ring is stable across builds
+        assert_eq!(hash(""), mix(0xcbf2_9ce4_8422_2325));
+        assert_eq!(hash("a"), mix(0xaf63_dc4c_8601_ec8c));
+        assert_eq!(hash("foobar"), mix(0x8594_4171_f739_67e8));
+        assert_eq!(mix(0), 0);
+        assert_eq!(mix(1), 0x5692_161d_100b_05e5);
+    }
+
+    #[test]
+    fn owners_agree_regardless_of_node_order() {
+        let ids = node_ids(5);
+        let mut reversed = ids.clone();
+        reversed.reverse();
+        let (ring, reversed_ring) = (HashRing::new(&ids), HashRing::new(&reversed));
+        for key in keys() {
+            assert_eq!(ring.owner(&key), reversed_ring.owner(&key), "{key}");
+        }
+    }
+
+    #[test]
+    fn keys_spread_over_all_nodes() {
+        for node_count in [3, 25] {
+            let ids = node_ids(node_count);
+            let ring = HashRing::new(&ids);
+            let mut counts = BTreeMap::<_, usize>::new();
+            for key in keys() {
+                *counts.entry(ring.owner(&key).to_string()).or_default() += 1;
+            }
+            assert_eq!(counts.len(), node_count);
+            let fair = keys().count() / node_count;
+            for (node_id, count) in counts {
+                assert!(
+                    (fair / 2..=fair * 3 / 2).contains(&count),
+                    "{node_id} owns {count} of {node_count} nodes' keys"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn adding_a_node_only_moves_keys_to_it() {
+        let ring = HashRing::new(&node_ids(3));
+        let grown = HashRing::new(&node_ids(4));
+        let mut moved = 0;
+        for key in keys() {
+            if ring.owner(&key) != grown.owner(&key) {
+                assert_eq!(grown.owner(&key), "n3", "{key}");
+                moved += 1;
+            }
+        }
+        assert!(moved > 0);
+    }
+
+    #[test]
+    fn single_node_owns_every_key() {
+        let router = Router::<()>::new("n0".to_string(), &node_ids(1));
+        assert!(keys().all(|key| router.is_local(&key)));
+    }
+
+    #[test]
+    fn partition_groups_items_by_owner() {
+        let ids = node_ids(3);
+        let router = Router::<()>::new("n1".to_string(), &ids);
+        let items: Vec<_> = keys().take(100).enumerate().collect();
+
+        let partitions = router.partition(items.clone(), |(_, key)| key);
+        let mut partitioned = 0;
+        for (owner, owned) in &partitions {
+            assert!(ids.contains(owner));
+            for (_, key) in owned {
+                assert_eq!(router.owner(key), owner);
+                assert_eq!(router.is_local(key), owner == "n1");
+            }
+            // NOTE: items keep their relative order within a partition
+            assert!(owned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            partitioned += owned.len();
+        }
+        assert_eq!(partitioned, items.len());
+    }
+}
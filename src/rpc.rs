@@ -0,0 +1,167 @@
+//! Correlation of outgoing requests with their replies, by `in_reply_to`
+
+use crate::{EventSender, Message, MessageSink};
+use anyhow::bail;
+use std::{collections::HashMap, time::Duration};
+
+/// Outstanding requests sent by a node, each with a context `C` to resume from when the reply
+/// (or timeout) arrives
+///
+/// The context may be plain data describing the request, or a boxed continuation closure.
+pub struct Rpc<C> {
+    pending: HashMap<usize, C>,
+}
+
+impl<C> Default for Rpc<C> {
+    fn default() -> Self {
+        Self {
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<C> Rpc<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends the request `message`, remembering `context` until the reply arrives
+    ///
+    /// Returns the `msg_id` of the request, which must be present
+    pub fn send<P>(
+        &mut self,
+        message: Message<P>,
+        context: C,
+        output: &mut impl MessageSink<P>,
+    ) -> anyhow::Result<usize> {
+        let Some(msg_id) = message.body.msg_id else {
+            bail!("request to {} is missing msg_id", message.dest);
+        };
+        // NOTE: check before sending, so a duplicate neither goes out nor replaces the context
+        // of the pending request
+        if self.pending.contains_key(&msg_id) {
+            bail!("duplicate request msg_id {msg_id}");
+        }
+        message.send(output)?;
+        self.pending.insert(msg_id, context);
+        Ok(msg_id)
+    }
+
+    /// Sends the request `message` as in [`Rpc::send`], and after `timeout` sends the event
+    /// returned by `timeout_fn` (called with the request `msg_id`)
+    ///
+    /// Upon receiving the timeout event, use [`Rpc::take_timed_out`] to find the request
+    /// context (if the reply has not arrived already)
+    pub fn send_with_timeout<P, T>(
+        &mut self,
+        message: Message<P>,
+        context: C,
+        output: &mut impl MessageSink<P>,
+        timeout: Duration,
+        event_tx: &EventSender<T>,
+        timeout_fn: impl FnOnce(usize) -> T,
+    ) -> anyhow::Result<usize>
+    where
+        T: Send + 'static,
+    {
+        let msg_id = self.send(message, context, output)?;
+        event_tx.after(timeout, timeout_fn(msg_id));
+        Ok(msg_id)
+    }
+
+    /// Removes the pending request that `reply` responds to, returning its `msg_id` and context
+    ///
+    /// Returns `None` if `reply` is not a reply, or if the request is no longer pending
+    pub fn take_reply<P>(&mut self, reply: &Message<P>) -> Option<(usize, C)> {
+        let msg_id = reply.body.in_reply_to?;
+        let context = self.pending.remove(&msg_id)?;
+        Some((msg_id, context))
+    }
+
+    /// Returns the context of the pending request `msg_id`, if its reply has not arrived
+    pub fn get(&self, msg_id: usize) -> Option<&C> {
+        self.pending.get(&msg_id)
+    }
+
+    /// Removes the pending request `msg_id` after its timeout elapsed, returning its context
+    ///
+    /// Returns `None` if the reply already arrived
+    pub fn take_timed_out(&mut self, msg_id: usize) -> Option<C> {
+        self.pending.remove(&msg_id)
+    }
+
+    /// Returns the number of requests still awaiting a reply
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Body, RawMessage};
+    use serde_json::json;
+
+    fn request(msg_id: Option<usize>) -> RawMessage {
+        Message {
+            src: "n0".to_string(),
+            dest: "n1".to_string(),
+            body: Body {
+                msg_id,
+                in_reply_to: None,
+                payload: json!({"type": "request"}),
+            },
+        }
+    }
+
+    fn reply(in_reply_to: usize) -> RawMessage {
+        Message {
+            src: "n1".to_string(),
+            dest: "n0".to_string(),
+            body: Body {
+                msg_id: None,
+                in_reply_to: Some(in_reply_to),
+                payload: json!({"type": "reply"}),
+            },
+        }
+    }
+
+    #[test]
+    fn replies_complete_their_requests() {
+        let mut rpc = Rpc::new();
+        let mut output = vec![];
+        rpc.send(request(Some(1)), "first", &mut output).unwrap();
+        rpc.send(request(Some(2)), "second", &mut output).unwrap();
+        assert_eq!(rpc.len(), 2);
+
+        assert_eq!(rpc.take_reply(&reply(2)), Some((2, "second")));
+        assert_eq!(rpc.take_reply(&reply(2)), None);
+        assert_eq!(rpc.take_timed_out(1), Some("first"));
+        assert_eq!(rpc.take_reply(&reply(1)), None);
+        assert!(rpc.is_empty());
+    }
+
+    #[test]
+    fn duplicate_msg_id_is_rejected_before_sending() {
+        let mut rpc = Rpc::new();
+        let mut output = vec![];
+        rpc.send(request(Some(1)), "first", &mut output).unwrap();
+        let sent = output.len();
+
+        assert!(rpc.send(request(Some(1)), "second", &mut output).is_err());
+        assert_eq!(output.len(), sent, "duplicate request was sent");
+        assert_eq!(rpc.get(1), Some(&"first"));
+    }
+
+    #[test]
+    fn request_without_msg_id_is_rejected() {
+        let mut rpc = Rpc::<()>::new();
+        let mut output = vec![];
+        assert!(rpc.send(request(None), (), &mut output).is_err());
+        assert!(output.is_empty());
+    }
+}
@@ -1,69 +1,415 @@
-//! Common interface for maelstrom's `seq-kv` and `lin-kv` endpoints
+//! Common interface for maelstrom's `seq-kv`, `lin-kv` and `lww-kv` endpoints
+
+pub use crate::error::{Code as ErrorCode, Error};
+use crate::{rpc::Rpc, Body, Message, MessageSink};
+use serde::{Deserialize, Serialize};
 
 /// Node id of the `seq-kv` provided by maelstrom test harness
 pub const NODE_ID_SEQ: &str = "seq-kv";
 pub const NODE_ID_LIN: &str = "lin-kv";
+pub const NODE_ID_LWW: &str = "lww-kv";
 
-/// Variant of `Send` for `seq-kv`
-pub enum SendSeq {
-    Read { key: String },
-    Write { key: String, value: usize },
-    Cas { key: String, from: usize, to: usize },
+/// Key-value service provided by the maelstrom test harness
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    /// Sequentially-consistent store
+    Seq,
+    /// Linearizable store
+    Lin,
+    /// Last-write-wins store (eventually consistent)
+    Lww,
+}
+impl Service {
+    pub fn node_id(self) -> &'static str {
+        match self {
+            Service::Seq => NODE_ID_SEQ,
+            Service::Lin => NODE_ID_LIN,
+            Service::Lww => NODE_ID_LWW,
+        }
+    }
 }
-/// Variant of `Send` for `lin-kv`
-pub enum SendLin {
+
+/// Request payload, common to all key-value services
+///
+/// Values may be any JSON, e.g. a [`serde_json::Value`] or a type that (de)serializes as a
+/// list or map. Note that `Cas` compares the JSON representation of `from`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request<V = serde_json::Value> {
     Read {
         key: String,
     },
     Write {
         key: String,
-        value: usize,
+        value: V,
     },
     Cas {
         key: String,
-        from: usize,
-        to: usize,
+        from: V,
+        to: V,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
         create_if_not_exists: bool,
     },
 }
-pub enum Receive {
-    ReadOk { value: usize },
+/// Reply payload from a key-value service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Receive<V = serde_json::Value> {
+    ReadOk {
+        value: V,
+    },
     WriteOk,
     CasOk,
-    Error { code: ErrorCode, text: String },
+    Error {
+        code: ErrorCode,
+        #[serde(default)]
+        text: String,
+    },
+}
+
+/// Successful response to a [`Request`]
+#[derive(Debug, Clone)]
+pub enum Response<V = serde_json::Value> {
+    ReadOk { value: V },
+    WriteOk,
+    CasOk,
+}
+
+/// Completed request, returned from [`Client::receive`]
+pub struct Reply<C, V = serde_json::Value> {
+    /// Id of the request message
+    pub msg_id: usize,
+    /// Context supplied when sending the request
+    pub context: C,
+    pub result: Result<Response<V>, Error>,
+}
+
+/// Client for a key-value service storing values of type `V`, correlating each reply with
+/// the context of its request
+pub struct Client<C, V = serde_json::Value> {
+    service: Service,
+    node_id: String,
+    requests: Rpc<C>,
+    _value: std::marker::PhantomData<fn(V) -> V>,
+}
+impl<C, V> Client<C, V>
+where
+    V: Serialize,
+{
+    /// Creates a client sending requests from `node_id` (the current node) to `service`
+    pub fn new(service: Service, node_id: String) -> Self {
+        Self {
+            service,
+            node_id,
+            requests: Rpc::new(),
+            _value: std::marker::PhantomData,
+        }
+    }
+
+    pub fn service(&self) -> Service {
+        self.service
+    }
+
+    /// Sends `request`, returning its `msg_id`
+    pub fn send(
+        &mut self,
+        request: Request<V>,
+        context: C,
+        msg_id: &mut usize,
+        output: &mut impl MessageSink<Request<V>>,
+    ) -> anyhow::Result<usize> {
+        let message = Message {
+            src: self.node_id.clone(),
+            dest: self.service.node_id().to_string(),
+            body: Body {
+                msg_id: Some(crate::next_msg_id(msg_id)),
+                in_reply_to: None,
+                payload: request,
+            },
+        };
+        self.requests.send(message, context, output)
+    }
+
+    /// Sends a request to read the value for `key`
+    pub fn read(
+        &mut self,
+        key: impl Into<String>,
+        context: C,
+        msg_id: &mut usize,
+        output: &mut impl MessageSink<Request<V>>,
+    ) -> anyhow::Result<usize> {
+        let key = key.into();
+        self.send(Request::Read { key }, context, msg_id, output)
+    }
+
+    /// Sends a request to overwrite the value for `key`
+    pub fn write(
+        &mut self,
+        key: impl Into<String>,
+        value: V,
+        context: C,
+        msg_id: &mut usize,
+        output: &mut impl MessageSink<Request<V>>,
+    ) -> anyhow::Result<usize> {
+        let key = key.into();
+        self.send(Request::Write { key, value }, context, msg_id, output)
+    }
+
+    /// Sends a request to change the value for `key` from `from` to `to`, failing if the
+    /// current value is not `from`
+    ///
+    /// To create the key if it does not exist, [`Client::send`] a [`Request::Cas`] instead.
+    pub fn cas(
+        &mut self,
+        key: impl Into<String>,
+        from: V,
+        to: V,
+        context: C,
+        msg_id: &mut usize,
+        output: &mut impl MessageSink<Request<V>>,
+    ) -> anyhow::Result<usize> {
+        let request = Request::Cas {
+            key: key.into(),
+            from,
+            to,
+            create_if_not_exists: false,
+        };
+        self.send(request, context, msg_id, output)
+    }
+
+    /// Completes the request that `reply` responds to
+    ///
+    /// Returns `None` if no matching request is pending (e.g. after it timed out)
+    pub fn receive<P>(&mut self, reply: &Message<P>, payload: Receive<V>) -> Option<Reply<C, V>> {
+        let (msg_id, context) = self.requests.take_reply(reply)?;
+        let result = match payload {
+            Receive::ReadOk { value } => Ok(Response::ReadOk { value }),
+            Receive::WriteOk => Ok(Response::WriteOk),
+            Receive::CasOk => Ok(Response::CasOk),
+            Receive::Error { code, text } => Err(Error::new(code, text)),
+        };
+        Some(Reply {
+            msg_id,
+            context,
+            result,
+        })
+    }
+
+    /// Returns the context of the pending request `msg_id`, if its reply has not arrived
+    pub fn pending(&self, msg_id: usize) -> Option<&C> {
+        self.requests.get(msg_id)
+    }
+
+    /// Forgets the pending request `msg_id`, returning its context if the reply has not arrived
+    pub fn take_timed_out(&mut self, msg_id: usize) -> Option<C> {
+        self.requests.take_timed_out(msg_id)
+    }
 }
 
-pub use error::Code as ErrorCode;
-mod error {
-    use serde::{Deserialize, Serialize};
-
-    #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-    #[serde(from = "u32", into = "u32")]
-    pub enum Code {
-        KeyNotFound,
-        CasFromMismatch,
-        Unknown(u32),
-    }
-    impl Code {
-        const KEY_NOT_FOUND: u32 = 20;
-        const CAS_FROM_MISMATCH: u32 = 22;
-    }
-    impl From<u32> for Code {
-        fn from(code: u32) -> Self {
-            match code {
-                Self::KEY_NOT_FOUND => Self::KeyNotFound,
-                Self::CAS_FROM_MISMATCH => Self::CasFromMismatch,
-                unknown => Self::Unknown(unknown),
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RawMessage;
+    use serde_json::{json, Value};
+
+    /// Parses each message the client wrote to `output`
+    fn sent(output: &[u8]) -> Vec<RawMessage> {
+        crate::parse_output(output).unwrap()
+    }
+
+    fn reply(in_reply_to: usize) -> Message<()> {
+        Message {
+            src: NODE_ID_SEQ.to_string(),
+            dest: "n0".to_string(),
+            body: Body {
+                msg_id: None,
+                in_reply_to: Some(in_reply_to),
+                payload: (),
+            },
+        }
+    }
+
+    #[test]
+    fn requests_serialize_to_the_service() {
+        let mut client = Client::<(), usize>::new(Service::Seq, "n0".to_string());
+        let (mut msg_id, mut output) = (0, vec![]);
+        client.read("k", (), &mut msg_id, &mut output).unwrap();
+        client.write("k", 1, (), &mut msg_id, &mut output).unwrap();
+        client.cas("k", 1, 2, (), &mut msg_id, &mut output).unwrap();
+        let request = Request::Cas {
+            key: "k".to_string(),
+            from: 0,
+            to: 3,
+            create_if_not_exists: true,
+        };
+        client.send(request, (), &mut msg_id, &mut output).unwrap();
+
+        let sent = sent(&output);
+        for (index, message) in sent.iter().enumerate() {
+            assert_eq!(
+                (message.src.as_str(), message.dest.as_str()),
+                ("n0", "seq-kv")
+            );
+            assert_eq!(message.body.msg_id, Some(index));
+            assert_eq!(message.body.in_reply_to, None);
+        }
+        let payloads: Vec<_> = sent.into_iter().map(|m| m.body.payload).collect();
+        assert_eq!(
+            payloads,
+            [
+                json!({"type": "read", "key": "k"}),
+                json!({"type": "write", "key": "k", "value": 1}),
+                // NOTE: `create_if_not_exists` is left out when false
+                json!({"type": "cas", "key": "k", "from": 1, "to": 2}),
+                json!({"type": "cas", "key": "k", "from": 0, "to": 3, "create_if_not_exists": true}),
+            ]
+        );
+    }
+
+    #[test]
+    fn replies_are_correlated_by_in_reply_to() {
+        let mut client = Client::<&str, usize>::new(Service::Seq, "n0".to_string());
+        let (mut msg_id, mut output) = (0, vec![]);
+        let read = client.read("k", "read", &mut msg_id, &mut output).unwrap();
+        let write = client
+            .write("k", 1, "write", &mut msg_id, &mut output)
+            .unwrap();
+
+        let reply_to_write = client.receive(&reply(write), Receive::WriteOk).unwrap();
+        assert_eq!(
+            (reply_to_write.msg_id, reply_to_write.context),
+            (write, "write")
+        );
+        assert!(matches!(reply_to_write.result, Ok(Response::WriteOk)));
+
+        let reply_to_read = client
+            .receive(&reply(read), Receive::ReadOk { value: 7 })
+            .unwrap();
+        assert_eq!(
+            (reply_to_read.msg_id, reply_to_read.context),
+            (read, "read")
+        );
+        assert!(matches!(
+            reply_to_read.result,
+            Ok(Response::ReadOk { value: 7 })
+        ));
+
+        // NOTE: each request is only completed once
+        assert!(client.receive(&reply(read), Receive::WriteOk).is_none());
+        assert!(client.receive(&reply(msg_id), Receive::WriteOk).is_none());
+    }
+
+    #[test]
+    fn error_replies_map_to_errors() {
+        let mut client = Client::<(), usize>::new(Service::Seq, "n0".to_string());
+        let (mut msg_id, mut output) = (0, vec![]);
+        let cas = client.cas("k", 1, 2, (), &mut msg_id, &mut output).unwrap();
+
+        let payload: Receive<usize> = serde_json::from_value(json!({
+            "type": "error", "code": 22, "text": "current value 3 is not 1"
+        }))
+        .unwrap();
+        let completed = client.receive(&reply(cas), payload).unwrap();
+        let Err(error) = completed.result else {
+            panic!("error reply completed as a success");
+        };
+        assert_eq!(
+            error,
+            Error::new(ErrorCode::PreconditionFailed, "current value 3 is not 1")
+        );
+
+        // NOTE: the text may be left out
+        let cas = client.cas("k", 1, 2, (), &mut msg_id, &mut output).unwrap();
+        let payload: Receive<usize> =
+            serde_json::from_value(json!({"type": "error", "code": 20})).unwrap();
+        let completed = client.receive(&reply(cas), payload).unwrap();
+        assert_eq!(
+            completed.result.unwrap_err(),
+            Error::new(ErrorCode::KeyDoesNotExist, "")
+        );
+    }
+
+    #[test]
+    fn timed_out_requests_are_forgotten() {
+        let mut client = Client::<&str, usize>::new(Service::Seq, "n0".to_string());
+        let (mut msg_id, mut output) = (0, vec![]);
+        let read = client.read("k", "read", &mut msg_id, &mut output).unwrap();
+        let write = client
+            .write("k", 1, "write", &mut msg_id, &mut output)
+            .unwrap();
+        assert_eq!(client.pending(read), Some(&"read"));
+        assert_eq!(client.pending(write), Some(&"write"));
+
+        assert_eq!(client.take_timed_out(read), Some("read"));
+        assert_eq!(client.pending(read), None);
+        assert_eq!(client.take_timed_out(read), None);
+        // NOTE: so a late reply is not matched
+        assert!(client.receive(&reply(read), Receive::WriteOk).is_none());
+
+        assert!(client.receive(&reply(write), Receive::WriteOk).is_some());
+        assert_eq!(client.pending(write), None);
+        assert_eq!(client.take_timed_out(write), None);
+    }
+
+    #[test]
+    fn requests_go_to_each_service() {
+        for (service, node_id) in [
+            (Service::Seq, "seq-kv"),
+            (Service::Lin, "lin-kv"),
+            (Service::Lww, "lww-kv"),
+        ] {
+            assert_eq!(service.node_id(), node_id);
+            let mut client = Client::<(), usize>::new(service, "n0".to_string());
+            assert_eq!(client.service(), service);
+            let (mut msg_id, mut output) = (0, vec![]);
+            client.read("k", (), &mut msg_id, &mut output).unwrap();
+            assert_eq!(sent(&output)[0].dest, node_id);
         }
     }
-    impl From<Code> for u32 {
-        fn from(code: Code) -> Self {
-            match code {
-                Code::KeyNotFound => Code::KEY_NOT_FOUND,
-                Code::CasFromMismatch => Code::CAS_FROM_MISMATCH,
-                Code::Unknown(unknown) => unknown,
-            }
+
+    #[test]
+    fn values_may_be_any_json() {
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        struct Entry {
+            offset: u64,
+            msgs: Vec<String>,
         }
+        let entry = Entry {
+            offset: 3,
+            msgs: vec!["a".to_string()],
+        };
+        let mut client = Client::<(), Entry>::new(Service::Lin, "n0".to_string());
+        let (mut msg_id, mut output) = (0, vec![]);
+        let read = client.read("k", (), &mut msg_id, &mut output).unwrap();
+        client
+            .cas(
+                "k",
+                entry.clone(),
+                entry.clone(),
+                (),
+                &mut msg_id,
+                &mut output,
+            )
+            .unwrap();
+        let value = json!({"offset": 3, "msgs": ["a"]});
+        assert_eq!(
+            sent(&output)[1].body.payload,
+            json!({"type": "cas", "key": "k", "from": value, "to": value})
+        );
+
+        let payload: Receive<Entry> =
+            serde_json::from_value(json!({"type": "read_ok", "value": value})).unwrap();
+        let reply = client.receive(&reply(read), payload).unwrap();
+        assert!(matches!(reply.result, Ok(Response::ReadOk { value }) if value == entry));
+
+        let mut client = Client::<(), Value>::new(Service::Lww, "n0".to_string());
+        let value = json!([1, {"two": null}]);
+        client
+            .write("k", value.clone(), (), &mut msg_id, &mut output)
+            .unwrap();
+        assert_eq!(
+            sent(&output)[2].body.payload,
+            json!({"type": "write", "key": "k", "value": value})
+        );
     }
 }
@@ -0,0 +1,755 @@
+//! In-process network of [`Node`]s, for exercising a cluster without the maelstrom harness
+//!
+//! Each node is constructed from an `init` message (as in [`main_loop`](crate::main_loop)),
+//! and every [`Message`] written by a node is routed by `dest` to another node, to a
+//! registered [`Service`], or queued for the client that the message is addressed to.
+//!
+//! Messages may be dropped (e.g. to partition the network) with [`Simulator::set_drop`].
+//!
+//! Execution is deterministic for a given seed: time is virtual, timers registered through
+//! [`EventSender`] fire on the virtual clock, each node's [`EventSender::rng`] is seeded from
+//! the simulation seed, and message delivery order follows from seeded per-message latencies.
+//! Replaying a failing seed (with the same client inputs) reproduces the run exactly.
+
+pub use crate::RawMessage;
+use crate::{
+    timer::{ManualClock, Scheduled, Timers},
+    Body, Error, ErrorCode, EventSender, Init, InitPayload, InputReceiver, InputSender, Message,
+    MessageEvent, Node, Shutdown,
+};
+use anyhow::{bail, Context};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, VecDeque},
+    ops::RangeInclusive,
+    sync::Arc,
+    time::Duration,
+};
+
+/// Upper bound on virtual time taken by [`Simulator::run_until_idle`], to catch nodes which
+/// keep the network busy
+const MAX_IDLE_TIME: Duration = Duration::from_secs(60);
+/// Upper bound on steps taken by [`Simulator::run_until_idle`], to catch livelocked nodes
+/// (which may not advance the virtual time)
+const MAX_IDLE_STEPS: usize = 1_000_000;
+
+/// Default range of network latency, applied to each message
+const LATENCY_DEFAULT: RangeInclusive<Duration> = Duration::ZERO..=Duration::from_millis(10);
+
+/// Predicate on a message's `src`, `dest` and delivery time, which is true to drop the message
+type DropFn = Box<dyn FnMut(&str, &str, Duration) -> bool>;
+
+/// Counts of messages sent between nodes (excluding clients and services)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    /// Messages sent by a node to another node
+    pub sent: usize,
+    /// Messages sent by a node to another node, and dropped before delivery
+    pub dropped: usize,
+}
+
+/// Stand-in for a maelstrom-provided service (e.g. `seq-kv`), reachable by node id
+pub trait Service {
+    /// Handles a message addressed to the service, returning any replies
+    fn step_message(&mut self, message: RawMessage) -> Vec<RawMessage>;
+}
+
+/// Cluster of nodes of the same type, communicating over a simulated network
+pub struct Simulator<N, S = ()>
+where
+    N: Node<S>,
+{
+    seed: u64,
+    rng: StdRng,
+    now: Duration,
+    latency: RangeInclusive<Duration>,
+    nodes: Vec<SimNode<N, S>>,
+    node_indices: HashMap<String, usize>,
+    services: BTreeMap<String, Box<dyn Service>>,
+    network: BinaryHeap<Reverse<Scheduled<RawMessage>>>,
+    network_seq: usize,
+    network_stats: NetworkStats,
+    drop_fn: Option<DropFn>,
+    clock: Arc<ManualClock>,
+    timers: Arc<Timers<N::Event>>,
+    client_inbox: HashMap<String, Vec<RawMessage>>,
+    client_msg_id: usize,
+}
+
+struct SimNode<N, S>
+where
+    N: Node<S>,
+{
+    node: N,
+    event_rx: InputReceiver<N::Event>,
+    pending_events: VecDeque<N::Event>,
+    input_closed: bool,
+}
+
+impl<N, S> Simulator<N, S>
+where
+    N: Node<S>,
+    S: Clone,
+{
+    /// Creates `node_count` nodes named `n0`, `n1`, ..., each initialized with a clone of `start`
+    pub fn new(node_count: usize, start: S, seed: u64) -> anyhow::Result<Self> {
+        let node_ids: Vec<String> = (0..node_count).map(|n| format!("n{n}")).collect();
+        Self::with_node_ids(node_ids, start, seed)
+    }
+
+    /// Creates one node for each of the specified ids, each initialized with a clone of `start`
+    pub fn with_node_ids(node_ids: Vec<String>, start: S, seed: u64) -> anyhow::Result<Self> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let clock = Arc::new(ManualClock::default());
+        let timers = Arc::new(Timers::new(
+            Arc::clone(&clock) as _,
+            StdRng::seed_from_u64(rng.gen()),
+        ));
+        let mut nodes = Vec::with_capacity(node_ids.len());
+        let mut node_indices = HashMap::new();
+        for (node_index, node_id) in node_ids.iter().enumerate() {
+            if node_indices.insert(node_id.clone(), node_index).is_some() {
+                bail!("duplicate node id {node_id:?}");
+            }
+            let init = Init {
+                node_id: node_id.clone(),
+                node_ids: node_ids.clone(),
+            };
+            let event_tx = |tx| {
+                let node_rng = StdRng::seed_from_u64(rng.gen());
+                EventSender::new(tx, Arc::clone(&timers), node_index, node_rng)
+            };
+            nodes.push(SimNode::from_init(init, start.clone(), event_tx)?);
+        }
+        Ok(Self {
+            seed,
+            rng,
+            now: Duration::ZERO,
+            latency: LATENCY_DEFAULT,
+            nodes,
+            node_indices,
+            services: BTreeMap::new(),
+            network: BinaryHeap::new(),
+            network_seq: 0,
+            network_stats: NetworkStats::default(),
+            drop_fn: None,
+            clock,
+            timers,
+            client_inbox: HashMap::new(),
+            client_msg_id: 0,
+        })
+    }
+}
+
+impl<N, S> Simulator<N, S>
+where
+    N: Node<S>,
+{
+    /// Returns the seed used to construct the simulation, for replaying a run
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns the current virtual time, measured from the start of the simulation
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// Sets the range of latency (chosen at random for each message) for subsequent messages
+    pub fn set_latency(&mut self, latency: RangeInclusive<Duration>) {
+        self.latency = latency;
+    }
+
+    /// Drops each message for which `drop` returns true, given the message's `src`, `dest` and
+    /// delivery time (e.g. to partition the network)
+    ///
+    /// Replaces any previous predicate.
+    pub fn set_drop(&mut self, drop: impl FnMut(&str, &str, Duration) -> bool + 'static) {
+        self.drop_fn = Some(Box::new(drop));
+    }
+
+    /// Delivers all subsequent messages, removing the predicate set by [`Simulator::set_drop`]
+    pub fn clear_drop(&mut self) {
+        self.drop_fn = None;
+    }
+
+    /// Returns the counts of messages sent between nodes so far
+    pub fn network_stats(&self) -> NetworkStats {
+        self.network_stats
+    }
+
+    /// Registers a service, to receive all messages addressed to `service_id`
+    pub fn add_service(&mut self, service_id: impl Into<String>, service: impl Service + 'static) {
+        self.services.insert(service_id.into(), Box::new(service));
+    }
+
+    /// Returns the ids of all nodes, in the order they were created
+    pub fn node_ids(&self) -> impl Iterator<Item = &str> {
+        let mut ids: Vec<_> = self.node_indices.iter().collect();
+        ids.sort_by_key(|(_, &index)| index);
+        ids.into_iter().map(|(id, _)| id.as_str())
+    }
+
+    /// Returns the node with the specified id, for inspecting its state
+    pub fn node(&self, node_id: &str) -> Option<&N> {
+        let &index = self.node_indices.get(node_id)?;
+        Some(&self.nodes[index].node)
+    }
+
+    /// Queues a request from `client` to `dest`, returning the assigned `msg_id`
+    pub fn send_client(
+        &mut self,
+        client: &str,
+        dest: &str,
+        payload: impl Serialize,
+    ) -> anyhow::Result<usize> {
+        let payload = serde_json::to_value(payload).context("serialize client payload")?;
+        let msg_id = crate::next_msg_id(&mut self.client_msg_id);
+        self.route(Message {
+            src: client.to_string(),
+            dest: dest.to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        });
+        Ok(msg_id)
+    }
+
+    /// Removes and returns all messages delivered to `client` so far
+    pub fn take_client_messages(&mut self, client: &str) -> Vec<RawMessage> {
+        self.client_inbox.remove(client).unwrap_or_default()
+    }
+
+    /// Removes and returns the message delivered to `client` in reply to `msg_id`, if any
+    pub fn take_reply(&mut self, client: &str, msg_id: usize) -> Option<RawMessage> {
+        let inbox = self.client_inbox.get_mut(client)?;
+        let index = inbox
+            .iter()
+            .position(|m| m.body.in_reply_to == Some(msg_id))?;
+        Some(inbox.remove(index))
+    }
+
+    /// Performs the next action (delivering a message, or firing an event), advancing the
+    /// virtual clock as needed
+    ///
+    /// Returns `false` if there is nothing left to do
+    pub fn step(&mut self) -> anyhow::Result<bool> {
+        self.step_until(None)
+    }
+
+    /// Steps until no messages are in flight and no sent events are pending
+    ///
+    /// Timers which come due before then fire as usual, but pending timers (e.g. periodic
+    /// gossip, which never finishes) do not keep the simulation busy. Fails if still busy after
+    /// a minute of virtual time.
+    pub fn run_until_idle(&mut self) -> anyhow::Result<()> {
+        let deadline = self.now + MAX_IDLE_TIME;
+        for _ in 0..MAX_IDLE_STEPS {
+            if self.network.is_empty() && !self.has_sent_events()? {
+                return Ok(());
+            }
+            if !self.step_until(Some(deadline))? {
+                bail!("network still busy after {MAX_IDLE_TIME:?}");
+            }
+        }
+        bail!("network still busy after {MAX_IDLE_STEPS} steps")
+    }
+
+    /// Steps through all actions scheduled in the next `duration` of virtual time
+    pub fn run_for(&mut self, duration: Duration) -> anyhow::Result<()> {
+        let deadline = self.now + duration;
+        while self.step_until(Some(deadline))? {}
+        self.set_now(deadline);
+        Ok(())
+    }
+
+    /// Calls [`Node::shutdown`] on every node, as at the end of input, sending any output
+    pub fn shutdown(&mut self) -> anyhow::Result<()> {
+        for index in 0..self.nodes.len() {
+            let output = self.nodes[index]
+                .shutdown()
+                .with_context(|| self.context_description())?;
+            self.route_all(output);
+        }
+        Ok(())
+    }
+
+    fn step_until(&mut self, deadline: Option<Duration>) -> anyhow::Result<bool> {
+        // events sent directly by nodes are delivered at the current time
+        for index in 0..self.nodes.len() {
+            self.nodes[index].poll_events()?;
+            if let Some(event) = self.nodes[index].pending_events.pop_front() {
+                self.step_event(index, event)?;
+                return Ok(true);
+            }
+        }
+
+        let next_message_at = self.network.peek().map(|Reverse(next)| next.at);
+        let next_timer_at = self.timers.lock().next_at();
+        let next_at = match (next_message_at, next_timer_at) {
+            (Some(message_at), Some(timer_at)) => message_at.min(timer_at),
+            (Some(at), None) | (None, Some(at)) => at,
+            (None, None) => return Ok(false),
+        };
+        if deadline.is_some_and(|deadline| next_at > deadline) {
+            return Ok(false);
+        }
+        self.set_now(next_at);
+
+        if next_message_at == Some(next_at) {
+            let Some(Reverse(Scheduled { item: message, .. })) = self.network.pop() else {
+                unreachable!("peeked message missing");
+            };
+            self.deliver(message)?;
+        } else {
+            let Some((index, event)) = self.timers.lock().pop_due(next_at) else {
+                unreachable!("peeked timer missing");
+            };
+            self.step_event(index, event)?;
+        }
+        Ok(true)
+    }
+
+    fn has_sent_events(&mut self) -> anyhow::Result<bool> {
+        for node in &mut self.nodes {
+            node.poll_events()?;
+        }
+        Ok(self
+            .nodes
+            .iter()
+            .any(|node| !node.pending_events.is_empty()))
+    }
+
+    fn set_now(&mut self, now: Duration) {
+        self.now = now;
+        self.clock.set(now);
+    }
+
+    fn deliver(&mut self, message: RawMessage) -> anyhow::Result<()> {
+        if let Some(drop_fn) = &mut self.drop_fn {
+            if drop_fn(&message.src, &message.dest, self.now) {
+                if self.is_between_nodes(&message) {
+                    self.network_stats.dropped += 1;
+                }
+                return Ok(());
+            }
+        }
+        if let Some(&index) = self.node_indices.get(&message.dest) {
+            let output = self.nodes[index]
+                .step_message(message)
+                .with_context(|| self.context_description())?;
+            self.route_all(output);
+        } else if let Some(service) = self.services.get_mut(&message.dest) {
+            let replies = service.step_message(message);
+            self.route_all(replies);
+        } else {
+            self.client_inbox
+                .entry(message.dest.clone())
+                .or_default()
+                .push(message);
+        }
+        Ok(())
+    }
+
+    fn step_event(&mut self, index: usize, event: N::Event) -> anyhow::Result<()> {
+        let output = self.nodes[index]
+            .step_event(event)
+            .with_context(|| self.context_description())?;
+        self.route_all(output);
+        Ok(())
+    }
+
+    fn is_between_nodes(&self, message: &RawMessage) -> bool {
+        self.node_indices.contains_key(&message.src)
+            && self.node_indices.contains_key(&message.dest)
+    }
+
+    fn context_description(&self) -> String {
+        format!("simulation seed {} at {:?}", self.seed, self.now)
+    }
+
+    fn route_all(&mut self, messages: Vec<RawMessage>) {
+        for message in messages {
+            self.route(message);
+        }
+    }
+
+    fn route(&mut self, message: RawMessage) {
+        if self.is_between_nodes(&message) {
+            self.network_stats.sent += 1;
+        }
+        let latency = self.rng.gen_range(self.latency.clone());
+        let seq = crate::next_msg_id(&mut self.network_seq);
+        self.network.push(Reverse(Scheduled {
+            at: self.now + latency,
+            seq,
+            item: message,
+        }));
+    }
+}
+
+impl<N, S> SimNode<N, S>
+where
+    N: Node<S>,
+{
+    fn from_init(
+        init: Init,
+        start: S,
+        event_tx_fn: impl FnOnce(InputSender<N::Event>) -> EventSender<N::Event>,
+    ) -> anyhow::Result<Self> {
+        let mut msg_id = 0;
+        // reply as in `main_loop`, to keep `msg_id` numbering identical
+        let init = crate::reply_init(
+            Message {
+                src: String::new(),
+                dest: init.node_id.clone(),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: None,
+                    payload: InitPayload::Init(init),
+                },
+            },
+            &mut msg_id,
+            &mut std::io::sink(),
+        )?;
+
+        let (tx, event_rx) = std::sync::mpsc::channel();
+        let node = N::from_init(init, msg_id, start, event_tx_fn(tx));
+        Ok(Self {
+            node,
+            event_rx,
+            pending_events: VecDeque::new(),
+            input_closed: false,
+        })
+    }
+
+    /// Moves all events sent by the node (so far) into `pending_events`
+    fn poll_events(&mut self) -> anyhow::Result<()> {
+        while !self.input_closed {
+            let Ok(input) = self.event_rx.try_recv() else {
+                break;
+            };
+            match input? {
+                Ok(MessageEvent::Event(event)) => self.pending_events.push_back(event),
+                Ok(MessageEvent::Line(_)) => bail!("unexpected message on event channel"),
+                Err(Shutdown) => self.input_closed = true,
+            }
+        }
+        Ok(())
+    }
+
+    fn step_message(&mut self, message: RawMessage) -> anyhow::Result<Vec<RawMessage>> {
+        let mut output = vec![];
+        crate::step_message(&mut self.node, message, &mut output)?;
+        crate::parse_output(&output)
+    }
+
+    fn step_event(&mut self, event: N::Event) -> anyhow::Result<Vec<RawMessage>> {
+        let mut output = vec![];
+        crate::step_event(&mut self.node, event, &mut output)?;
+        crate::parse_output(&output)
+    }
+
+    fn shutdown(&mut self) -> anyhow::Result<Vec<RawMessage>> {
+        let mut output = vec![];
+        crate::step_shutdown(&mut self.node, &mut output)?;
+        crate::parse_output(&output)
+    }
+}
+
+/// In-memory stand-in for maelstrom's `seq-kv`, `lin-kv` and `lww-kv` services
+///
+/// All operations are applied in delivery order, so the store is linearizable. Keys may be any
+/// JSON value, and only equal values are the same key (e.g. `0` and `"0"` are distinct).
+#[derive(Default)]
+pub struct KeyValueService {
+    /// Values by the JSON text of their key
+    values: HashMap<String, Value>,
+    msg_id: usize,
+}
+
+impl Service for KeyValueService {
+    fn step_message(&mut self, message: RawMessage) -> Vec<RawMessage> {
+        let payload = match message.body.payload.get("type").and_then(Value::as_str) {
+            Some("read") => {
+                let key = key_string(&message.body.payload);
+                match self.values.get(&key) {
+                    Some(value) => serde_json::json!({ "type": "read_ok", "value": value }),
+                    None => {
+                        error_payload(Error::new(ErrorCode::KeyDoesNotExist, "key does not exist"))
+                    }
+                }
+            }
+            Some("write") => {
+                let key = key_string(&message.body.payload);
+                let value = message.body.payload["value"].clone();
+                self.values.insert(key, value);
+                serde_json::json!({ "type": "write_ok" })
+            }
+            Some("cas") => {
+                let payload = &message.body.payload;
+                let key = key_string(payload);
+                let create_if_not_exists = payload["create_if_not_exists"] == Value::Bool(true);
+                match self.values.get(&key) {
+                    Some(current) if *current == payload["from"] => {
+                        self.values.insert(key, payload["to"].clone());
+                        serde_json::json!({ "type": "cas_ok" })
+                    }
+                    Some(current) => error_payload(Error::new(
+                        ErrorCode::PreconditionFailed,
+                        format!("current value {current} is not {}", payload["from"]),
+                    )),
+                    None if create_if_not_exists => {
+                        self.values.insert(key, payload["to"].clone());
+                        serde_json::json!({ "type": "cas_ok" })
+                    }
+                    None => {
+                        error_payload(Error::new(ErrorCode::KeyDoesNotExist, "key does not exist"))
+                    }
+                }
+            }
+            other => error_payload(Error::not_supported(format!(
+                "unsupported request type {other:?}"
+            ))),
+        };
+        let mut reply = message.reply(Some(&mut self.msg_id));
+        reply.body.payload = payload;
+        vec![reply]
+    }
+}
+
+fn key_string(payload: &Value) -> String {
+    payload["key"].to_string()
+}
+
+fn error_payload(error: Error) -> Value {
+    serde_json::to_value(error).expect("error serializes to json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Never, Output};
+    use serde::Deserialize;
+    use serde_json::json;
+
+    /// Node which echoes requests, passes each `ping` on to the next node, forwards each `ask`
+    /// to the `svc` service (without expecting a reply), and ticks periodically
+    struct Pinger {
+        msg_id: usize,
+        node_id: String,
+        next: String,
+        ticks: usize,
+    }
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        Echo { echo: String },
+        Ping,
+        Ask,
+    }
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Outbound {
+        EchoOk { echo: String },
+        Ping,
+        Ask,
+    }
+    #[derive(Serialize, Deserialize)]
+    struct Tick;
+
+    impl Node for Pinger {
+        type Request = Request;
+        type Response = Never;
+        type Outbound = Outbound;
+        type Event = Tick;
+
+        fn from_init(init: Init, msg_id: usize, _start: (), event_tx: EventSender<Tick>) -> Self {
+            event_tx.every(Duration::from_millis(100), || Tick);
+            let index = init.node_ids.iter().position(|id| *id == init.node_id);
+            let next = (index.unwrap_or_default() + 1) % init.node_ids.len();
+            Self {
+                msg_id,
+                next: init.node_ids[next].clone(),
+                node_id: init.node_id,
+                ticks: 0,
+            }
+        }
+
+        fn step_request(
+            &mut self,
+            message: Message<Request>,
+            output: &mut Output<'_, Self::Outbound>,
+        ) -> anyhow::Result<()> {
+            match &message.body.payload {
+                Request::Echo { echo } => {
+                    let echo = echo.clone();
+                    message
+                        .reply_with(Some(&mut self.msg_id), Outbound::EchoOk { echo })
+                        .send(output)
+                }
+                Request::Ping => Message {
+                    src: self.node_id.clone(),
+                    dest: self.next.clone(),
+                    body: Body {
+                        msg_id: None,
+                        in_reply_to: None,
+                        payload: Outbound::Ping,
+                    },
+                }
+                .send(output),
+                Request::Ask => Message {
+                    src: self.node_id.clone(),
+                    dest: "svc".to_string(),
+                    body: Body {
+                        msg_id: Some(crate::next_msg_id(&mut self.msg_id)),
+                        in_reply_to: None,
+                        payload: Outbound::Ask,
+                    },
+                }
+                .send(output),
+            }
+        }
+
+        fn step_response(
+            &mut self,
+            message: Message<Never>,
+            _output: &mut Output<'_, Self::Outbound>,
+        ) -> anyhow::Result<()> {
+            match message.body.payload {}
+        }
+
+        fn step_event(
+            &mut self,
+            _tick: Tick,
+            _output: &mut Output<'_, Self::Outbound>,
+        ) -> anyhow::Result<()> {
+            self.ticks += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn run_until_idle_ignores_pending_periodic_timers() {
+        let mut sim = Simulator::<Pinger>::new(3, (), 1).unwrap();
+        let msg_id = sim
+            .send_client("c1", "n1", json!({"type": "echo", "echo": "hi"}))
+            .unwrap();
+        sim.run_until_idle().unwrap();
+        let reply = sim.take_reply("c1", msg_id).unwrap();
+        assert_eq!(reply.body.payload, json!({"type": "echo_ok", "echo": "hi"}));
+
+        // the timers keep firing, and the simulation still goes idle between them
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        sim.run_until_idle().unwrap();
+        assert_eq!(sim.node("n2").unwrap().ticks, 10);
+    }
+
+    #[test]
+    fn run_until_idle_fails_when_network_stays_busy() {
+        let mut sim = Simulator::<Pinger>::new(3, (), 1).unwrap();
+        // NOTE: the ping is passed around the ring forever
+        sim.send_client("c1", "n0", json!({"type": "ping"}))
+            .unwrap();
+        let error = sim.run_until_idle().unwrap_err();
+        assert!(error.to_string().contains("still busy"), "{error:#}");
+        assert!(sim.now() <= MAX_IDLE_TIME);
+    }
+
+    #[test]
+    fn dropped_messages_are_not_delivered() {
+        let mut sim = Simulator::<Pinger>::new(3, (), 1).unwrap();
+        // NOTE: cuts the ring between n1 and n2, until healed
+        sim.set_drop(|src, dest, _at| src == "n1" && dest == "n2");
+        sim.send_client("c1", "n0", json!({"type": "ping"}))
+            .unwrap();
+        sim.run_until_idle().unwrap();
+        let stats = sim.network_stats();
+        assert_eq!(
+            stats,
+            NetworkStats {
+                sent: 2,
+                dropped: 1
+            }
+        );
+
+        // clients are not counted
+        sim.clear_drop();
+        let msg_id = sim
+            .send_client("c1", "n2", json!({"type": "echo", "echo": "hi"}))
+            .unwrap();
+        sim.run_until_idle().unwrap();
+        assert!(sim.take_reply("c1", msg_id).is_some());
+        assert_eq!(sim.network_stats(), stats);
+    }
+
+    /// Service replying to each message with an unexpected payload, counting the messages
+    struct Confused {
+        received: Arc<std::sync::atomic::AtomicUsize>,
+        msg_id: usize,
+    }
+    impl Service for Confused {
+        fn step_message(&mut self, message: RawMessage) -> Vec<RawMessage> {
+            self.received
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let mut reply = message.reply(Some(&mut self.msg_id));
+            reply.body.payload = json!({"type": "bogus"});
+            vec![reply]
+        }
+    }
+
+    #[test]
+    fn unexpected_replies_are_not_answered() {
+        let mut sim = Simulator::<Pinger>::new(1, (), 1).unwrap();
+        let received = Arc::default();
+        sim.add_service(
+            "svc",
+            Confused {
+                received: Arc::clone(&received),
+                msg_id: 0,
+            },
+        );
+        sim.send_client("c1", "n0", json!({"type": "ask"})).unwrap();
+        sim.run_until_idle().unwrap();
+        // NOTE: the node rejects the reply, without an `error` reply to the service
+        assert_eq!(received.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        let msg_id = sim
+            .send_client("c1", "n0", json!({"type": "echo", "echo": "hi"}))
+            .unwrap();
+        sim.run_until_idle().unwrap();
+        assert!(sim.take_reply("c1", msg_id).is_some());
+    }
+
+    #[test]
+    fn key_value_keys_of_different_types_are_distinct() {
+        let mut kv = KeyValueService::default();
+        let mut request = |payload: Value| {
+            let message = Message {
+                src: "n0".to_string(),
+                dest: "lin-kv".to_string(),
+                body: Body {
+                    msg_id: Some(0),
+                    in_reply_to: None,
+                    payload,
+                },
+            };
+            let mut replies = kv.step_message(message);
+            assert_eq!(replies.len(), 1);
+            replies.remove(0).body.payload
+        };
+        request(json!({"type": "write", "key": 0, "value": "int"}));
+        request(json!({"type": "write", "key": "0", "value": "string"}));
+        let read = request(json!({"type": "read", "key": 0}));
+        assert_eq!(read, json!({"type": "read_ok", "value": "int"}));
+        let read = request(json!({"type": "read", "key": "0"}));
+        assert_eq!(read, json!({"type": "read_ok", "value": "string"}));
+        let read = request(json!({"type": "read", "key": 1}));
+        assert_eq!(read["code"], json!(20));
+    }
+}
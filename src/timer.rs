@@ -0,0 +1,413 @@
+//! One-shot and periodic timers, delivering [`Node::Event`](crate::Node::Event)s on a
+//! swappable [`Clock`]
+
+use rand::{rngs::StdRng, Rng};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+/// Source of the current time, as elapsed since the clock started
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+/// Clock following the system's monotonic time
+pub struct SystemClock {
+    start: Instant,
+}
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Clock that only moves when set, e.g. by the [`simulator`](crate::simulator)
+#[derive(Default)]
+pub struct ManualClock {
+    now: Mutex<Duration>,
+}
+impl ManualClock {
+    pub fn set(&self, now: Duration) {
+        *self.now.lock().expect("clock lock poisoned") = now;
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().expect("clock lock poisoned")
+    }
+}
+
+/// Handle to cancel or reschedule a scheduled timer
+///
+/// Dropping the handle does NOT cancel the timer.
+#[derive(Clone)]
+pub struct TimerHandle {
+    timer_id: usize,
+    cancelled: Arc<AtomicBool>,
+    control: Arc<dyn TimerControl>,
+}
+impl TimerHandle {
+    /// Stops the timer from firing again
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.control.cancel(self.timer_id);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+    /// Moves the next firing of the timer to `delay` from now (a periodic timer then repeats
+    /// on its interval from there)
+    ///
+    /// Returns false if the timer is cancelled, or is a one-shot timer which already fired.
+    pub fn reschedule(&self, delay: Duration) -> bool {
+        self.control.reschedule(self.timer_id, delay)
+    }
+}
+
+/// Operations on a timer through its [`TimerHandle`], independent of the event type
+trait TimerControl: Send + Sync {
+    fn cancel(&self, timer_id: usize);
+    fn reschedule(&self, timer_id: usize, delay: Duration) -> bool;
+}
+
+/// Repetition of a periodic timer
+#[derive(Clone, Copy, Debug)]
+pub struct Interval {
+    /// Time between firings, from [`Interval::MIN_PERIOD`] to [`Interval::MAX_PERIOD`]
+    pub period: Duration,
+    /// Maximum random delay added to each firing (not accumulated over firings)
+    pub jitter: Duration,
+}
+impl Interval {
+    /// Shortest period of a timer, to which shorter (e.g. zero) periods are raised, so that a
+    /// periodic timer never fires repeatedly at the same instant
+    pub const MIN_PERIOD: Duration = Duration::from_millis(1);
+    /// Longest period of a timer, to which longer periods are lowered, so that its firing
+    /// times cannot overflow
+    pub const MAX_PERIOD: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+    /// Returns the interval with its period raised or lowered to the range of periods
+    pub fn clamped(self) -> Self {
+        Self {
+            period: self.period.clamp(Self::MIN_PERIOD, Self::MAX_PERIOD),
+            ..self
+        }
+    }
+}
+impl From<Duration> for Interval {
+    fn from(period: Duration) -> Self {
+        Self {
+            period,
+            jitter: Duration::ZERO,
+        }
+    }
+}
+
+/// Item scheduled for a point in time, ordered by time then by scheduling order
+pub(crate) struct Scheduled<T> {
+    pub at: Duration,
+    pub seq: usize,
+    pub item: T,
+}
+impl<T> Scheduled<T> {
+    fn key(&self) -> (Duration, usize) {
+        (self.at, self.seq)
+    }
+}
+impl<T> PartialEq for Scheduled<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+impl<T> Eq for Scheduled<T> {}
+impl<T> PartialOrd for Scheduled<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<T> Ord for Scheduled<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Timers shared between the nodes' [`EventSender`](crate::EventSender)s and the driver
+/// that fires them (a thread in [`main_loop`](crate::main_loop), or the simulator)
+pub(crate) struct Timers<T> {
+    clock: Arc<dyn Clock>,
+    queue: Mutex<TimerQueue<T>>,
+    wake: Condvar,
+}
+pub(crate) struct TimerQueue<T> {
+    seq: usize,
+    rng: StdRng,
+    /// Firings of the timers, by timer id, which are stale unless the timer has the same `seq`
+    schedule: BinaryHeap<Reverse<Scheduled<usize>>>,
+    /// Timers which are neither cancelled nor finished, by id
+    timers: HashMap<usize, Timer<T>>,
+    next_timer_id: usize,
+}
+struct Timer<T> {
+    node_index: usize,
+    /// Time of the current firing, before adding jitter
+    nominal_at: Duration,
+    /// Sequence number of the current firing in the schedule
+    seq: usize,
+    interval: Option<Interval>,
+    event_fn: Box<dyn FnMut() -> T + Send>,
+}
+
+impl<T> Timers<T> {
+    /// Creates an empty set of timers, using `rng` to choose jitter
+    pub fn new(clock: Arc<dyn Clock>, rng: StdRng) -> Self {
+        Self {
+            clock,
+            queue: Mutex::new(TimerQueue {
+                seq: 0,
+                rng,
+                schedule: BinaryHeap::new(),
+                timers: HashMap::new(),
+                next_timer_id: 0,
+            }),
+            wake: Condvar::new(),
+        }
+    }
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+    pub fn lock(&self) -> MutexGuard<'_, TimerQueue<T>> {
+        self.queue.lock().expect("timer lock poisoned")
+    }
+    /// Fires timers as they become due (per the system clock), until `send_fn` returns `false`
+    pub fn run_thread(&self, mut send_fn: impl FnMut(usize, T) -> bool) {
+        let mut queue = self.lock();
+        loop {
+            let now = self.now();
+            while let Some((node_index, event)) = queue.pop_due(now) {
+                if !send_fn(node_index, event) {
+                    return;
+                }
+            }
+            queue = match queue.next_at() {
+                Some(next_at) => {
+                    let timeout = next_at.saturating_sub(now);
+                    let (queue, _) = self
+                        .wake
+                        .wait_timeout(queue, timeout)
+                        .expect("timer lock poisoned");
+                    queue
+                }
+                None => self.wake.wait(queue).expect("timer lock poisoned"),
+            };
+        }
+    }
+}
+impl<T> Timers<T>
+where
+    T: 'static,
+{
+    /// Schedules `event_fn` to fire after `delay`, then repeating on `interval` (if any)
+    pub fn schedule(
+        self: &Arc<Self>,
+        node_index: usize,
+        delay: Duration,
+        interval: Option<Interval>,
+        event_fn: Box<dyn FnMut() -> T + Send>,
+    ) -> TimerHandle {
+        let timer = Timer {
+            node_index,
+            nominal_at: self.now().saturating_add(delay),
+            seq: 0,
+            interval: interval.map(Interval::clamped),
+            event_fn,
+        };
+        let timer_id = self.lock().insert(timer);
+        self.wake.notify_all();
+        TimerHandle {
+            timer_id,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            control: Arc::clone(self) as _,
+        }
+    }
+}
+impl<T> TimerControl for Timers<T> {
+    fn cancel(&self, timer_id: usize) {
+        self.lock().timers.remove(&timer_id);
+    }
+    fn reschedule(&self, timer_id: usize, delay: Duration) -> bool {
+        let nominal_at = self.now().saturating_add(delay);
+        let mut queue = self.lock();
+        let Some(timer) = queue.timers.get_mut(&timer_id) else {
+            return false;
+        };
+        timer.nominal_at = nominal_at;
+        queue.push(timer_id);
+        drop(queue);
+        self.wake.notify_all();
+        true
+    }
+}
+impl<T> TimerQueue<T> {
+    /// Adds `timer`, scheduling its first firing, and returns its id
+    fn insert(&mut self, timer: Timer<T>) -> usize {
+        let timer_id = crate::next_msg_id(&mut self.next_timer_id);
+        self.timers.insert(timer_id, timer);
+        self.push(timer_id);
+        timer_id
+    }
+    /// Schedules the next firing of the timer at its `nominal_at` (plus jitter), replacing any
+    /// firing already scheduled
+    fn push(&mut self, timer_id: usize) {
+        let Some(timer) = self.timers.get_mut(&timer_id) else {
+            return;
+        };
+        let jitter = match timer.interval {
+            Some(Interval { jitter, .. }) if !jitter.is_zero() => {
+                self.rng.gen_range(Duration::ZERO..=jitter)
+            }
+            _ => Duration::ZERO,
+        };
+        let at = timer.nominal_at.saturating_add(jitter);
+        let seq = crate::next_msg_id(&mut self.seq);
+        timer.seq = seq;
+        self.schedule.push(Reverse(Scheduled {
+            at,
+            seq,
+            item: timer_id,
+        }));
+    }
+    /// Returns the time of the next firing (of a timer not cancelled)
+    pub fn next_at(&mut self) -> Option<Duration> {
+        while let Some(Reverse(next)) = self.schedule.peek() {
+            let current = self.timers.get(&next.item);
+            if current.is_some_and(|timer| timer.seq == next.seq) {
+                return Some(next.at);
+            }
+            self.schedule.pop();
+        }
+        None
+    }
+    /// Removes the next timer due at or before `now` (rescheduling it, if periodic),
+    /// returning its node and event
+    pub fn pop_due(&mut self, now: Duration) -> Option<(usize, T)> {
+        if self.next_at()? > now {
+            return None;
+        }
+        let Reverse(Scheduled { item: timer_id, .. }) = self.schedule.pop()?;
+        let timer = self.timers.get_mut(&timer_id)?;
+        let node_index = timer.node_index;
+        let event = (timer.event_fn)();
+        match timer.interval {
+            Some(interval) => {
+                timer.nominal_at = timer.nominal_at.saturating_add(interval.period);
+                self.push(timer_id);
+            }
+            None => {
+                self.timers.remove(&timer_id);
+            }
+        }
+        Some((node_index, event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn timers() -> (Arc<ManualClock>, Arc<Timers<usize>>) {
+        let clock = Arc::new(ManualClock::default());
+        let timers = Timers::new(clock.clone(), StdRng::seed_from_u64(0));
+        (clock, Arc::new(timers))
+    }
+
+    fn fire(timers: &Timers<usize>, now: Duration) -> Vec<usize> {
+        let mut queue = timers.lock();
+        std::iter::from_fn(|| queue.pop_due(now).map(|(_, event)| event)).collect()
+    }
+
+    fn counter() -> Box<dyn FnMut() -> usize + Send> {
+        let mut count = 0;
+        Box::new(move || {
+            count += 1;
+            count
+        })
+    }
+
+    #[test]
+    fn zero_period_is_raised_to_minimum() {
+        let (_, timers) = timers();
+        timers.schedule(0, Duration::ZERO, Some(Duration::ZERO.into()), counter());
+
+        assert_eq!(fire(&timers, Duration::ZERO), [1]);
+        assert_eq!(timers.lock().next_at(), Some(Interval::MIN_PERIOD));
+        assert_eq!(fire(&timers, Interval::MIN_PERIOD * 3), [2, 3, 4]);
+    }
+
+    #[test]
+    fn every_clamps_the_first_delay() {
+        let (_, timers) = timers();
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let event_tx =
+            crate::EventSender::new(tx, Arc::clone(&timers), 0, StdRng::seed_from_u64(0));
+
+        let zero = event_tx.every(Duration::ZERO, counter());
+        assert_eq!(timers.lock().next_at(), Some(Interval::MIN_PERIOD));
+        assert!(fire(&timers, Duration::ZERO).is_empty());
+        assert_eq!(fire(&timers, Interval::MIN_PERIOD * 2), [1, 2]);
+        zero.cancel();
+
+        event_tx.every(Duration::MAX, counter());
+        assert_eq!(timers.lock().next_at(), Some(Interval::MAX_PERIOD));
+    }
+
+    #[test]
+    fn reschedule_moves_next_firing() {
+        let (clock, timers) = timers();
+        let second = Duration::from_secs(1);
+        let handle = timers.schedule(0, second, Some(second.into()), counter());
+
+        clock.set(second / 2);
+        assert!(handle.reschedule(second * 2));
+        assert!(fire(&timers, second * 2).is_empty());
+        assert_eq!(fire(&timers, second * 5 / 2), [1]);
+        assert_eq!(timers.lock().next_at(), Some(second * 7 / 2));
+    }
+
+    #[test]
+    fn fired_one_shot_cannot_be_rescheduled() {
+        let (_, timers) = timers();
+        let handle = timers.schedule(0, Duration::ZERO, None, counter());
+
+        assert_eq!(fire(&timers, Duration::ZERO), [1]);
+        assert!(!handle.reschedule(Duration::ZERO));
+        assert_eq!(timers.lock().next_at(), None);
+    }
+
+    #[test]
+    fn cancel_stops_timer() {
+        let (_, timers) = timers();
+        let second = Duration::from_secs(1);
+        let handle = timers.schedule(0, second, Some(second.into()), counter());
+
+        assert_eq!(fire(&timers, second), [1]);
+        handle.cancel();
+        assert!(handle.is_cancelled());
+        assert!(!handle.reschedule(second));
+        assert!(fire(&timers, second * 10).is_empty());
+        assert_eq!(timers.lock().next_at(), None);
+    }
+}
@@ -0,0 +1,479 @@
+//! Derive macros for `telephone_line`
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, LitStr};
+
+/// Composes several protocol enums into one wire payload
+///
+/// Each variant wraps one protocol type (typically an internally tagged serde enum), e.g. the
+/// workload's requests and replies, and a service's replies:
+///
+/// ```ignore
+/// #[derive(Debug, Clone, Protocol)]
+/// pub enum Raw {
+///     Count(CountReceive),
+///     CountOk(CountSend),
+///     #[protocol(peer = "seq-kv")]
+///     Kv(key_value::Receive<usize>),
+/// }
+/// ```
+///
+/// Derives `Serialize` (as the wrapped value), `Deserialize`, `From` for each wrapped type, and
+/// `TryFrom` back to each wrapped type (failing with the payload itself).
+///
+/// Deserializing tries each variant in declaration order, so a colliding `type` tag is resolved
+/// by field shape. A variant marked `#[protocol(peer = "...")]` (repeatable) only matches
+/// messages from the listed peers, and is tried first for those messages. When the sender is
+/// unknown (the payload is deserialized outside of a `Message`), every variant is tried in order.
+/// If no variant matches, the error lists each variant tried with its own error.
+///
+/// The payload can also be split into the requests and the responses a node receives, as
+/// separate enums named by `#[protocol(request = "...", response = "...")]`:
+///
+/// ```ignore
+/// #[derive(Debug, Clone, Protocol)]
+/// #[protocol(request = "Request", response = "Response")]
+/// pub enum Raw {
+///     #[protocol(request)]
+///     Count(CountReceive),
+///     #[protocol(response)]
+///     CountOk(CountSend),
+///     #[protocol(response, peer = "seq-kv")]
+///     Kv(key_value::Receive<usize>),
+/// }
+/// ```
+///
+/// Each generated enum has the marked variants, derives `Clone`, `Debug` and `Protocol`, and
+/// converts into the payload (`From`) and back (`TryFrom`).
+#[proc_macro_derive(Protocol, attributes(protocol))]
+pub fn derive_protocol(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_protocol(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[derive(Clone)]
+struct Variant {
+    ident: syn::Ident,
+    ty: syn::Type,
+    peers: Vec<LitStr>,
+    request: bool,
+    response: bool,
+}
+
+/// Enum generated with some of the payload's variants
+struct Split {
+    ident: syn::Ident,
+    select: fn(&Variant) -> bool,
+}
+
+fn expand_protocol(input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            name,
+            "Protocol can only be derived for enums",
+        ));
+    };
+
+    let mut request = None;
+    let mut response = None;
+    for attr in &input.attrs {
+        if !attr.path().is_ident("protocol") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            let split = if meta.path.is_ident("request") {
+                &mut request
+            } else if meta.path.is_ident("response") {
+                &mut response
+            } else {
+                return Err(meta.error("expected `request = \"...\"` or `response = \"...\"`"));
+            };
+            let ident: LitStr = meta.value()?.parse()?;
+            *split = Some(ident.parse::<syn::Ident>()?);
+            Ok(())
+        })?;
+    }
+
+    let variants = data
+        .variants
+        .iter()
+        .map(|variant| {
+            let ty = match &variant.fields {
+                Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                    fields.unnamed[0].ty.clone()
+                }
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        variant,
+                        "Protocol variants must wrap exactly one protocol type",
+                    ))
+                }
+            };
+            let mut parsed = Variant {
+                ident: variant.ident.clone(),
+                ty,
+                peers: vec![],
+                request: false,
+                response: false,
+            };
+            for attr in &variant.attrs {
+                if !attr.path().is_ident("protocol") {
+                    continue;
+                }
+                attr.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("peer") {
+                        parsed.peers.push(meta.value()?.parse()?);
+                    } else if meta.path.is_ident("request") && request.is_some() {
+                        parsed.request = true;
+                    } else if meta.path.is_ident("response") && response.is_some() {
+                        parsed.response = true;
+                    } else if meta.path.is_ident("request") || meta.path.is_ident("response") {
+                        return Err(meta.error(
+                            "the enum needs `#[protocol(request = \"...\", response = \"...\")]`",
+                        ));
+                    } else {
+                        return Err(
+                            meta.error("expected `peer = \"...\"`, `request` or `response`")
+                        );
+                    }
+                    Ok(())
+                })?;
+            }
+            Ok(parsed)
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let splits = [
+        request.map(|ident| Split {
+            ident,
+            select: |v| v.request,
+        }),
+        response.map(|ident| Split {
+            ident,
+            select: |v| v.response,
+        }),
+    ];
+    let splits = splits.into_iter().flatten().collect::<Vec<_>>();
+    if !splits.is_empty() && !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "generic Protocol enums cannot be split into requests and responses",
+        ));
+    }
+
+    let mut expanded = expand_impls(name, &input.generics, &variants);
+    for split in splits {
+        expanded.extend(expand_split(&input, &variants, split));
+    }
+    Ok(expanded)
+}
+
+/// Expands the `Serialize`, `Deserialize` and conversion impls for the enum `name`
+fn expand_impls(name: &syn::Ident, generics: &syn::Generics, variants: &[Variant]) -> TokenStream {
+    let private = quote! { ::telephone_line::protocol::__private };
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let where_predicates = where_clause.map(|where_clause| &where_clause.predicates);
+    let mut de_generics = generics.clone();
+    de_generics.params.insert(0, parse_quote!('de));
+    let (de_impl_generics, _, _) = de_generics.split_for_impl();
+    let types = variants.iter().map(|v| &v.ty).collect::<Vec<_>>();
+
+    let serialize_arms = variants.iter().map(|Variant { ident, .. }| {
+        quote! { Self::#ident(inner) => #private::serde::Serialize::serialize(inner, serializer), }
+    });
+
+    let try_variant = |Variant { ident, ty, .. }: &Variant| {
+        let ident_str = ident.to_string();
+        quote! {
+            match <#ty as #private::serde::Deserialize>::deserialize(&value) {
+                ::std::result::Result::Ok(inner) => {
+                    return ::std::result::Result::Ok(Self::#ident(inner));
+                }
+                ::std::result::Result::Err(err) => {
+                    errors.push(::std::format!("{}: {}", #ident_str, err));
+                }
+            }
+        }
+    };
+    let peer_attempts = variants.iter().filter(|v| !v.peers.is_empty()).map(|v| {
+        let peers = &v.peers;
+        let attempt = try_variant(v);
+        quote! {
+            if peer.as_deref().is_some_and(|peer| [#(#peers),*].contains(&peer)) {
+                #attempt
+            }
+        }
+    });
+    let ordered_attempts = variants.iter().map(|v| {
+        let attempt = try_variant(v);
+        if v.peers.is_empty() {
+            attempt
+        } else {
+            quote! {
+                if peer.is_none() {
+                    #attempt
+                }
+            }
+        }
+    });
+
+    let conversion_impls = variants.iter().map(|Variant { ident, ty, .. }| {
+        quote! {
+            impl #impl_generics ::std::convert::From<#ty> for #name #ty_generics #where_clause {
+                fn from(inner: #ty) -> Self {
+                    Self::#ident(inner)
+                }
+            }
+
+            impl #impl_generics ::std::convert::TryFrom<#name #ty_generics> for #ty #where_clause {
+                type Error = #name #ty_generics;
+
+                fn try_from(
+                    payload: #name #ty_generics,
+                ) -> ::std::result::Result<Self, #name #ty_generics> {
+                    #[allow(unreachable_patterns)]
+                    match payload {
+                        #name::#ident(inner) => ::std::result::Result::Ok(inner),
+                        other => ::std::result::Result::Err(other),
+                    }
+                }
+            }
+        }
+    });
+
+    let name_str = name.to_string();
+    quote! {
+        impl #impl_generics #private::serde::Serialize for #name #ty_generics
+        where
+            #(#types: #private::serde::Serialize,)*
+            #where_predicates
+        {
+            fn serialize<__S>(&self, serializer: __S) -> ::std::result::Result<__S::Ok, __S::Error>
+            where
+                __S: #private::serde::Serializer,
+            {
+                match self {
+                    #(#serialize_arms)*
+                }
+            }
+        }
+
+        impl #de_impl_generics #private::serde::Deserialize<'de> for #name #ty_generics
+        where
+            #(#types: #private::serde::de::DeserializeOwned,)*
+            #where_predicates
+        {
+            fn deserialize<__D>(deserializer: __D) -> ::std::result::Result<Self, __D::Error>
+            where
+                __D: #private::serde::Deserializer<'de>,
+            {
+                let value =
+                    <#private::serde_json::Value as #private::serde::Deserialize>::deserialize(
+                        deserializer,
+                    )?;
+                let peer = ::telephone_line::protocol::current_peer();
+                // NOTE: the error of each variant tried, to report if none matches
+                let mut errors: ::std::vec::Vec<::std::string::String> = ::std::vec::Vec::new();
+                #(#peer_attempts)*
+                #(#ordered_attempts)*
+                let tried = if errors.is_empty() {
+                    ::std::format!("no variant for peer {:?}", peer)
+                } else {
+                    errors.join("; ")
+                };
+                ::std::result::Result::Err(<__D::Error as #private::serde::de::Error>::custom(
+                    ::std::format!("no {} variant matches payload {} ({})", #name_str, value, tried),
+                ))
+            }
+        }
+
+        #(#conversion_impls)*
+    }
+}
+
+/// Expands the enum `split` of the selected variants, its impls, and its conversions to and
+/// from the payload
+fn expand_split(input: &DeriveInput, variants: &[Variant], split: Split) -> TokenStream {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let ident = &split.ident;
+    let variants = variants
+        .iter()
+        .filter(|v| (split.select)(v))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let docs = format!("Variants of [`{name}`] received as `{ident}`s");
+    let decls = variants
+        .iter()
+        .map(|Variant { ident, ty, .. }| quote! { #ident(#ty), });
+    let into_arms = variants.iter().map(|Variant { ident: variant, .. }| {
+        quote! { #ident::#variant(inner) => #name::#variant(inner), }
+    });
+    let from_arms = variants.iter().map(|Variant { ident: variant, .. }| {
+        quote! { #name::#variant(inner) => ::std::result::Result::Ok(#ident::#variant(inner)), }
+    });
+    let impls = expand_impls(ident, &input.generics, &variants);
+
+    quote! {
+        #[doc = #docs]
+        #[derive(Clone, Debug)]
+        #vis enum #ident {
+            #(#decls)*
+        }
+
+        #impls
+
+        impl ::std::convert::From<#ident> for #name {
+            fn from(split: #ident) -> Self {
+                match split {
+                    #(#into_arms)*
+                }
+            }
+        }
+
+        impl ::std::convert::TryFrom<#name> for #ident {
+            type Error = #name;
+
+            fn try_from(payload: #name) -> ::std::result::Result<Self, #name> {
+                #[allow(unreachable_patterns)]
+                match payload {
+                    #(#from_arms)*
+                    other => ::std::result::Result::Err(other),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the names of the enums and the `trait for type` of the impls in `tokens`
+    fn items(tokens: TokenStream) -> (Vec<String>, Vec<String>) {
+        let file: syn::File = syn::parse2(tokens).expect("expansion is valid Rust");
+        let mut enums = vec![];
+        let mut impls = vec![];
+        for item in file.items {
+            match item {
+                syn::Item::Enum(item) => enums.push(item.ident.to_string()),
+                syn::Item::Impl(item) => {
+                    let (_, path, _) = item.trait_.expect("trait impl");
+                    let trait_ = path.segments.last().unwrap();
+                    let self_ty = &item.self_ty;
+                    impls.push(quote!(#trait_ for #self_ty).to_string());
+                }
+                item => panic!("unexpected item {}", quote!(#item)),
+            }
+        }
+        (enums, impls)
+    }
+
+    fn error(input: DeriveInput) -> String {
+        expand_protocol(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn expands_serde_and_conversion_impls() {
+        let input = parse_quote! {
+            enum Raw {
+                Count(CountReceive),
+                #[protocol(peer = "seq-kv", peer = "lin-kv")]
+                Kv(key_value::Receive<usize>),
+            }
+        };
+        let expanded = expand_protocol(input).unwrap();
+        let text = expanded.to_string();
+        assert!(
+            text.contains(r#"["seq-kv" , "lin-kv"] . contains"#),
+            "{text}"
+        );
+
+        let (enums, impls) = items(expanded);
+        assert!(enums.is_empty());
+        assert_eq!(
+            impls,
+            [
+                "Serialize for Raw",
+                "Deserialize < 'de > for Raw",
+                "From < CountReceive > for Raw",
+                "TryFrom < Raw > for CountReceive",
+                "From < key_value :: Receive < usize > > for Raw",
+                "TryFrom < Raw > for key_value :: Receive < usize >",
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_request_and_response_enums() {
+        let input = parse_quote! {
+            #[protocol(request = "Request", response = "Response")]
+            pub enum Raw {
+                #[protocol(request)]
+                Count(CountReceive),
+                #[protocol(response, peer = "seq-kv")]
+                Kv(KvReceive),
+                Other(Other),
+            }
+        };
+        let (enums, impls) = items(expand_protocol(input).unwrap());
+        assert_eq!(enums, ["Request", "Response"]);
+        for expected in [
+            "Deserialize < 'de > for Request",
+            "TryFrom < Request > for CountReceive",
+            "From < Request > for Raw",
+            "TryFrom < Raw > for Request",
+            "Deserialize < 'de > for Response",
+            "From < KvReceive > for Response",
+            "From < Response > for Raw",
+            "TryFrom < Raw > for Response",
+        ] {
+            assert!(impls.iter().any(|i| i == expected), "missing {expected}");
+        }
+        assert!(!impls
+            .iter()
+            .any(|i| i.contains("Other") && i.contains("Request")));
+    }
+
+    #[test]
+    fn keeps_generics() {
+        let input = parse_quote! {
+            enum Raw<T> where T: Clone {
+                Kv(key_value::Receive<T>),
+            }
+        };
+        let (_, impls) = items(expand_protocol(input).unwrap());
+        let expected = "TryFrom < Raw < T > > for key_value :: Receive < T >";
+        assert!(impls.iter().any(|i| i == expected), "{impls:?}");
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert!(error(parse_quote! { struct Raw(Count); }).contains("only be derived for enums"));
+        assert!(error(parse_quote! { enum Raw { A(B, C) } }).contains("exactly one"));
+        assert!(error(parse_quote! { enum Raw { A { b: B } } }).contains("exactly one"));
+        assert!(
+            error(parse_quote! { enum Raw { #[protocol(pear = "x")] A(B) } })
+                .contains("expected `peer")
+        );
+        assert!(
+            error(parse_quote! { enum Raw { #[protocol(request)] A(B) } })
+                .contains("the enum needs")
+        );
+        assert!(error(parse_quote! {
+            #[protocol(reply = "Reply")]
+            enum Raw { A(B) }
+        })
+        .contains("expected `request"));
+        assert!(error(parse_quote! {
+            #[protocol(request = "Request")]
+            enum Raw<T> { #[protocol(request)] A(T) }
+        })
+        .contains("cannot be split"));
+    }
+}